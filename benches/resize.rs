@@ -0,0 +1,62 @@
+// Measures how long each filter takes to scale a large photo to the built-in
+// image qualities, with and without the pre-shrink, and how long the filters
+// of the image crate take for comparison. Run it with `cargo bench`.
+
+use std::path::Path;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use image::imageops::FilterType;
+
+use image_mapper::{resize, ImageQuality, ResizeFilter};
+
+const FILTERS: [(ResizeFilter, FilterType); 5] = [
+    (ResizeFilter::Nearest, FilterType::Nearest),
+    (ResizeFilter::Bilinear, FilterType::Triangle),
+    (ResizeFilter::CatmullRom, FilterType::CatmullRom),
+    (ResizeFilter::Gaussian, FilterType::Gaussian),
+    (ResizeFilter::Lanczos3, FilterType::Lanczos3),
+];
+
+fn scale_to_built_in_qualities(c: &mut Criterion) {
+    let image =
+        image::open(Path::new("test_resources/large-with-exif.jpg")).unwrap();
+
+    for quality in [ImageQuality::Television, ImageQuality::Thumbnail] {
+        let preset = quality.preset();
+        // The size that fits the preset, like the mapper scales to
+        let ratio = (preset.max_width as f64 / image.width() as f64)
+            .min(preset.max_height as f64 / image.height() as f64);
+        let width = (image.width() as f64 * ratio).round() as u32;
+        let height = (image.height() as f64 * ratio).round() as u32;
+
+        let mut group = c.benchmark_group(format!(
+            "{}x{} to {} {}x{}",
+            image.width(),
+            image.height(),
+            preset.name,
+            width,
+            height
+        ));
+        group.sample_size(10);
+        for (filter, filter_type) in FILTERS {
+            let name = format!("{:?}", filter);
+            group.bench_function(BenchmarkId::new("simd", &name), |b| {
+                b.iter(|| resize::resize(&image, width, height, filter))
+            });
+            group.bench_function(BenchmarkId::new("pre-shrink", &name), |b| {
+                b.iter(|| {
+                    let pre_shrunk =
+                        image.thumbnail_exact(width * 2, height * 2);
+                    resize::resize(&pre_shrunk, width, height, filter)
+                })
+            });
+            group.bench_function(BenchmarkId::new("image crate", &name), |b| {
+                b.iter(|| image.resize_exact(width, height, filter_type))
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, scale_to_built_in_qualities);
+criterion_main!(benches);
@@ -8,17 +8,39 @@ if [[ "$VIDEOS" = "true" ]]; then
     videos="--include-videos"
 fi
 
+# Only needed once, to take over a /dst that has no marker yet, for example
+# one mapped by an older version
+if [[ "$ADOPT" = "true" ]]; then
+    adopt="--adopt"
+fi
+
 while :
 do
     # sh -c 'exit 1' # for testing
-    image_mapper "/src" "/dst" "$QUALITY" $videos --verbose
+    image_mapper "/src" "/dst" "$QUALITY" $videos $adopt --verbose
     last_status="$?"
 
-    if [[ ! "$last_status" = "0" ]]; then
-        die "image_mapper command crashed"
-    fi
+    # See the README for the meaning of the exit codes. 9 is only returned by
+    # the verify command, which isn't run here.
+    case "$last_status" in
+        0)
+            # /dst is marked now, so it doesn't have to be adopted again
+            adopt=""
+            ;;
+        6)
+            echo "image_mapper failed on some entries, retrying next time"
+            ;;
+        7)
+            echo "image_mapper is already running on /dst, retrying next time"
+            ;;
+        8)
+            die "/dst isn't marked as mapped from /src. If it really is, for example if it was mapped by an older version, set ADOPT=true once to take it over"
+            ;;
+        *)
+            die "image_mapper command failed with exit code $last_status"
+            ;;
+    esac
 
     echo "Sleeping $TIME seconds before converting again"
     sleep $TIME
 done
-
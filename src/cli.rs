@@ -0,0 +1,689 @@
+use std::path::{Component, Path, PathBuf};
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use image_mapper::file_names;
+use image_mapper::{
+    parse_color, ChromaSubsampling, ColorProfile, Config, CropMode,
+    DuplicatesSettings, ExifGroup, ImageQuality, JpegEncoder, Layout,
+    OutputFormat, Preset, PresetOverrides, RealFileSystem, ResizeFilter,
+    ResizePolicy, Settings, VerifySettings,
+};
+
+use crate::logging::{self, LogConfig, LogFormat};
+
+// Fails if the config file can't be read, or the image quality is unknown
+pub fn settings_from_matches(matches: &ArgMatches) -> Result<Settings, String> {
+    let config = match matches.value_of("config") {
+        Some(config_path) => {
+            image_mapper::read_config(Path::new(config_path), &RealFileSystem)
+                .map_err(|e| e.to_string())?
+        }
+        None => Config::default(),
+    };
+    let image_quality = image_quality_from_matches(matches, &config)?;
+
+    let layout = match matches.value_of("layout").unwrap() {
+        "year-month" => Layout::YearMonth,
+        "year-date" => Layout::YearDate,
+        _ => Layout::Mirror,
+    };
+
+    let keep_exif: Vec<ExifGroup> = matches
+        .values_of("keep-exif")
+        .map(|values| values.filter_map(ExifGroup::from_name).collect())
+        .unwrap_or_default();
+
+    let color_profile =
+        ColorProfile::from_name(matches.value_of("color-profile").unwrap())
+            .unwrap();
+
+    Ok(Settings::builder(image_quality)
+        .include_videos(matches.is_present("include-videos"))
+        .write_index(matches.is_present("index"))
+        .write_gallery(matches.is_present("gallery"))
+        .layout(layout)
+        .undated_folder(matches.value_of("undated-folder").unwrap())
+        .deduplicate(matches.is_present("dedup"))
+        .keep_exif(&keep_exif)
+        .strip_gps(matches.is_present("strip-gps"))
+        .color_profile(color_profile)
+        .adopt(matches.is_present("adopt"))
+        .build())
+}
+
+// The image quality options override the named image quality, or make up one
+fn image_quality_from_matches(
+    matches: &ArgMatches,
+    config: &Config,
+) -> Result<ImageQuality, String> {
+    let image_quality = match matches.value_of("image quality") {
+        Some(name) => config.image_quality(name).ok_or_else(|| {
+            format!("'{}' is neither a built-in image quality nor a preset in the config file", name)
+        })?,
+        None => ImageQuality::Custom(Preset::custom("Custom")),
+    };
+
+    // All are already validated
+    let overrides = PresetOverrides {
+        max_size: matches.value_of("max-size").and_then(parse_size),
+        quality: matches
+            .value_of("quality")
+            .and_then(|quality| quality.parse().ok()),
+        target_size: matches.value_of("target-size").and_then(parse_bytes),
+        target_ssim: matches
+            .value_of("target-ssim")
+            .and_then(|ssim| ssim.parse().ok()),
+        min_quality: matches
+            .value_of("min-quality")
+            .and_then(|quality| quality.parse().ok()),
+        max_quality: matches
+            .value_of("max-quality")
+            .and_then(|quality| quality.parse().ok()),
+        resize: matches.value_of("resize").and_then(ResizePolicy::from_name),
+        crop: matches.value_of("crop").and_then(CropMode::from_name),
+        filter: matches.value_of("filter").and_then(ResizeFilter::from_name),
+        pre_shrink: matches
+            .value_of("pre-shrink")
+            .and_then(|pre_shrink| pre_shrink.parse().ok()),
+        format: matches
+            .value_of("output-format")
+            .and_then(OutputFormat::from_name),
+        background: matches.value_of("background").and_then(parse_color),
+        jpeg_encoder: matches
+            .value_of("jpeg-encoder")
+            .and_then(JpegEncoder::from_name),
+        progressive: matches
+            .value_of("progressive")
+            .and_then(|progressive| progressive.parse().ok()),
+        trellis: matches
+            .value_of("trellis")
+            .and_then(|trellis| trellis.parse().ok()),
+        chroma_subsampling: matches
+            .value_of("chroma-subsampling")
+            .and_then(ChromaSubsampling::from_name),
+    };
+    if overrides.is_empty() {
+        return Ok(image_quality);
+    }
+    let preset = image_quality.preset().with_overrides(&overrides)?;
+    Ok(ImageQuality::Custom(preset))
+}
+
+// Fails with the message of clap if the arguments are invalid
+pub fn get_matches<'a>() -> Result<ArgMatches<'a>, String> {
+    let result = App::new("ImageMapper")
+        .setting(AppSettings::DisableVersion)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .about("Maps the source directory structure to an equivalent structure in the destination directory. The differences are: 1. Images will be downscaled and compressed. 2. Images will get their exif date/time prepended to their file names. 3. Images (and optionally videos) are the only files that will be kept.")
+        .max_term_width(90)
+        .arg(source_path_argument())
+        .arg(destination_path_argument())
+        .arg(image_quality_argument())
+        .arg(max_size_argument())
+        .arg(quality_argument())
+        .arg(target_size_argument())
+        .arg(target_ssim_argument())
+        .arg(min_quality_argument())
+        .arg(max_quality_argument())
+        .arg(resize_argument())
+        .arg(crop_argument())
+        .arg(filter_argument())
+        .arg(pre_shrink_argument())
+        .arg(output_format_argument())
+        .arg(background_argument())
+        .arg(jpeg_encoder_argument())
+        .arg(progressive_argument())
+        .arg(trellis_argument())
+        .arg(chroma_subsampling_argument())
+        .arg(config_argument())
+        .arg(verbose_print_argument())
+        .arg(quiet_argument())
+        .arg(include_videos_argument())
+        .arg(index_argument())
+        .arg(gallery_argument())
+        .arg(layout_argument())
+        .arg(undated_folder_argument())
+        .arg(dedup_argument())
+        .arg(keep_exif_argument())
+        .arg(strip_gps_argument())
+        .arg(color_profile_argument())
+        .arg(adopt_argument())
+        .arg(log_file_argument())
+        .arg(log_file_max_size_argument())
+        .arg(log_file_count_argument())
+        .arg(log_format_argument())
+        .subcommand(verify_subcommand())
+        .subcommand(duplicates_subcommand())
+        .after_help("EXIT CODES:\n    0    Success\n    2    Invalid arguments\n    3    The source or destination directory does not exist\n    4    The source and destination directories overlap\n    5    Stopped by the top-level safety check\n    6    Some entries failed, but the rest were mapped\n    7    The destination is locked by another instance\n    8    The destination is not marked as mapped from the source\n    9    verify found damaged files")
+        .get_matches_safe();
+
+    match result {
+        Ok(matches) => Ok(matches),
+        // --help is also reported as an error by clap
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => Err(e.message),
+    }
+}
+
+fn verify_subcommand<'a>() -> App<'a, 'a> {
+    SubCommand::with_name("verify")
+        .about("Checks that the images in the destination directory are complete and can be decoded, and that the videos match their sources. The source directory is the one that the destination was last mapped from.")
+        .arg(destination_path_argument())
+        .arg(repair_argument())
+        .arg(hash_argument())
+        .arg(verbose_print_argument())
+        .arg(quiet_argument())
+        .arg(log_file_argument())
+        .arg(log_file_max_size_argument())
+        .arg(log_file_count_argument())
+        .arg(log_format_argument())
+}
+
+pub fn verify_settings_from_matches(matches: &ArgMatches) -> VerifySettings {
+    VerifySettings {
+        repair: matches.is_present("repair"),
+        compare_hashes: matches.is_present("hash"),
+    }
+}
+
+fn duplicates_subcommand<'a>() -> App<'a, 'a> {
+    SubCommand::with_name("duplicates")
+        .about("Lists groups of images in the source directory that look alike, such as burst shots, re-compressed and resized copies. Nothing is written to the source directory.")
+        .arg(source_path_argument())
+        .arg(threshold_argument())
+        .arg(format_argument())
+        .arg(verbose_print_argument())
+        .arg(quiet_argument())
+        .arg(log_file_argument())
+        .arg(log_file_max_size_argument())
+        .arg(log_file_count_argument())
+        .arg(log_format_argument())
+}
+
+pub fn duplicates_settings_from_matches(
+    matches: &ArgMatches,
+) -> DuplicatesSettings {
+    DuplicatesSettings {
+        threshold: matches.value_of("threshold").unwrap().parse().unwrap(),
+    }
+}
+
+pub fn json_output_from_matches(matches: &ArgMatches) -> bool {
+    matches.value_of("format") == Some("json")
+}
+
+fn threshold_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("threshold")
+        .long("threshold")
+        .takes_value(true)
+        .value_name("BITS")
+        .default_value("10")
+        .validator(validate_threshold)
+        .help("How many of the 64 bits of the perceptual hashes of two images may differ for them to be listed as near-duplicates. 0 only lists images that look the same, higher values also list less similar ones.")
+}
+
+fn format_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("format")
+        .long("format")
+        .takes_value(true)
+        .possible_values(&["text", "json"])
+        .default_value("text")
+        .help("Print the groups as lines of paths separated by empty lines, or as JSON.")
+}
+
+fn validate_threshold(value: String) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(threshold) if threshold <= 64 => Ok(()),
+        _ => Err(format!("'{}' is not a number from 0 to 64", value)),
+    }
+}
+
+fn repair_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("repair")
+        .long("repair")
+        .takes_value(false)
+        .help("Delete the damaged files, so that the next mapping creates them again.")
+}
+
+fn hash_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("hash")
+        .long("hash")
+        .takes_value(false)
+        .help("Compare the videos with their sources by hash instead of just by size. This reads all of them.")
+}
+
+fn source_path_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("source directory")
+        .required(true)
+        .takes_value(true)
+        .help("The path to the directory that will be mapped.")
+}
+
+pub fn source_path_from_matches(matches: &ArgMatches) -> PathBuf {
+    let source_path = matches.value_of("source directory").unwrap();
+    PathBuf::from(source_path)
+}
+
+fn destination_path_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("destination directory")
+        .required(true)
+        .takes_value(true)
+        .help("The path to the directory where the result of the mapping will be placed.")
+}
+
+pub fn destination_path_from_matches(matches: &ArgMatches) -> PathBuf {
+    let destination_path = matches.value_of("destination directory").unwrap();
+    PathBuf::from(destination_path)
+}
+
+fn image_quality_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("image quality")
+        .required_unless("max-size")
+        .takes_value(true)
+        .help("Select if the images should be converted to the mobile quality (1024x1024, 30% compression), the TV quality (1920x1080, 70% compression) the thumbnail quality (300x300, 30% compression) or square thumbnails cropped to exactly 300x300, by giving Mobile, TV, Thumbnail or SquareThumbnail, or to a preset from the config file by giving its name. Can be left out if --max-size is given.")
+}
+
+fn max_size_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("max-size")
+        .long("max-size")
+        .takes_value(true)
+        .value_name("WxH")
+        .validator(validate_size)
+        .help("Downscale the images to fit in this width and height, for example 3840x2160, instead of the size of the image quality.")
+}
+
+fn quality_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("quality")
+        .long("quality")
+        .takes_value(true)
+        .value_name("N")
+        .validator(validate_quality)
+        .help("Compress the images with this JPEG quality from 1 to 100, instead of the quality of the image quality. 70 if only --max-size is given.")
+}
+
+fn target_size_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("target-size")
+        .long("target-size")
+        .takes_value(true)
+        .value_name("SIZE")
+        .validator(validate_bytes)
+        .help("Compress each image with the highest quality that keeps it within this file size, in bytes or with KB or MB, like 250KB, instead of a fixed quality. The quality is searched from --min-quality to --max-quality, and images that are larger at the minimum quality are written at it anyway.")
+}
+
+fn target_ssim_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("target-ssim")
+        .long("target-ssim")
+        .takes_value(true)
+        .value_name("SSIM")
+        .conflicts_with("target-size")
+        .validator(validate_ssim)
+        .help("Compress each image with the lowest quality that keeps it looking like the resized original, measured as its SSIM from above 0 to 1, like 0.95, instead of a fixed quality. The quality is searched from --min-quality to --max-quality, and images that don't reach it are written at the maximum quality. The chosen quality of each image is logged. Not for AVIF.")
+}
+
+fn min_quality_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("min-quality")
+        .long("min-quality")
+        .takes_value(true)
+        .value_name("N")
+        .validator(validate_quality)
+        .help(
+            "The lowest quality that --target-size or --target-ssim may choose. 10 by default.",
+        )
+}
+
+fn max_quality_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("max-quality")
+        .long("max-quality")
+        .takes_value(true)
+        .value_name("N")
+        .validator(validate_quality)
+        .help(
+            "The highest quality that --target-size or --target-ssim may choose. 90 by default.",
+        )
+}
+
+fn resize_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("resize")
+        .long("resize")
+        .takes_value(true)
+        .possible_values(&["fit", "fit-upscale", "fill", "long-edge", "short-edge"])
+        .help("How images are scaled to the size of the image quality, instead of how the image quality does it. fit downscales them to fit in it, and keeps smaller images as they are, which is what the built-in image qualities do. fit-upscale also upscales smaller images. fill scales them to cover it and crops the rest. long-edge scales them so that their longer edge gets the longer side of it, and short-edge so that their shorter edge gets the shorter side.")
+}
+
+fn crop_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("crop")
+        .long("crop")
+        .takes_value(true)
+        .possible_values(&["center", "smart"])
+        .help("Which part of the images is kept when fill crops them. center keeps the middle, and smart keeps the part with the most detail, which is usually the subject.")
+}
+
+fn filter_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("filter")
+        .long("filter")
+        .takes_value(true)
+        .possible_values(&["nearest", "bilinear", "catmull-rom", "gaussian", "lanczos3"])
+        .help("The filter that images are scaled with, instead of the one of the image quality, which is gaussian for the built-in ones. From fastest to slowest: nearest is jagged, bilinear is a little blurry, catmull-rom is sharp, lanczos3 is the sharpest but may ring around hard edges and gaussian is soft.")
+}
+
+fn pre_shrink_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("pre-shrink")
+        .long("pre-shrink")
+        .takes_value(true)
+        .value_name("BOOL")
+        .possible_values(&["true", "false"])
+        .help("Whether large images are first shrunk by averaging blocks of pixels to twice the target size, before the filter scales them. Faster, but a little blurrier. false by default.")
+}
+
+fn output_format_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("output-format")
+        .long("output-format")
+        .takes_value(true)
+        .possible_values(&["jpeg", "webp", "avif"])
+        .help("The file format that images are written in, instead of the one of the image quality, which is jpeg for the built-in ones. webp and avif files are much smaller at the same visual quality, but take longer to convert, avif in particular.")
+}
+
+fn background_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("background")
+        .long("background")
+        .takes_value(true)
+        .value_name("COLOR")
+        .validator(validate_color)
+        .help("The colour that transparent parts of images, such as in PNG logos and screenshots, are filled with when they are written as JPEGs, as a hex colour like #000000. White by default. WebP and AVIF keep the transparency.")
+}
+
+fn jpeg_encoder_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("jpeg-encoder")
+        .long("jpeg-encoder")
+        .takes_value(true)
+        .value_name("ENCODER")
+        .possible_values(&["image", "mozjpeg"])
+        .help("The encoder of JPEGs. image is fast, but only writes baseline JPEGs. mozjpeg writes considerably smaller files at the same quality, and progressive JPEGs, which viewers can show before they have loaded. image by default.")
+}
+
+fn progressive_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("progressive")
+        .long("progressive")
+        .takes_value(true)
+        .value_name("BOOL")
+        .possible_values(&["true", "false"])
+        .help("Whether mozjpeg writes progressive JPEGs. true by default.")
+}
+
+fn trellis_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("trellis")
+        .long("trellis")
+        .takes_value(true)
+        .value_name("BOOL")
+        .possible_values(&["true", "false"])
+        .help("Whether mozjpeg uses trellis quantization, which makes JPEGs a little smaller but takes several times longer. false by default.")
+}
+
+fn chroma_subsampling_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("chroma-subsampling")
+        .long("chroma-subsampling")
+        .takes_value(true)
+        .value_name("SUBSAMPLING")
+        .possible_values(&["4:2:0", "4:2:2", "4:4:4"])
+        .help("How much of the colour resolution mozjpeg keeps. 4:2:0 halves it in both directions, which is hardly noticeable in photos and the default. 4:4:4 keeps all of it, which keeps sharp coloured edges, such as in text, sharp.")
+}
+
+fn validate_color(value: String) -> Result<(), String> {
+    match parse_color(&value) {
+        Some(_) => Ok(()),
+        None => Err("Must be a hex colour like #ffffff".to_string()),
+    }
+}
+
+fn config_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("config")
+        .long("config")
+        .takes_value(true)
+        .value_name("PATH")
+        .help("Read image quality presets from this TOML file. See the README for the format.")
+}
+
+fn parse_size(value: &str) -> Option<(u32, u32)> {
+    let (width, height) = value.split_once('x')?;
+    match (width.parse(), height.parse()) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => {
+            Some((width, height))
+        }
+        _ => None,
+    }
+}
+
+fn validate_size(value: String) -> Result<(), String> {
+    parse_size(&value)
+        .map(|_| ())
+        .ok_or_else(|| format!("'{}' is not a size like 1920x1080", value))
+}
+
+// A number of bytes, optionally in KB or MB, like 250KB
+fn parse_bytes(value: &str) -> Option<u64> {
+    let unit_start = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(unit_start);
+    let multiplier = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "KB" => 1000,
+        "MB" => 1000 * 1000,
+        _ => return None,
+    };
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 => number.checked_mul(multiplier),
+        _ => None,
+    }
+}
+
+fn validate_bytes(value: String) -> Result<(), String> {
+    parse_bytes(&value)
+        .map(|_| ())
+        .ok_or_else(|| format!("'{}' is not a size like 250KB", value))
+}
+
+fn validate_ssim(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(ssim) if ssim > 0.0 && ssim <= 1.0 => Ok(()),
+        _ => Err(format!("'{}' is not a number above 0 and up to 1", value)),
+    }
+}
+
+fn validate_quality(value: String) -> Result<(), String> {
+    match value.parse::<u8>() {
+        Ok(quality) if (1..=100).contains(&quality) => Ok(()),
+        _ => Err(format!("'{}' is not a number from 1 to 100", value)),
+    }
+}
+
+fn verbose_print_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("verbose")
+        .short("v")
+        .long("verbose")
+        .takes_value(false)
+        .multiple(true)
+        .help("Print when a file is added/deleted. Give it twice to also print when a directory is entered and when a file already exists, and three times for everything. No matter of this setting, errors and warnings will always be printed, unless --quiet is given.")
+}
+
+fn quiet_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("quiet")
+        .short("q")
+        .long("quiet")
+        .takes_value(false)
+        .conflicts_with("verbose")
+        .help("Only print errors.")
+}
+
+fn include_videos_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("include-videos")
+        .short("i")
+        .long("include-videos")
+        .takes_value(false)
+        .help("Instead of just images, with this option, videos will also be included in the destination. Note that they will just be copied as-is without any conversion.")
+}
+
+fn index_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("index")
+        .long("index")
+        .takes_value(false)
+        .help("Write an index.json to each destination directory, describing its files and subdirectories. Without this option, existing index.json files are deleted.")
+}
+
+fn gallery_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("gallery")
+        .long("gallery")
+        .takes_value(false)
+        .help("Write an index.html to each destination directory, showing its images sorted by capture time and linking to its subdirectories, so that the destination can be browsed in a web browser. Without this option, existing index.html files are deleted.")
+}
+
+fn layout_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("layout")
+        .long("layout")
+        .takes_value(true)
+        .possible_values(&["mirror", "year-month", "year-date"])
+        .default_value("mirror")
+        .help("How the destination is structured. mirror keeps the directories of the source. year-month puts the files in yyyy/mm/ and year-date in yyyy/yyyy-mm-dd/ directories, by the capture time of the images. Changing the layout of an existing destination stops at the top-level safety check, so map to an empty destination instead.")
+}
+
+fn undated_folder_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("undated-folder")
+        .long("undated-folder")
+        .takes_value(true)
+        .value_name("NAME")
+        .default_value("Undated")
+        .validator(validate_folder_name)
+        .help("The directory that files without a capture time, such as videos, are put in with the year-month and year-date layouts.")
+}
+
+fn dedup_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("dedup")
+        .long("dedup")
+        .takes_value(false)
+        .help("Convert source files with identical contents, such as the same photos copied into several albums, only once. The other destination files are created as hard links to the first one, or as copies of it where the file system doesn't support hard links.")
+}
+
+fn keep_exif_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("keep-exif")
+        .long("keep-exif")
+        .takes_value(true)
+        .multiple(true)
+        .use_delimiter(true)
+        // Or the source and destination paths would be taken as groups too
+        .require_delimiter(true)
+        .value_name("GROUPS")
+        .possible_values(&["date", "camera", "exposure", "gps", "none"])
+        .default_value("date,camera,exposure")
+        .help("The exif metadata to copy to the destination images, as a comma separated list. date is when the image was taken, camera the camera and lens models, exposure the exposure time, aperture, ISO and such, and gps the location. none copies nothing. The location is only copied when asked for.")
+}
+
+fn strip_gps_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("strip-gps")
+        .long("strip-gps")
+        .takes_value(false)
+        .help("Never copy the gps location to the destination images, even if --keep-exif includes gps, for destinations that are shared with others.")
+}
+
+fn color_profile_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("color-profile")
+        .long("color-profile")
+        .takes_value(true)
+        .value_name("ACTION")
+        .possible_values(&["convert", "keep"])
+        .default_value("convert")
+        .help("What to do with the ICC colour profile of images that have one, such as Display P3 or Adobe RGB photos. convert converts the colours to sRGB, which all viewers show correctly. keep embeds the profile in the destination images instead, for viewers that manage colours, except in AVIFs, which are converted.")
+}
+
+fn validate_folder_name(value: String) -> Result<(), String> {
+    let mut components = Path::new(&value).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None)
+            if !file_names::is_reserved_destination_name(&value) =>
+        {
+            Ok(())
+        }
+        _ => Err(format!("'{}' is not a valid directory name", value)),
+    }
+}
+
+fn adopt_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("adopt")
+        .long("adopt")
+        .takes_value(false)
+        .help("Map into the destination even if it's not empty and not marked as mapped from the source, or marked as mapped from another source. Afterwards, it's marked as mapped from the source.")
+}
+
+fn log_file_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("log-file")
+        .long("log-file")
+        .takes_value(true)
+        .value_name("PATH")
+        .help("Also write the log to this file. The same log level applies as for the terminal.")
+}
+
+fn log_file_max_size_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("log-file-max-size")
+        .long("log-file-max-size")
+        .takes_value(true)
+        .value_name("BYTES")
+        .default_value("10485760")
+        .validator(validate_number)
+        .help("When the log file would grow larger than this, it's rotated to PATH.1, PATH.1 to PATH.2 and so on.")
+}
+
+fn log_file_count_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("log-file-count")
+        .long("log-file-count")
+        .takes_value(true)
+        .value_name("COUNT")
+        .default_value("5")
+        .validator(validate_number)
+        .help("The number of rotated log files to keep.")
+}
+
+fn log_format_argument<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("log-format")
+        .long("log-format")
+        .takes_value(true)
+        .possible_values(&["text", "json"])
+        .default_value("text")
+        .help(
+            "Print the log as plain text lines or as one JSON object per line.",
+        )
+}
+
+fn validate_number(value: String) -> Result<(), String> {
+    value
+        .parse::<u64>()
+        .map(|_| ())
+        .map_err(|_| format!("'{}' is not a non-negative number", value))
+}
+
+pub fn log_config_from_matches(matches: &ArgMatches) -> LogConfig {
+    let level = logging::level_from_verbosity(
+        matches.occurrences_of("verbose"),
+        matches.is_present("quiet"),
+    );
+    let format = match matches.value_of("log-format").unwrap() {
+        "json" => LogFormat::Json,
+        _ => LogFormat::Text,
+    };
+    let file = matches.value_of("log-file").map(PathBuf::from);
+    let max_file_size = matches
+        .value_of("log-file-max-size")
+        .unwrap()
+        .parse()
+        .unwrap();
+    let kept_files =
+        matches.value_of("log-file-count").unwrap().parse().unwrap();
+
+    LogConfig {
+        level,
+        format,
+        file,
+        max_file_size,
+        kept_files,
+    }
+}
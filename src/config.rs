@@ -0,0 +1,319 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::file_system::FileSystem;
+use crate::settings::{
+    default_background, parse_color, CropMode, ImageQuality, JpegOptions,
+    OutputFormat, Preset, PresetOverrides, ResizeFilter, ResizePolicy,
+    TargetSize, TargetSsim,
+};
+
+/// A config file, in TOML. It defines image quality presets in addition to
+/// the built-in ones, for example:
+///
+/// ```toml
+/// [presets.4K]
+/// max_width = 3840
+/// max_height = 2160
+/// quality = 80
+/// resize = "fit-upscale"
+/// filter = "lanczos3"
+/// format = "webp"
+/// background = "#000000"
+///
+/// [presets.4K.target_size]
+/// max_bytes = 1000000
+/// min_quality = 50
+/// max_quality = 95
+///
+/// [presets.4K.jpeg]
+/// encoder = "mozjpeg"
+/// progressive = true
+/// trellis = true
+/// chroma_subsampling = "4:4:4"
+/// ```
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    /// By name
+    pub presets: BTreeMap<String, Preset>,
+}
+
+impl Config {
+    /// The preset with this name, or the built-in image quality if there is
+    /// no such preset, so presets can replace the built-in ones
+    pub fn image_quality(&self, name: &str) -> Option<ImageQuality> {
+        match self.presets.get(name) {
+            Some(preset) => Some(ImageQuality::Custom(preset.clone())),
+            None => ImageQuality::built_in(name),
+        }
+    }
+}
+
+// The file format, which has the names as keys instead of in the presets
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    presets: BTreeMap<String, PresetValues>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PresetValues {
+    max_width: u32,
+    max_height: u32,
+    quality: u8,
+    target_size: Option<TargetSize>,
+    target_ssim: Option<TargetSsim>,
+    #[serde(default)]
+    resize: ResizePolicy,
+    #[serde(default)]
+    crop: CropMode,
+    #[serde(default)]
+    filter: ResizeFilter,
+    #[serde(default)]
+    pre_shrink: bool,
+    #[serde(default)]
+    format: OutputFormat,
+    background: Option<String>,
+    #[serde(default)]
+    jpeg: JpegOptions,
+}
+
+pub fn read_config(
+    path: &Path,
+    fs: &dyn FileSystem,
+) -> Result<Config, ConfigError> {
+    let contents = fs
+        .read(path)
+        .map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+    let contents = String::from_utf8_lossy(&contents);
+    parse_config(&contents)
+}
+
+pub fn parse_config(contents: &str) -> Result<Config, ConfigError> {
+    let file: ConfigFile =
+        toml::from_str(contents).map_err(ConfigError::Parse)?;
+
+    let mut config = Config::default();
+    for (name, values) in file.presets {
+        let background = match values.background {
+            Some(background) => match parse_color(&background) {
+                Some(background) => background,
+                None => {
+                    let reason = format!(
+                        "The background \"{}\" is not a colour like \"#ffffff\"",
+                        background
+                    );
+                    return Err(ConfigError::InvalidPreset(name, reason));
+                }
+            },
+            None => default_background(),
+        };
+        if values.target_size.is_some() && values.target_ssim.is_some() {
+            let reason = "A target size and a target SSIM can't both be given"
+                .to_string();
+            return Err(ConfigError::InvalidPreset(name, reason));
+        }
+        let (min_quality, max_quality) =
+            match (values.target_size, values.target_ssim) {
+                (Some(t), _) => (Some(t.min_quality), Some(t.max_quality)),
+                (None, Some(t)) => (Some(t.min_quality), Some(t.max_quality)),
+                (None, None) => (None, None),
+            };
+        let overrides = PresetOverrides {
+            max_size: Some((values.max_width, values.max_height)),
+            quality: Some(values.quality),
+            target_size: values.target_size.map(|t| t.max_bytes),
+            target_ssim: values.target_ssim.map(|t| t.min_ssim),
+            min_quality,
+            max_quality,
+            resize: Some(values.resize),
+            crop: Some(values.crop),
+            filter: Some(values.filter),
+            pre_shrink: Some(values.pre_shrink),
+            format: Some(values.format),
+            background: Some(background),
+            jpeg_encoder: Some(values.jpeg.encoder),
+            progressive: Some(values.jpeg.progressive),
+            trellis: Some(values.jpeg.trellis),
+            chroma_subsampling: Some(values.jpeg.chroma_subsampling),
+        };
+        let preset = match Preset::custom(&name).with_overrides(&overrides) {
+            Ok(preset) => preset,
+            Err(reason) => {
+                return Err(ConfigError::InvalidPreset(name, reason))
+            }
+        };
+        config.presets.insert(name, preset);
+    }
+
+    Ok(config)
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(toml::de::Error),
+    /// The name of the preset, and what's wrong with it
+    InvalidPreset(String, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(
+                f,
+                "Could not read the config file \"{}\" due to \"{}\"",
+                path.display(),
+                e
+            ),
+            ConfigError::Parse(e) => {
+                write!(f, "Could not parse the config file due to \"{}\"", e)
+            }
+            ConfigError::InvalidPreset(name, reason) => {
+                write!(f, "The preset \"{}\" is invalid: {}", name, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Read(_, e) => Some(e),
+            ConfigError::Parse(e) => Some(e),
+            ConfigError::InvalidPreset(_, _) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{ChromaSubsampling, JpegEncoder};
+
+    #[test]
+    fn presets_are_parsed() {
+        let config = parse_config(
+            "[presets.4K]\nmax_width = 3840\nmax_height = 2160\nquality = 80\nresize = \"fill\"\ncrop = \"smart\"\nfilter = \"nearest\"\nformat = \"avif\"\nbackground = \"#102030\"\n\n\
+             [presets.4K.jpeg]\nencoder = \"mozjpeg\"\ntrellis = true\nchroma_subsampling = \"4:4:4\"\n\n\
+             [presets.Tablet]\nmax_width = 2048\nmax_height = 1536\nquality = 60\n\n\
+             [presets.Tablet.target_size]\nmax_bytes = 250000\n\n\
+             [presets.Phone]\nmax_width = 1280\nmax_height = 1280\nquality = 70\n\n\
+             [presets.Phone.target_ssim]\nmin_ssim = 0.95\nmax_quality = 80\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some(ImageQuality::Custom(Preset {
+                name: "4K".to_string(),
+                max_width: 3840,
+                max_height: 2160,
+                quality: 80,
+                target_size: None,
+                target_ssim: None,
+                resize: ResizePolicy::Fill,
+                crop: CropMode::Smart,
+                filter: ResizeFilter::Nearest,
+                pre_shrink: false,
+                format: OutputFormat::Avif,
+                background: [0x10, 0x20, 0x30],
+                jpeg: JpegOptions {
+                    encoder: JpegEncoder::Mozjpeg,
+                    progressive: true,
+                    trellis: true,
+                    chroma_subsampling: ChromaSubsampling::Yuv444,
+                },
+            })),
+            config.image_quality("4K")
+        );
+        assert_eq!(
+            Some(TargetSize {
+                max_bytes: 250000,
+                min_quality: 10,
+                max_quality: 90,
+            }),
+            config.image_quality("Tablet").unwrap().preset().target_size
+        );
+        assert_eq!(
+            Some(TargetSsim {
+                min_ssim: 0.95,
+                min_quality: 10,
+                max_quality: 80,
+            }),
+            config.image_quality("Phone").unwrap().preset().target_ssim
+        );
+        assert_eq!(3, config.presets.len());
+    }
+
+    #[test]
+    fn built_in_image_qualities_are_found() {
+        let config = Config::default();
+
+        assert_eq!(Some(ImageQuality::Television), config.image_quality("TV"));
+        assert_eq!(None, config.image_quality("Tablet"));
+        assert_eq!(
+            ResizeFilter::Gaussian,
+            config.image_quality("TV").unwrap().preset().filter
+        );
+    }
+
+    #[test]
+    fn presets_replace_built_in_image_qualities() {
+        let config = parse_config(
+            "[presets.Mobile]\nmax_width = 2048\nmax_height = 2048\nquality = 50\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            2048,
+            config.image_quality("Mobile").unwrap().preset().max_width
+        );
+    }
+
+    #[test]
+    fn invalid_presets_are_rejected() {
+        let result = parse_config(
+            "[presets.Broken]\nmax_width = 0\nmax_height = 100\nquality = 50\n",
+        );
+        assert!(
+            matches!(result, Err(ConfigError::InvalidPreset(name, _)) if name == "Broken")
+        );
+
+        let result = parse_config(
+            "[presets.Typo]\nmax_widht = 100\nmax_height = 100\nquality = 50\n",
+        );
+        assert!(matches!(result, Err(ConfigError::Parse(_))));
+
+        let result = parse_config(
+            "[presets.Pink]\nmax_width = 100\nmax_height = 100\nquality = 50\nbackground = \"pink\"\n",
+        );
+        assert!(
+            matches!(result, Err(ConfigError::InvalidPreset(name, _)) if name == "Pink")
+        );
+
+        let result = parse_config(
+            "[presets.Small]\nmax_width = 100\nmax_height = 100\nquality = 50\n\n[presets.Small.target_size]\nmax_bytes = 1000\nmin_quality = 80\nmax_quality = 40\n",
+        );
+        assert!(
+            matches!(result, Err(ConfigError::InvalidPreset(name, _)) if name == "Small")
+        );
+
+        let result = parse_config(
+            "[presets.Blurry]\nmax_width = 100\nmax_height = 100\nquality = 50\n\n[presets.Blurry.target_ssim]\nmin_ssim = 1.5\n",
+        );
+        assert!(
+            matches!(result, Err(ConfigError::InvalidPreset(name, _)) if name == "Blurry")
+        );
+
+        let result = parse_config(
+            "[presets.Fast]\nmax_width = 100\nmax_height = 100\nquality = 50\n\n[presets.Fast.jpeg]\nencoder = \"turbo\"\n",
+        );
+        assert!(matches!(result, Err(ConfigError::Parse(_))));
+    }
+}
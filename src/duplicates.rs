@@ -0,0 +1,268 @@
+use std::path::{Path, PathBuf};
+
+use log::{debug, error};
+use serde::Serialize;
+
+use crate::file_names;
+use crate::file_system::{FileSystem, RealFileSystem};
+use crate::image;
+use crate::mapper::MapperError;
+
+/// Controls which images [`find_duplicates`] considers near-duplicates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DuplicatesSettings {
+    /// The number of bits, out of 64, that the perceptual hashes of two
+    /// images may differ in for them to be near-duplicates. 0 only finds
+    /// images that look the same.
+    pub threshold: u32,
+}
+
+impl Default for DuplicatesSettings {
+    fn default() -> DuplicatesSettings {
+        DuplicatesSettings { threshold: 10 }
+    }
+}
+
+/// Groups the images in `source_path` that look alike, such as burst shots,
+/// re-compressed and resized copies. Nothing is written to the source.
+pub fn find_duplicates(
+    source_path: &Path,
+    settings: DuplicatesSettings,
+) -> Result<DuplicatesReport, MapperError> {
+    find_duplicates_in(source_path, settings, &RealFileSystem)
+}
+
+/// Like [`find_duplicates`], but reads through `fs`
+pub fn find_duplicates_in(
+    source_path: &Path,
+    settings: DuplicatesSettings,
+    fs: &dyn FileSystem,
+) -> Result<DuplicatesReport, MapperError> {
+    if !fs.is_dir(source_path) {
+        return Err(MapperError::SrcDoesNotExist);
+    }
+
+    let mut report = DuplicatesReport::default();
+    let mut hashes = Vec::new();
+    hash_directory(source_path, fs, &mut hashes, &mut report);
+    report.checked_files = hashes.len();
+    report.groups = group_hashes(&hashes, settings.threshold);
+
+    Ok(report)
+}
+
+fn hash_directory(
+    source_path: &Path,
+    fs: &dyn FileSystem,
+    hashes: &mut Vec<(PathBuf, u64)>,
+    report: &mut DuplicatesReport,
+) {
+    debug!("Hashing the images in \"{}\"", source_path.display());
+
+    let mut source_entry_paths = match fs.read_dir(source_path) {
+        Ok(source_entry_paths) => source_entry_paths,
+        Err(e) => {
+            let message = format!(
+                "Could not read the source directory \"{}\" due to \"{}\"",
+                source_path.display(),
+                e
+            );
+            report_failure(source_path, message, report);
+            return;
+        }
+    };
+    source_entry_paths.sort();
+
+    for source_entry_path in source_entry_paths {
+        if fs.is_dir(&source_entry_path) {
+            hash_directory(&source_entry_path, fs, hashes, report);
+            continue;
+        }
+
+        let is_image = source_entry_path
+            .extension()
+            .map(file_names::extension_is_image_extension)
+            .unwrap_or(false);
+        if !is_image {
+            continue;
+        }
+
+        match image::perceptual_hash(&source_entry_path, fs) {
+            Ok(hash) => hashes.push((source_entry_path, hash)),
+            Err(e) => report_failure(&source_entry_path, e.to_string(), report),
+        }
+    }
+}
+
+fn report_failure(path: &Path, message: String, report: &mut DuplicatesReport) {
+    error!("{}", message);
+    report.failed_files.push(FailedFile {
+        path: path.to_path_buf(),
+        message,
+    });
+}
+
+// Images end up in the same group if they are near-duplicates of any image
+// in it, so the images at both ends of a long burst can differ by more than
+// the threshold.
+fn group_hashes(hashes: &[(PathBuf, u64)], threshold: u32) -> Vec<Group> {
+    // The index of another image in the same group, or the image itself for
+    // one image per group
+    let mut parents: Vec<usize> = (0..hashes.len()).collect();
+
+    for i in 0..hashes.len() {
+        for j in i + 1..hashes.len() {
+            if (hashes[i].1 ^ hashes[j].1).count_ones() <= threshold {
+                let root_i = root(&mut parents, i);
+                let root_j = root(&mut parents, j);
+                parents[root_j] = root_i;
+            }
+        }
+    }
+
+    let mut groups: Vec<Group> = Vec::new();
+    let mut group_of_root = vec![None; hashes.len()];
+    for (i, (path, _)) in hashes.iter().enumerate() {
+        let root = root(&mut parents, i);
+        let group = *group_of_root[root].get_or_insert_with(|| {
+            groups.push(Group { files: vec![] });
+            groups.len() - 1
+        });
+        groups[group].files.push(path.clone());
+    }
+
+    groups.retain(|group| group.files.len() > 1);
+    groups
+}
+
+fn root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+/// The outcome of a [`find_duplicates`] run.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct DuplicatesReport {
+    pub checked_files: usize,
+    /// Sorted by the path of their first image
+    pub groups: Vec<Group>,
+    pub failed_files: Vec<FailedFile>,
+}
+
+/// Images that are near-duplicates of each other.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Group {
+    /// Sorted by path
+    pub files: Vec<PathBuf>,
+}
+
+/// A source entry that could not be read or decoded.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct FailedFile {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::InMemoryFileSystem;
+
+    #[test]
+    fn resized_copy_is_a_near_duplicate() {
+        let fs = InMemoryFileSystem::new();
+        add_test_image(&fs, "/src/small.jpg", "small-with-exif.jpg");
+        add_test_image(&fs, "/src/album/large.jpg", "large-with-exif.jpg");
+        add_mirrored_test_image(
+            &fs,
+            "/src/mirrored.png",
+            "small-without-exif.png",
+        );
+        fs.add_file(Path::new("/src/notes.txt"), b"notes");
+
+        let report = find_duplicates_in(
+            Path::new("/src"),
+            DuplicatesSettings::default(),
+            &fs,
+        )
+        .unwrap();
+
+        assert_eq!(3, report.checked_files);
+        assert_eq!(
+            vec![Group {
+                files: vec![
+                    PathBuf::from("/src/album/large.jpg"),
+                    PathBuf::from("/src/small.jpg")
+                ]
+            }],
+            report.groups
+        );
+        assert!(report.failed_files.is_empty());
+    }
+
+    #[test]
+    fn broken_image_is_reported() {
+        let fs = InMemoryFileSystem::new();
+        fs.add_file(Path::new("/src/broken.jpg"), b"not an image");
+
+        let report = find_duplicates_in(
+            Path::new("/src"),
+            DuplicatesSettings::default(),
+            &fs,
+        )
+        .unwrap();
+
+        assert_eq!(0, report.checked_files);
+        assert_eq!(1, report.failed_files.len());
+        assert_eq!(Path::new("/src/broken.jpg"), report.failed_files[0].path);
+    }
+
+    #[test]
+    fn groups_are_joined_through_common_images() {
+        let hashes = vec![
+            (PathBuf::from("a"), 0b0000),
+            (PathBuf::from("b"), 0b1111),
+            (PathBuf::from("c"), 0b0011),
+            (PathBuf::from("d"), u64::MAX),
+        ];
+
+        let groups = group_hashes(&hashes, 2);
+
+        assert_eq!(
+            vec![Group {
+                files: vec![
+                    PathBuf::from("a"),
+                    PathBuf::from("b"),
+                    PathBuf::from("c")
+                ]
+            }],
+            groups
+        );
+        assert!(group_hashes(&hashes, 1).is_empty());
+    }
+
+    // Mirroring turns around which neighbour is brighter
+    fn add_mirrored_test_image(
+        fs: &InMemoryFileSystem,
+        path: &str,
+        name: &str,
+    ) {
+        let image =
+            ::image::open(Path::new("test_resources").join(name)).unwrap();
+        let mut contents = std::io::Cursor::new(Vec::new());
+        image
+            .fliph()
+            .write_to(&mut contents, ::image::ImageOutputFormat::Png)
+            .unwrap();
+        fs.add_file(Path::new(path), contents.get_ref());
+    }
+
+    fn add_test_image(fs: &InMemoryFileSystem, path: &str, name: &str) {
+        let contents =
+            std::fs::read(Path::new("test_resources").join(name)).unwrap();
+        fs.add_file(Path::new(path), &contents);
+    }
+}
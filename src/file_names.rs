@@ -1,17 +1,47 @@
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::ffi::OsStr;
-use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
 
 use exif::{In, Tag};
 use unwrap::unwrap;
 
+use crate::file_system::{FileSystem, RealFileSystem};
+use crate::settings::OutputFormat;
+
 lazy_static! {
-    static ref DST_NAME_RE: Regex =
-        Regex::new(r"(   \d{4}-\d{2}-\d{2} \d{2};\d{2};\d{2} )?(.+)\.jpg")
-            .unwrap();
+    static ref DST_NAME_RE: Regex = Regex::new(
+        r"(   \d{4}-\d{2}-\d{2} \d{2};\d{2};\d{2} )?(.+)\.(?:jpg|webp|avif)"
+    )
+    .unwrap();
+}
+
+// Written to the root of the destination to prevent concurrent runs
+pub const LOCK_FILE_NAME: &str = ".image_mapper.lock";
+
+// Written to the root of the destination to identify which source it was
+// mapped from
+pub const MARKER_FILE_NAME: &str = ".image_mapper.json";
+
+// Written to each destination directory if the index is enabled
+pub const INDEX_FILE_NAME: &str = "index.json";
+
+// Written to each destination directory if the gallery is enabled
+pub const GALLERY_FILE_NAME: &str = "index.html";
+
+// Written to each destination directory if the gallery is enabled, with the
+// thumbnails that the gallery page shows
+pub const THUMBNAILS_DIR_NAME: &str = ".thumbs";
+
+// Files that the mapper itself keeps in the destination, so they must never
+// be deleted even though they have no corresponding source entry.
+pub fn is_reserved_destination_name(file_name: &str) -> bool {
+    file_name == LOCK_FILE_NAME
+        || file_name == MARKER_FILE_NAME
+        || file_name == INDEX_FILE_NAME
+        || file_name == GALLERY_FILE_NAME
+        || file_name == THUMBNAILS_DIR_NAME
 }
 
 pub fn extension_is_image_extension(extension: &OsStr) -> bool {
@@ -20,41 +50,62 @@ pub fn extension_is_image_extension(extension: &OsStr) -> bool {
             "jpg" => true,
             "jpeg" => true,
             "png" => true,
-            _ => false,
+            _ => extension_is_raw_extension(OsStr::new(extension)),
         }
     } else {
         false
     }
 }
 
+// Camera RAW files, which are read from their embedded preview
+pub fn extension_is_raw_extension(extension: &OsStr) -> bool {
+    if let Some(extension) = extension.to_str() {
+        matches!(
+            extension.to_lowercase().as_str(),
+            "dng" | "nef" | "cr2" | "arw"
+        )
+    } else {
+        false
+    }
+}
+
 pub fn extension_is_video_extension(extension: &OsStr) -> bool {
     if let Some(extension) = extension.to_str() {
-        match extension.to_lowercase().as_str() {
-            "mov" => true,
-            "avi" => true,
-            "mp4" => true,
-            "m4v" => true,
-            "mpg" => true,
-            "mpeg" => true,
-            _ => false,
-        }
+        matches!(
+            extension.to_lowercase().as_str(),
+            "mov" | "avi" | "mp4" | "m4v" | "mpg" | "mpeg"
+        )
     } else {
         false
     }
 }
 
+// In any output format, so that images written before the format was changed
+// are still recognised
 pub fn extension_is_destination_image_extension(extension: &OsStr) -> bool {
     if let Some(extension) = extension.to_str() {
-        match extension.to_lowercase().as_str() {
-            "jpg" => true,
-            _ => false,
-        }
+        matches!(extension.to_lowercase().as_str(), "jpg" | "webp" | "avif")
     } else {
         false
     }
 }
 
-pub fn destination_image_name_from_image_path(image_path: &Path) -> String {
+pub fn destination_image_name_from_image_path(
+    image_path: &Path,
+    format: OutputFormat,
+) -> String {
+    destination_image_name_from_image_path_in(
+        image_path,
+        format,
+        &RealFileSystem,
+    )
+}
+
+pub fn destination_image_name_from_image_path_in(
+    image_path: &Path,
+    format: OutputFormat,
+    fs: &dyn FileSystem,
+) -> String {
     let file_name = unwrap!(
         image_path.file_name(),
         "Could not get the file name of \"{}\"",
@@ -66,25 +117,34 @@ pub fn destination_image_name_from_image_path(image_path: &Path) -> String {
         file_name
     );
 
-    let date_time_string = date_time_string_from_image_path(image_path);
+    let date_time_string = date_time_string_from_image_path(image_path, fs);
 
     if date_time_string.is_empty() {
-        format!("{}.jpg", file_name)
+        format!("{}.{}", file_name, format.extension())
     } else {
-        format!("   {} {}.jpg", date_time_string, file_name)
+        format!(
+            "   {} {}.{}",
+            date_time_string,
+            file_name,
+            format.extension()
+        )
     }
 }
 
 // Returns a string of the format "yyyy-mm-dd hh;mm;ss" if the image has an exif date, or "" if it doesn't.
-fn date_time_string_from_image_path(image_path: &Path) -> String {
-    let file = unwrap!(
-        File::open(image_path),
-        "Could not open the image \"{}\"",
-        image_path.display()
-    );
+fn date_time_string_from_image_path(
+    image_path: &Path,
+    fs: &dyn FileSystem,
+) -> String {
+    // If the image can't be opened, neither can it be converted, and that
+    // error is reported then.
+    let file = match fs.open(image_path) {
+        Ok(file) => file,
+        Err(_) => return "".to_string(),
+    };
     let reader = exif::Reader::new();
 
-    if let Ok(r) = reader.read_from_container(&mut BufReader::new(&file)) {
+    if let Ok(r) = reader.read_from_container(&mut BufReader::new(file)) {
         if let Some(date_time) = r.get_field(Tag::DateTimeOriginal, In::PRIMARY)
         {
             return format!(
@@ -100,11 +160,9 @@ fn date_time_string_from_image_path(image_path: &Path) -> String {
 pub fn destination_image_name_to_source_image_name(
     file_name: &str,
 ) -> Option<String> {
-    if let Some(captures) = DST_NAME_RE.captures(file_name) {
-        Some(captures.get(2).unwrap().as_str().to_owned())
-    } else {
-        None
-    }
+    DST_NAME_RE
+        .captures(file_name)
+        .map(|captures| captures.get(2).unwrap().as_str().to_owned())
 }
 
 #[cfg(test)]
@@ -117,16 +175,31 @@ mod tests {
 
     #[test]
     fn extension_is_image_extension_is_true_for_image_extensions() {
-        let extensions = vec!["jpg", "JPG", "jpeg", "JPEG", "png", "PNG"];
+        let extensions = vec![
+            "jpg", "JPG", "jpeg", "JPEG", "png", "PNG", "dng", "NEF", "cr2",
+            "ARW",
+        ];
 
         for extension in extensions.iter() {
             assert!(extension_is_image_extension(OsStr::new(extension)));
         }
     }
 
+    #[test]
+    fn extension_is_raw_extension_is_only_true_for_raw_extensions() {
+        for extension in
+            ["dng", "DNG", "nef", "NEF", "cr2", "CR2", "arw", "ARW"]
+        {
+            assert!(extension_is_raw_extension(OsStr::new(extension)));
+        }
+        for extension in ["jpg", "png", "tif", "tiff", "raw"] {
+            assert!(!extension_is_raw_extension(OsStr::new(extension)));
+        }
+    }
+
     #[test]
     fn extension_is_image_extension_is_false_for_non_image_extensions() {
-        let extensions = vec!["gif", "m4v", "mp4", "mov", "pdf", "doc", "txt"];
+        let extensions = ["gif", "m4v", "mp4", "mov", "pdf", "doc", "txt"];
 
         for extension in extensions.iter() {
             assert!(!extension_is_image_extension(OsStr::new(extension)));
@@ -136,7 +209,11 @@ mod tests {
     #[test]
     fn extension_is_destination_image_extension_is_true_for_destination_image_extension(
     ) {
-        assert!(extension_is_destination_image_extension(OsStr::new("jpg")));
+        for extension in ["jpg", "webp", "avif"] {
+            assert!(extension_is_destination_image_extension(OsStr::new(
+                extension
+            )));
+        }
     }
 
     #[test]
@@ -168,7 +245,7 @@ mod tests {
 
     #[test]
     fn extension_is_video_extension_is_false_for_non_video_extensions() {
-        let extensions = vec!["jpg", "doc", "pdf", "png"];
+        let extensions = ["jpg", "doc", "pdf", "png"];
 
         for extension in extensions.iter() {
             assert!(!extension_is_video_extension(OsStr::new(extension)));
@@ -178,7 +255,10 @@ mod tests {
     #[test]
     fn destination_image_name_for_exif_image() {
         let image_path = PathBuf::from(IMAGE_WITH_EXIF);
-        let image_name = destination_image_name_from_image_path(&image_path);
+        let image_name = destination_image_name_from_image_path(
+            &image_path,
+            OutputFormat::Jpeg,
+        );
         let correct_image_name =
             "   2010-03-14 11;22;33 large-with-exif.jpg.jpg".to_string();
 
@@ -188,17 +268,55 @@ mod tests {
     #[test]
     fn destination_image_name_for_non_exif_image() {
         let image_path = PathBuf::from(IMAGE_WITHOUT_EXIF);
-        let image_name = destination_image_name_from_image_path(&image_path);
+        let image_name = destination_image_name_from_image_path(
+            &image_path,
+            OutputFormat::Jpeg,
+        );
 
         let correct_image_name = "large-without-exif.jpg.jpg".to_string();
 
         assert_eq!(image_name, correct_image_name);
     }
 
+    #[test]
+    fn destination_image_name_for_raw_images() {
+        for (raw_image, date_time) in [
+            ("small-with-preview.nef", "2012-05-06 07;08;09"),
+            ("small-without-preview.dng", "2013-01-02 03;04;05"),
+        ] {
+            let image_path = Path::new("test_resources").join(raw_image);
+            let image_name = destination_image_name_from_image_path(
+                &image_path,
+                OutputFormat::Jpeg,
+            );
+
+            assert_eq!(
+                image_name,
+                format!("   {} {}.jpg", date_time, raw_image)
+            );
+        }
+    }
+
+    #[test]
+    fn destination_image_name_has_extension_of_format() {
+        let image_path = PathBuf::from(IMAGE_WITHOUT_EXIF);
+        let image_name = destination_image_name_from_image_path(
+            &image_path,
+            OutputFormat::Avif,
+        );
+
+        assert_eq!(image_name, "large-without-exif.jpg.avif");
+        assert_eq!(
+            destination_image_name_to_source_image_name(&image_name),
+            Some("large-without-exif.jpg".to_string())
+        );
+    }
+
     #[test]
     fn date_time_string_is_correct_for_image_with_exif() {
         let image_path = PathBuf::from(IMAGE_WITH_EXIF);
-        let date_time_string = date_time_string_from_image_path(&image_path);
+        let date_time_string =
+            date_time_string_from_image_path(&image_path, &RealFileSystem);
 
         assert_eq!(date_time_string, "2010-03-14 11;22;33");
     }
@@ -206,7 +324,8 @@ mod tests {
     #[test]
     fn date_time_string_is_correct_for_image_without_exif() {
         let image_path = PathBuf::from(IMAGE_WITHOUT_EXIF);
-        let date_time_string = date_time_string_from_image_path(&image_path);
+        let date_time_string =
+            date_time_string_from_image_path(&image_path, &RealFileSystem);
 
         assert_eq!(date_time_string, "");
     }
@@ -0,0 +1,611 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+use fs2::FileExt;
+
+/// The file system operations that the mapper and the media handlers use.
+/// [`RealFileSystem`] is the one used normally, and [`InMemoryFileSystem`]
+/// exists for tests.
+pub trait FileSystem {
+    fn is_dir(&self, path: &Path) -> bool;
+    fn is_file(&self, path: &Path) -> bool;
+    fn exists(&self, path: &Path) -> bool;
+    fn file_size(&self, path: &Path) -> io::Result<u64>;
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// The paths of the entries in the directory
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadSeek>>;
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Makes `link` another name for the file at `original`
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()>;
+
+    /// Takes an exclusive lock using the file at `path`, creating it if
+    /// needed. Returns None if someone else holds the lock. The lock is
+    /// released when the returned guard is dropped.
+    fn try_lock(&self, path: &Path) -> io::Result<Option<LockGuard>>;
+}
+
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+pub type LockGuard = Box<dyn Any>;
+
+/// Delegates to `std::fs`
+pub struct RealFileSystem;
+
+impl FileSystem for RealFileSystem {
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        fs::metadata(path).map(|metadata| metadata.len())
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadSeek>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        fs::write(path, contents)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::copy(from, to).map(|_| ())
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        fs::hard_link(original, link)
+    }
+
+    fn try_lock(&self, path: &Path) -> io::Result<Option<LockGuard>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(Some(Box::new(file))),
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A file system that only exists in memory. Paths should be absolute. The
+/// root directory always exists.
+///
+/// Operations can be made to fail with [`InMemoryFileSystem::fail`], to test
+/// how errors such as missing permissions, a full disk or files that vanish
+/// are handled.
+#[derive(Default)]
+pub struct InMemoryFileSystem {
+    entries: RefCell<BTreeMap<PathBuf, Entry>>,
+    failures: RefCell<Vec<Failure>>,
+    // Shared with the guards, which release their locks when dropped
+    locked: Rc<RefCell<Vec<PathBuf>>>,
+}
+
+enum Entry {
+    Dir,
+    // Shared by the hard links to the file
+    File(Rc<RefCell<Vec<u8>>>),
+}
+
+struct InMemoryLock {
+    locked: Rc<RefCell<Vec<PathBuf>>>,
+    path: PathBuf,
+}
+
+impl Drop for InMemoryLock {
+    fn drop(&mut self) {
+        let mut locked = self.locked.borrow_mut();
+        if let Some(i) = locked.iter().position(|path| *path == self.path) {
+            locked.remove(i);
+        }
+    }
+}
+
+/// The operations that failures can be injected into
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    Canonicalize,
+    ReadDir,
+    CreateDir,
+    Remove,
+    Read,
+    Write,
+    Link,
+    Lock,
+}
+
+struct Failure {
+    operation: Operation,
+    path: PathBuf,
+    kind: io::ErrorKind,
+}
+
+impl InMemoryFileSystem {
+    pub fn new() -> InMemoryFileSystem {
+        InMemoryFileSystem::default()
+    }
+
+    /// Creates the directory and all its missing parents
+    pub fn add_dir(&self, path: &Path) {
+        let mut entries = self.entries.borrow_mut();
+        for ancestor in normalize(path).ancestors() {
+            if ancestor.parent().is_some() {
+                entries.insert(ancestor.to_path_buf(), Entry::Dir);
+            }
+        }
+    }
+
+    /// Creates the file and all its missing parent directories
+    pub fn add_file(&self, path: &Path, contents: &[u8]) {
+        let path = normalize(path);
+        if let Some(parent) = path.parent() {
+            self.add_dir(parent);
+        }
+        self.entries.borrow_mut().insert(path, new_file(contents));
+    }
+
+    /// From now on, `operation` on `path` fails with an error of `kind`. For
+    /// example, [`Operation::Read`] with [`io::ErrorKind::NotFound`]
+    /// simulates a file that vanishes after the directory was listed.
+    pub fn fail(&self, operation: Operation, path: &Path, kind: io::ErrorKind) {
+        self.failures.borrow_mut().push(Failure {
+            operation,
+            path: normalize(path),
+            kind,
+        });
+    }
+
+    /// Makes [`FileSystem::try_lock`] on `path` report that someone else
+    /// holds the lock
+    pub fn hold_lock(&self, path: &Path) {
+        self.locked.borrow_mut().push(normalize(path));
+    }
+
+    /// All paths in the file system, sorted
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.entries.borrow().keys().cloned().collect()
+    }
+
+    fn check(&self, operation: Operation, path: &Path) -> io::Result<()> {
+        let failures = self.failures.borrow();
+        let failure = failures.iter().find(|failure| {
+            failure.operation == operation && failure.path == path
+        });
+
+        match failure {
+            Some(failure) => Err(io::Error::new(
+                failure.kind,
+                format!("injected {:?} failure", operation),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let path = normalize(path);
+        self.check(Operation::Read, &path)?;
+
+        match self.entries.borrow().get(&path) {
+            Some(Entry::File(contents)) => Ok(contents.borrow().clone()),
+            Some(Entry::Dir) => Err(is_a_directory_error(&path)),
+            None => Err(not_found_error(&path)),
+        }
+    }
+}
+
+impl FileSystem for InMemoryFileSystem {
+    fn is_dir(&self, path: &Path) -> bool {
+        let path = normalize(path);
+        path.parent().is_none()
+            || matches!(self.entries.borrow().get(&path), Some(Entry::Dir))
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        matches!(
+            self.entries.borrow().get(&normalize(path)),
+            Some(Entry::File(_))
+        )
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.is_dir(path) || self.is_file(path)
+    }
+
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        self.read_file(path).map(|contents| contents.len() as u64)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let path = normalize(path);
+        self.check(Operation::Canonicalize, &path)?;
+
+        if self.exists(&path) {
+            Ok(path)
+        } else {
+            Err(not_found_error(&path))
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let path = normalize(path);
+        self.check(Operation::ReadDir, &path)?;
+
+        if !self.is_dir(&path) {
+            return Err(not_found_error(&path));
+        }
+
+        Ok(self
+            .entries
+            .borrow()
+            .keys()
+            .filter(|entry_path| entry_path.parent() == Some(&path))
+            .cloned()
+            .collect())
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        self.check(Operation::CreateDir, &path)?;
+
+        if self.exists(&path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("\"{}\" already exists", path.display()),
+            ));
+        }
+        match path.parent() {
+            Some(parent) if self.is_dir(parent) => {
+                self.entries.borrow_mut().insert(path, Entry::Dir);
+                Ok(())
+            }
+            _ => Err(not_found_error(&path)),
+        }
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        self.check(Operation::Remove, &path)?;
+
+        if !self.is_file(&path) {
+            return Err(not_found_error(&path));
+        }
+        self.entries.borrow_mut().remove(&path);
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        self.check(Operation::Remove, &path)?;
+
+        if !self.is_dir(&path) {
+            return Err(not_found_error(&path));
+        }
+        self.entries
+            .borrow_mut()
+            .retain(|entry_path, _| !entry_path.starts_with(&path));
+        Ok(())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadSeek>> {
+        Ok(Box::new(Cursor::new(self.read_file(path)?)))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.read_file(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let path = normalize(path);
+        self.check(Operation::Write, &path)?;
+
+        if self.is_dir(&path) {
+            return Err(is_a_directory_error(&path));
+        }
+        // Like a real file, it's overwritten in place, so its hard links
+        // change too
+        if let Some(Entry::File(existing)) = self.entries.borrow().get(&path) {
+            *existing.borrow_mut() = contents.to_vec();
+            return Ok(());
+        }
+        match path.parent() {
+            Some(parent) if self.is_dir(parent) => {
+                self.entries.borrow_mut().insert(path, new_file(contents));
+                Ok(())
+            }
+            _ => Err(not_found_error(&path)),
+        }
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let contents = self.read_file(from)?;
+        self.write(to, &contents)
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        let link = normalize(link);
+        self.check(Operation::Link, &link)?;
+        if self.exists(&link) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("\"{}\" already exists", link.display()),
+            ));
+        }
+        let contents = match self.entries.borrow().get(&normalize(original)) {
+            Some(Entry::File(contents)) => Rc::clone(contents),
+            Some(Entry::Dir) => return Err(is_a_directory_error(original)),
+            None => return Err(not_found_error(original)),
+        };
+        match link.parent() {
+            Some(parent) if self.is_dir(parent) => {
+                self.entries
+                    .borrow_mut()
+                    .insert(link, Entry::File(contents));
+                Ok(())
+            }
+            _ => Err(not_found_error(&link)),
+        }
+    }
+
+    fn try_lock(&self, path: &Path) -> io::Result<Option<LockGuard>> {
+        let path = normalize(path);
+        self.check(Operation::Lock, &path)?;
+
+        if self.locked.borrow().contains(&path) {
+            return Ok(None);
+        }
+        if !self.exists(&path) {
+            self.write(&path, b"")?;
+        }
+        self.locked.borrow_mut().push(path.clone());
+        Ok(Some(Box::new(InMemoryLock {
+            locked: Rc::clone(&self.locked),
+            path,
+        })))
+    }
+}
+
+// Resolves "." and ".." without looking at the file system
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+fn new_file(contents: &[u8]) -> Entry {
+    Entry::File(Rc::new(RefCell::new(contents.to_vec())))
+}
+
+fn not_found_error(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("\"{}\" does not exist", path.display()),
+    )
+}
+
+fn is_a_directory_error(path: &Path) -> io::Error {
+    io::Error::other(format!("\"{}\" is a directory", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs `test` on a real temporary directory, and on an in-memory one,
+    // so that both behave the same
+    fn on_both(test: impl Fn(&dyn FileSystem, &Path)) {
+        let dir = tempfile::tempdir().unwrap();
+        test(&RealFileSystem, dir.path());
+
+        let fs = InMemoryFileSystem::new();
+        fs.add_dir(Path::new("/tmp/dir"));
+        test(&fs, Path::new("/tmp/dir"));
+    }
+
+    #[test]
+    fn missing_parents_are_errors() {
+        on_both(|fs, root| {
+            let file_path = root.join("missing/file");
+            fs.write(&root.join("file"), b"file").unwrap();
+
+            let kind = |result: io::Result<()>| result.unwrap_err().kind();
+            assert_eq!(
+                io::ErrorKind::NotFound,
+                kind(fs.write(&file_path, b""))
+            );
+            assert_eq!(
+                io::ErrorKind::NotFound,
+                kind(fs.copy(&root.join("file"), &file_path))
+            );
+            assert_eq!(
+                io::ErrorKind::NotFound,
+                kind(fs.hard_link(&root.join("file"), &file_path))
+            );
+            assert_eq!(
+                io::ErrorKind::NotFound,
+                kind(fs.create_dir(&root.join("missing/dir")))
+            );
+            assert!(!fs.exists(&root.join("missing")));
+        });
+    }
+
+    #[test]
+    fn hard_links_share_contents() {
+        on_both(|fs, root| {
+            let original = root.join("original");
+            let link = root.join("link");
+            fs.write(&original, b"old").unwrap();
+
+            fs.hard_link(&original, &link).unwrap();
+            fs.write(&original, b"new").unwrap();
+
+            assert_eq!(b"new".to_vec(), fs.read(&link).unwrap());
+            assert_eq!(
+                io::ErrorKind::AlreadyExists,
+                fs.hard_link(&original, &link).unwrap_err().kind()
+            );
+            fs.remove_file(&original).unwrap();
+            assert_eq!(b"new".to_vec(), fs.read(&link).unwrap());
+        });
+    }
+
+    #[test]
+    fn copies_are_independent() {
+        on_both(|fs, root| {
+            fs.write(&root.join("original"), b"old").unwrap();
+
+            fs.copy(&root.join("original"), &root.join("copy")).unwrap();
+            fs.write(&root.join("original"), b"new").unwrap();
+
+            assert_eq!(b"old".to_vec(), fs.read(&root.join("copy")).unwrap());
+        });
+    }
+
+    #[test]
+    fn directories_are_listed_and_removed_with_their_contents() {
+        on_both(|fs, root| {
+            fs.create_dir(&root.join("dir")).unwrap();
+            fs.create_dir(&root.join("dir/subdir")).unwrap();
+            fs.write(&root.join("dir/subdir/file"), b"file").unwrap();
+            fs.write(&root.join("dir/file"), b"file").unwrap();
+
+            let mut entries = fs.read_dir(&root.join("dir")).unwrap();
+            entries.sort();
+            assert_eq!(
+                vec![root.join("dir/file"), root.join("dir/subdir")],
+                entries
+            );
+            assert_eq!(
+                io::ErrorKind::AlreadyExists,
+                fs.create_dir(&root.join("dir")).unwrap_err().kind()
+            );
+
+            fs.remove_dir_all(&root.join("dir")).unwrap();
+            assert!(!fs.exists(&root.join("dir")));
+            assert!(fs.read_dir(&root.join("dir")).is_err());
+            assert!(fs.remove_file(&root.join("dir")).is_err());
+        });
+    }
+
+    #[test]
+    fn paths_are_canonicalized() {
+        on_both(|fs, root| {
+            fs.create_dir(&root.join("dir")).unwrap();
+
+            assert_eq!(
+                fs.canonicalize(&root.join("dir")).unwrap(),
+                fs.canonicalize(&root.join("dir/../dir/.")).unwrap()
+            );
+            assert_eq!(
+                io::ErrorKind::NotFound,
+                fs.canonicalize(&root.join("missing")).unwrap_err().kind()
+            );
+        });
+    }
+
+    #[test]
+    fn locks_conflict_until_released() {
+        on_both(|fs, root| {
+            let lock_path = root.join("lock");
+
+            let lock = fs.try_lock(&lock_path).unwrap();
+            assert!(lock.is_some());
+            assert!(fs.is_file(&lock_path));
+            assert!(fs.try_lock(&lock_path).unwrap().is_none());
+
+            drop(lock);
+            assert!(fs.try_lock(&lock_path).unwrap().is_some());
+        });
+    }
+
+    #[test]
+    fn injected_failures_only_affect_their_operation_and_path() {
+        let fs = InMemoryFileSystem::new();
+        fs.add_file(Path::new("/dir/file"), b"file");
+        fs.add_file(Path::new("/dir/other"), b"other");
+        fs.fail(
+            Operation::Read,
+            Path::new("/dir/./file"),
+            io::ErrorKind::PermissionDenied,
+        );
+
+        assert_eq!(
+            io::ErrorKind::PermissionDenied,
+            fs.read(Path::new("/dir/file")).unwrap_err().kind()
+        );
+        assert!(fs.open(Path::new("/dir/file")).is_err());
+        assert!(fs.read(Path::new("/dir/other")).is_ok());
+        assert!(fs.write(Path::new("/dir/file"), b"new").is_ok());
+    }
+
+    #[test]
+    fn held_locks_are_never_released() {
+        let fs = InMemoryFileSystem::new();
+        fs.hold_lock(Path::new("/lock"));
+
+        assert!(fs.try_lock(Path::new("/lock")).unwrap().is_none());
+        assert!(!fs.exists(Path::new("/lock")));
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::file_names;
+use crate::file_system::FileSystem;
+use crate::index::{FileEntry, Index};
+
+// Plain CSS, so that the pages work when opened straight from a USB stick.
+// The viewer is shown with :target, so no JavaScript is needed either.
+const STYLE: &str = "\
+body { margin: 0; padding: 1em; font-family: sans-serif; background: #111; color: #eee; }
+a { color: #9cf; }
+.folders { list-style: none; padding: 0; display: flex; flex-wrap: wrap; gap: 0.5em; }
+.folders a { display: block; padding: 0.5em 1em; background: #333; border-radius: 4px; text-decoration: none; }
+.grid { display: grid; grid-template-columns: repeat(auto-fill, minmax(200px, 1fr)); gap: 4px; }
+.grid > a { display: block; aspect-ratio: 1; background: #222; overflow: hidden; }
+.grid img { width: 100%; height: 100%; object-fit: cover; }
+.grid .video { display: flex; align-items: center; justify-content: center; padding: 0.5em; box-sizing: border-box; word-break: break-all; text-decoration: none; }
+.viewer { display: none; position: fixed; top: 0; right: 0; bottom: 0; left: 0; background: #000; }
+.viewer:target { display: flex; align-items: center; justify-content: center; }
+.viewer img { max-width: 100%; max-height: 100%; }
+.viewer > a { position: absolute; padding: 0.5em; font-size: 2em; text-decoration: none; }
+.viewer .close { top: 0; right: 0; }
+.viewer .previous { top: 45%; left: 0; }
+.viewer .next { top: 45%; right: 0; }
+.viewer .caption { position: absolute; bottom: 0; left: 0; right: 0; padding: 0.5em; text-align: center; background: rgba(0, 0, 0, 0.5); }
+";
+
+pub fn page_path(destination_path: &Path) -> PathBuf {
+    destination_path.join(file_names::GALLERY_FILE_NAME)
+}
+
+pub fn thumbnails_path(destination_path: &Path) -> PathBuf {
+    destination_path.join(file_names::THUMBNAILS_DIR_NAME)
+}
+
+/// The thumbnail of the destination image `name`, which is a JPEG whatever
+/// the format of the image
+pub fn thumbnail_path(destination_path: &Path, name: &str) -> PathBuf {
+    thumbnails_path(destination_path).join(thumbnail_name(name))
+}
+
+fn thumbnail_name(name: &str) -> String {
+    format!("{}.jpg", name)
+}
+
+/// Renders the gallery page of a destination directory described by
+/// `index`. The page links to `../index.html` if `has_parent` is set. The
+/// grid shows the thumbnails of the images named in `thumbnails`, and the
+/// images themselves for the others.
+pub fn render_page(
+    title: &str,
+    index: &Index,
+    has_parent: bool,
+    thumbnails: &BTreeSet<String>,
+) -> String {
+    let mut page = String::new();
+
+    let _ = write!(
+        page,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{}</title>\n<style>\n{}</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+        escape_html(title),
+        STYLE,
+        escape_html(title)
+    );
+
+    if has_parent {
+        page.push_str("<nav><a href=\"../index.html\">&uarr; Up</a></nav>\n");
+    }
+
+    if !index.directories.is_empty() {
+        page.push_str("<ul class=\"folders\">\n");
+        for directory in &index.directories {
+            let _ = writeln!(
+                page,
+                "<li><a href=\"{}/index.html\">{}</a> ({})</li>",
+                escape_html(&encode_url(&directory.name)),
+                escape_html(&directory.name),
+                directory.files
+            );
+        }
+        page.push_str("</ul>\n");
+    }
+
+    let files = sorted_by_capture_time(&index.files);
+    let images: Vec<&FileEntry> = files
+        .iter()
+        .cloned()
+        .filter(|file| is_image(file))
+        .collect();
+
+    page.push_str("<div class=\"grid\">\n");
+    let mut image_number = 0;
+    for file in &files {
+        let url = escape_html(&encode_url(&file.name));
+        if is_image(file) {
+            image_number += 1;
+            let image_url = if thumbnails.contains(&file.name) {
+                format!(
+                    "{}/{}",
+                    file_names::THUMBNAILS_DIR_NAME,
+                    escape_html(&encode_url(&thumbnail_name(&file.name)))
+                )
+            } else {
+                url
+            };
+            let _ = writeln!(
+                page,
+                "<a href=\"#image-{}\"><img src=\"{}\" loading=\"lazy\" alt=\"{}\"></a>",
+                image_number,
+                image_url,
+                escape_html(&file.source_name)
+            );
+        } else {
+            let _ = writeln!(
+                page,
+                "<a class=\"video\" href=\"{}\">{}</a>",
+                url,
+                escape_html(&file.source_name)
+            );
+        }
+    }
+    page.push_str("</div>\n");
+
+    for (i, image) in images.iter().enumerate() {
+        let number = i + 1;
+        let _ =
+            writeln!(page, "<div class=\"viewer\" id=\"image-{}\">", number);
+        let _ = writeln!(
+            page,
+            "<img src=\"{}\" loading=\"lazy\" alt=\"{}\">",
+            escape_html(&encode_url(&image.name)),
+            escape_html(&image.source_name)
+        );
+        if number > 1 {
+            let _ = writeln!(
+                page,
+                "<a class=\"previous\" href=\"#image-{}\">&lsaquo;</a>",
+                number - 1
+            );
+        }
+        if number < images.len() {
+            let _ = writeln!(
+                page,
+                "<a class=\"next\" href=\"#image-{}\">&rsaquo;</a>",
+                number + 1
+            );
+        }
+        page.push_str("<a class=\"close\" href=\"#\">&times;</a>\n");
+        let _ = writeln!(
+            page,
+            "<div class=\"caption\">{}</div>",
+            escape_html(&caption(image))
+        );
+        page.push_str("</div>\n");
+    }
+
+    page.push_str("</body>\n</html>\n");
+    page
+}
+
+pub fn write_page(
+    destination_path: &Path,
+    page: &str,
+    fs: &dyn FileSystem,
+) -> io::Result<()> {
+    fs.write(&page_path(destination_path), page.as_bytes())
+}
+
+// Only images have dimensions
+pub fn is_image(file: &FileEntry) -> bool {
+    file.width.is_some()
+}
+
+// Files without a capture time come last, sorted by name
+fn sorted_by_capture_time(files: &[FileEntry]) -> Vec<&FileEntry> {
+    let mut files: Vec<&FileEntry> = files.iter().collect();
+    files.sort_by(|a, b| {
+        let a_key = (a.capture_time.is_none(), &a.capture_time, &a.name);
+        let b_key = (b.capture_time.is_none(), &b.capture_time, &b.name);
+        a_key.cmp(&b_key)
+    });
+    files
+}
+
+fn caption(image: &FileEntry) -> String {
+    match &image.capture_time {
+        Some(capture_time) => format!("{} {}", image.source_name, capture_time),
+        None => image.source_name.clone(),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Percent-encodes a single path segment of a relative URL
+fn encode_url(segment: &str) -> String {
+    let mut encoded = String::new();
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~' => encoded.push(byte as char),
+            _ => {
+                let _ = write!(encoded, "%{:02X}", byte);
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::DirectoryEntry;
+
+    #[test]
+    fn encode_url_encodes_reserved_characters() {
+        assert_eq!(
+            encode_url("   2010-03-14 11;22;33 a#1.jpg.jpg"),
+            "%20%20%202010-03-14%2011%3B22%3B33%20a%231.jpg.jpg"
+        );
+        assert_eq!(encode_url("å"), "%C3%A5");
+    }
+
+    #[test]
+    fn escape_html_escapes_markup() {
+        assert_eq!(
+            escape_html("<b>\"Tom & Jerry\"</b>"),
+            "&lt;b&gt;&quot;Tom &amp; Jerry&quot;&lt;/b&gt;"
+        );
+    }
+
+    #[test]
+    fn images_are_sorted_by_capture_time() {
+        let files = vec![
+            image("b.jpg", None),
+            image("c.jpg", Some("2012-01-01 00:00:00")),
+            image("a.jpg", None),
+            image("d.jpg", Some("2011-01-01 00:00:00")),
+        ];
+
+        let names: Vec<&str> = sorted_by_capture_time(&files)
+            .iter()
+            .map(|file| file.source_name.as_str())
+            .collect();
+
+        assert_eq!(names, vec!["d.jpg", "c.jpg", "a.jpg", "b.jpg"]);
+    }
+
+    #[test]
+    fn page_links_folders_images_and_videos() {
+        let index = Index {
+            files: vec![
+                image("b&b.jpg", Some("2012-01-01 00:00:00")),
+                image("a.jpg", Some("2011-01-01 00:00:00")),
+                FileEntry {
+                    name: "clip.mp4".to_string(),
+                    source_name: "clip.mp4".to_string(),
+                    ..FileEntry::default()
+                },
+            ],
+            directories: vec![DirectoryEntry {
+                name: "Summer 2011".to_string(),
+                files: 12,
+                directories: 0,
+            }],
+        };
+
+        let page = render_page("Photos", &index, true, &BTreeSet::new());
+
+        assert!(page.contains("<title>Photos</title>"));
+        assert!(page.contains("<a href=\"../index.html\">"));
+        assert!(page.contains(
+            "<li><a href=\"Summer%202011/index.html\">Summer 2011</a> (12)</li>"
+        ));
+        assert!(page.contains(
+            "<a href=\"#image-1\"><img src=\"a.jpg.jpg\" loading=\"lazy\" alt=\"a.jpg\"></a>"
+        ));
+        assert!(page.contains(
+            "<a href=\"#image-2\"><img src=\"b%26b.jpg.jpg\" loading=\"lazy\" alt=\"b&amp;b.jpg\"></a>"
+        ));
+        assert!(
+            page.contains("<a class=\"video\" href=\"clip.mp4\">clip.mp4</a>")
+        );
+        assert!(page.contains("<a class=\"next\" href=\"#image-2\">"));
+        assert!(page.contains("<a class=\"previous\" href=\"#image-1\">"));
+        assert!(!page.contains("#image-3"));
+    }
+
+    #[test]
+    fn root_page_has_no_parent_link() {
+        let page =
+            render_page("Photos", &Index::default(), false, &BTreeSet::new());
+
+        assert!(!page.contains("../index.html"));
+    }
+
+    #[test]
+    fn grid_shows_thumbnails_and_viewer_shows_images() {
+        let index = Index {
+            files: vec![image("a b.jpg", None), image("c.jpg", None)],
+            directories: vec![],
+        };
+        let thumbnails = vec!["a b.jpg.jpg".to_string()].into_iter().collect();
+
+        let page = render_page("Photos", &index, false, &thumbnails);
+
+        assert!(page.contains(
+            "<a href=\"#image-1\"><img src=\".thumbs/a%20b.jpg.jpg.jpg\" loading=\"lazy\" alt=\"a b.jpg\"></a>"
+        ));
+        assert!(page.contains(
+            "<img src=\"a%20b.jpg.jpg\" loading=\"lazy\" alt=\"a b.jpg\">\n"
+        ));
+        // Without a thumbnail, the image itself
+        assert!(page.contains(
+            "<a href=\"#image-2\"><img src=\"c.jpg.jpg\" loading=\"lazy\" alt=\"c.jpg\"></a>"
+        ));
+    }
+
+    fn image(source_name: &str, capture_time: Option<&str>) -> FileEntry {
+        FileEntry {
+            name: format!("{}.jpg", source_name),
+            source_name: source_name.to_string(),
+            capture_time: capture_time.map(|time| time.to_string()),
+            width: Some(300),
+            height: Some(200),
+            ..FileEntry::default()
+        }
+    }
+}
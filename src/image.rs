@@ -1,77 +1,466 @@
 #![allow(dead_code)]
 
-use std::fs::File;
-use std::io::BufReader;
-use std::path::Path;
+use std::borrow::Cow;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::io::{BufRead, BufReader, Cursor, Read};
+use std::panic;
+use std::path::{Path, PathBuf};
 
-use exif::{In, Reader, Tag, Value};
-use image::codecs::jpeg::JpegEncoder;
-use image::imageops::Gaussian;
-use image::DynamicImage;
-use unwrap::unwrap;
+use exif::{Exif, In, Reader, Tag, Value};
+use image::error::{DecodingError, EncodingError, ImageFormatHint};
+use image::{
+    DynamicImage, GenericImageView, GrayImage, ImageFormat, Rgb, RgbImage,
+    RgbaImage,
+};
+use jpeg_decoder::PixelFormat;
+use qcms::DataType;
+use rgb::FromSlice;
 
-use crate::settings::{ImageQuality, Settings};
+use crate::file_names;
+use crate::file_system::{FileSystem, RealFileSystem};
+use crate::metadata;
+use crate::raw;
+use crate::resize;
+use crate::settings::{
+    ChromaSubsampling, ColorProfile, CropMode, JpegEncoder, JpegOptions,
+    OutputFormat, Preset, ResizeFilter, ResizePolicy, Settings, TargetSize,
+    TargetSsim,
+};
+use crate::ssim;
 
+/// Reads the image at `source_path`, applies its exif orientation, downscales
+/// and compresses it according to `settings`, and writes it in the format of
+/// `settings` to `destination_path`. Nothing is left at `destination_path` on failure.
+/// Returns the quality that it was compressed with, which differs between
+/// images if the preset has a target size or SSIM.
 pub fn open_compress_and_save_image(
     source_path: &Path,
     destination_path: &Path,
     settings: &Settings,
-) -> bool {
-    if let Some(original) = read_original_image(source_path) {
-        let orientation = orientation_from_path(source_path);
-
-        if let Some(rotated) = rotate_image(original, orientation) {
-            let dimensions = dimensions_from_settings(settings);
-            let resized = resize_image(rotated, dimensions);
-            encode_and_save_image(resized, destination_path, settings)
+) -> Result<u8, ImageError> {
+    open_compress_and_save_image_in(
+        source_path,
+        destination_path,
+        settings,
+        &RealFileSystem,
+    )
+}
+
+/// Like [`open_compress_and_save_image`], but reads and writes through `fs`
+pub fn open_compress_and_save_image_in(
+    source_path: &Path,
+    destination_path: &Path,
+    settings: &Settings,
+    fs: &dyn FileSystem,
+) -> Result<u8, ImageError> {
+    let contents = fs.read(source_path).map_err(|e| {
+        ImageError::Open(
+            source_path.to_path_buf(),
+            image::ImageError::IoError(e),
+        )
+    })?;
+    let orientation = orientation_from_contents(&contents);
+    let preset = settings.image_quality.preset();
+    // In the orientation of the stored pixels, which is what the decoder
+    // can scale
+    let minimum_size = |size| {
+        let oriented_size = orient_size(size, orientation);
+        orient_size(scaled_size(oriented_size, &preset), orientation)
+    };
+    let (original, full_size) =
+        read_reduced_image(source_path, &contents, minimum_size)?;
+
+    if let Some(rotated) = rotate_image(original, orientation) {
+        let full_size = orient_size(full_size, orientation);
+        let resized = resize_image(rotated, full_size, &preset);
+        // The AVIF encoder can't embed a profile, so those are converted
+        let keep_icc = settings.color_profile == ColorProfile::Keep
+            && preset.format != OutputFormat::Avif;
+        // The profile of a RAW file is in its preview
+        let icc_contents = if is_raw(source_path) {
+            raw::embedded_preview(&contents).unwrap_or_default()
         } else {
-            println!(
-                "Unsupported orientation for \"{}\"",
-                source_path.display()
-            );
-            false
-        }
+            &contents
+        };
+        let (converted, icc) = match metadata::icc_profile(icc_contents) {
+            Some(icc) if keep_icc => (resized, Some(icc)),
+            Some(icc) => (convert_to_srgb(resized, &icc), None),
+            None => (resized, None),
+        };
+        let exif = metadata::exif_to_keep(&contents, settings);
+        encode_and_save_image(
+            converted,
+            Metadata { exif, icc },
+            destination_path,
+            settings,
+            fs,
+        )
     } else {
-        false
+        Err(ImageError::UnsupportedOrientation(
+            source_path.to_path_buf(),
+            orientation,
+        ))
     }
 }
 
-fn read_original_image(image_path: &Path) -> Option<DynamicImage> {
-    match image::open(image_path) {
-        Ok(image) => Some(image),
-        Err(e) => {
-            println!(
-                "Could not open the image \"{}\" due to \"{}\", so skipping it.",
-                image_path.display(),
-                e
-            );
-            None
+/// A 64 bit difference hash of the image at `path`, after applying its exif
+/// orientation. The hashes of images that look alike differ in few bits,
+/// even if one of them was resized or compressed again.
+pub fn perceptual_hash(
+    path: &Path,
+    fs: &dyn FileSystem,
+) -> Result<u64, ImageError> {
+    let contents = fs.read(path).map_err(|e| {
+        ImageError::Open(path.to_path_buf(), image::ImageError::IoError(e))
+    })?;
+    let orientation = orientation_from_contents(&contents);
+    // Larger than the hashed thumbnail, so that it still averages many pixels
+    let (original, _) = read_reduced_image(path, &contents, |_| (64, 64))?;
+    let rotated = rotate_image(original, orientation).ok_or_else(|| {
+        ImageError::UnsupportedOrientation(path.to_path_buf(), orientation)
+    })?;
+
+    // 9 columns, so that each of the 8 rows has 8 pairs of neighbours
+    let small = rotated.thumbnail_exact(9, 8).to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter_right =
+                small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0];
+            hash = hash << 1 | brighter_right as u64;
         }
     }
+    Ok(hash)
 }
 
-fn orientation_from_path(image_path: &Path) -> u16 {
-    let file = unwrap!(
-        File::open(image_path),
-        "Could not open the image for exif \"{}\"",
-        image_path.display()
-    );
-    let mut buf_reader = BufReader::new(&file);
-    let exif_reader = Reader::new();
+/// Checks that the image at `path` is complete and can be decoded, and if
+/// not, returns what's wrong with it.
+pub fn verify_image(path: &Path, fs: &dyn FileSystem) -> Result<(), String> {
+    let contents = fs.read(path).map_err(|e| {
+        format!(
+            "Could not read the image \"{}\" due to \"{}\"",
+            path.display(),
+            e
+        )
+    })?;
 
-    if let Ok(exif_reader) = exif_reader.read_from_container(&mut buf_reader) {
-        if let Some(orientation) =
-            exif_reader.get_field(Tag::Orientation, In::PRIMARY)
-        {
-            if let Value::Short(orientation) = &orientation.value {
-                if orientation.len() == 1 {
-                    return orientation[0];
-                }
+    // The decoder pads truncated JPEGs with grey instead of failing, so
+    // check that the end of image marker is there.
+    if contents.starts_with(&[0xFF, 0xD8]) && !contents.ends_with(&[0xFF, 0xD9])
+    {
+        return Err(format!("The image \"{}\" is truncated", path.display()));
+    }
+
+    // The image crate can't decode AVIFs, but a truncated one has boxes
+    // that don't add up
+    if is_avif(&contents) {
+        return match avif_dimensions(&contents) {
+            Some(_) => Ok(()),
+            None => Err(format!(
+                "The image \"{}\" is truncated or malformed",
+                path.display()
+            )),
+        };
+    }
+
+    image::load_from_memory(&contents).map(|_| ()).map_err(|e| {
+        format!(
+            "Could not decode the image \"{}\" due to \"{}\"",
+            path.display(),
+            e
+        )
+    })
+}
+
+fn read_original_image(
+    image_path: &Path,
+    contents: &[u8],
+) -> Result<DynamicImage, ImageError> {
+    let to_image_error = |e| ImageError::Open(image_path.to_path_buf(), e);
+
+    // The extension is only a fallback, in case the format can't be told
+    // from the contents.
+    let mut reader = image::io::Reader::new(Cursor::new(contents));
+    if let Ok(format) = ImageFormat::from_path(image_path) {
+        reader.set_format(format);
+    }
+    let reader = reader
+        .with_guessed_format()
+        .map_err(|e| to_image_error(image::ImageError::IoError(e)))?;
+
+    reader.decode().map_err(to_image_error)
+}
+
+// Like read_original_image, but a JPEG is decoded at the smallest of 1/8, 1/4
+// and 1/2 of its size that is still at least `minimum_size` of its full
+// size, which is much faster and takes less memory than decoding all of it.
+// Returns the image and its full size.
+fn read_reduced_image(
+    image_path: &Path,
+    contents: &[u8],
+    minimum_size: impl Fn((u32, u32)) -> (u32, u32),
+) -> Result<(DynamicImage, (u32, u32)), ImageError> {
+    if is_raw(image_path) {
+        return read_raw_image(image_path, contents, minimum_size);
+    }
+    if let Some(reduced) = decode_reduced_jpeg(contents, minimum_size) {
+        return Ok(reduced);
+    }
+
+    let original = read_original_image(image_path, contents)?;
+    let full_size = original.dimensions();
+    Ok((original, full_size))
+}
+
+fn is_raw(image_path: &Path) -> bool {
+    image_path
+        .extension()
+        .map(file_names::extension_is_raw_extension)
+        .unwrap_or(false)
+}
+
+// A RAW file is read from its largest JPEG preview, which the camera
+// developed better than it can be here, and only developed from its sensor
+// data if it has none. The orientation is in the RAW file either way.
+fn read_raw_image(
+    image_path: &Path,
+    contents: &[u8],
+    minimum_size: impl Fn((u32, u32)) -> (u32, u32),
+) -> Result<(DynamicImage, (u32, u32)), ImageError> {
+    let to_image_error = |e| ImageError::Open(image_path.to_path_buf(), e);
+
+    let original = match raw::embedded_preview(contents) {
+        Some(preview) => {
+            if let Some(reduced) = decode_reduced_jpeg(preview, minimum_size) {
+                return Ok(reduced);
             }
+            image::load_from_memory_with_format(preview, ImageFormat::Jpeg)
+                .map_err(to_image_error)?
+        }
+        None => DynamicImage::ImageRgb8(raw::develop(contents).map_err(
+            |message| {
+                to_image_error(image::ImageError::Decoding(DecodingError::new(
+                    ImageFormatHint::Name("RAW".to_string()),
+                    message,
+                )))
+            },
+        )?),
+    };
+    let full_size = original.dimensions();
+    Ok((original, full_size))
+}
+
+// None if the contents aren't a JPEG that can be reduced, in which case it's
+// up to read_original_image to decode it, or tell what's wrong with it
+fn decode_reduced_jpeg(
+    contents: &[u8],
+    minimum_size: impl Fn((u32, u32)) -> (u32, u32),
+) -> Option<(DynamicImage, (u32, u32))> {
+    let mut decoder = jpeg_decoder::Decoder::new(contents);
+    decoder.read_info().ok()?;
+    let info = decoder.info()?;
+    let full_size = (info.width as u32, info.height as u32);
+
+    let (minimum_width, minimum_height) = minimum_size(full_size);
+    let to_u16 = |length: u32| length.min(u16::MAX as u32) as u16;
+    let (mut width, mut height) = decoder
+        .scale(to_u16(minimum_width), to_u16(minimum_height))
+        .ok()?;
+    // The decoder only makes sure that one of them is large enough, so the
+    // next larger scale is tried until both are
+    while (width as u32) < minimum_width || (height as u32) < minimum_height {
+        if (width as u32, height as u32) == full_size {
+            return None;
+        }
+        let (larger_width, larger_height) = decoder
+            .scale(width.saturating_add(1), height.saturating_add(1))
+            .ok()?;
+        width = larger_width;
+        height = larger_height;
+    }
+    let size = (width as u32, height as u32);
+    if size == full_size {
+        return None;
+    }
+
+    let pixels = decoder.decode().ok()?;
+    let image = match info.pixel_format {
+        PixelFormat::L8 => DynamicImage::ImageLuma8(GrayImage::from_raw(
+            size.0, size.1, pixels,
+        )?),
+        PixelFormat::RGB24 => {
+            DynamicImage::ImageRgb8(RgbImage::from_raw(size.0, size.1, pixels)?)
         }
+        // Rare in photos, and converted by the image crate
+        PixelFormat::L16 | PixelFormat::CMYK32 => return None,
+    };
+    Some((image, full_size))
+}
+
+// The size after applying `orientation` to an image of `size`. Since it only
+// swaps width and height, it also undoes itself.
+fn orient_size(size: (u32, u32), orientation: u16) -> (u32, u32) {
+    match orientation {
+        5..=8 => (size.1, size.0),
+        _ => size,
+    }
+}
+
+fn orientation_from_contents(contents: &[u8]) -> u16 {
+    let mut buf_reader = BufReader::new(Cursor::new(contents));
+    let exif_reader = Reader::new();
+
+    exif_reader
+        .read_from_container(&mut buf_reader)
+        .ok()
+        .and_then(|exif| orientation_from_exif(&exif))
+        .unwrap_or(1)
+}
+
+fn orientation_from_exif(exif: &Exif) -> Option<u16> {
+    let orientation = exif.get_field(Tag::Orientation, In::PRIMARY)?;
+    match &orientation.value {
+        Value::Short(orientation) if orientation.len() == 1 => {
+            Some(orientation[0])
+        }
+        _ => None,
+    }
+}
+
+/// The exif fields of an image that are shown in the index
+#[derive(Debug, Default, PartialEq)]
+pub struct ExifSummary {
+    /// "yyyy-mm-dd hh:mm:ss"
+    pub capture_time: Option<String>,
+    pub orientation: Option<u16>,
+    pub camera_model: Option<String>,
+}
+
+/// Reads the exif of the image at `path`. Fields that are missing, or all
+/// of them if the image has no exif, are None.
+pub fn read_exif_summary(path: &Path, fs: &dyn FileSystem) -> ExifSummary {
+    let exif = fs.open(path).ok().and_then(|file| {
+        Reader::new()
+            .read_from_container(&mut BufReader::new(file))
+            .ok()
+    });
+    let exif = match exif {
+        Some(exif) => exif,
+        None => return ExifSummary::default(),
+    };
+
+    let capture_time = exif
+        .get_field(Tag::DateTimeOriginal, In::PRIMARY)
+        .map(|field| field.value.display_as(Tag::DateTimeOriginal).to_string());
+    let camera_model =
+        exif.get_field(Tag::Model, In::PRIMARY)
+            .and_then(|field| match &field.value {
+                Value::Ascii(values) => values.first().map(|value| {
+                    String::from_utf8_lossy(value)
+                        .trim_end_matches('\0')
+                        .trim()
+                        .to_string()
+                }),
+                _ => None,
+            });
+
+    ExifSummary {
+        capture_time,
+        orientation: orientation_from_exif(&exif),
+        camera_model,
+    }
+}
+
+/// The width and height of the image at `path`, read from its header
+pub fn image_dimensions(
+    path: &Path,
+    fs: &dyn FileSystem,
+) -> Result<(u32, u32), ImageError> {
+    let to_image_error = |e| ImageError::Open(path.to_path_buf(), e);
+
+    let file = fs
+        .open(path)
+        .map_err(|e| to_image_error(image::ImageError::IoError(e)))?;
+    let mut reader = BufReader::new(file);
+
+    let head = reader
+        .fill_buf()
+        .map_err(|e| to_image_error(image::ImageError::IoError(e)))?;
+    if is_avif(head) {
+        let mut contents = Vec::new();
+        reader
+            .read_to_end(&mut contents)
+            .map_err(|e| to_image_error(image::ImageError::IoError(e)))?;
+        return avif_dimensions(&contents).ok_or_else(|| {
+            to_image_error(image::ImageError::Decoding(DecodingError::new(
+                ImageFormat::Avif.into(),
+                "The image size is missing",
+            )))
+        });
     }
 
-    1
+    image::io::Reader::new(reader)
+        .with_guessed_format()
+        .map_err(|e| to_image_error(image::ImageError::IoError(e)))?
+        .into_dimensions()
+        .map_err(to_image_error)
+}
+
+fn is_avif(contents: &[u8]) -> bool {
+    contents.get(4..8) == Some(b"ftyp") && contents.get(8..12) == Some(b"avif")
+}
+
+// The size in the "ispe" box of an AVIF, at meta/iprp/ipco/ispe. None if it's
+// missing, or if the boxes don't add up, for example since the file is
+// truncated.
+fn avif_dimensions(contents: &[u8]) -> Option<(u32, u32)> {
+    let top_level = bmff_boxes(contents)?;
+    top_level.iter().find(|(kind, _)| kind == b"mdat")?;
+    // Starts with a version and flags
+    let meta = find_bmff_box(&top_level, b"meta")?.get(4..)?;
+    let iprp = find_bmff_box(&bmff_boxes(meta)?, b"iprp")?;
+    let ipco = find_bmff_box(&bmff_boxes(iprp)?, b"ipco")?;
+    let ispe = find_bmff_box(&bmff_boxes(ipco)?, b"ispe")?;
+
+    let read_u32 = |offset: usize| {
+        let bytes = ispe.get(offset..offset + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?))
+    };
+    Some((read_u32(4)?, read_u32(8)?))
+}
+
+fn find_bmff_box<'a>(
+    boxes: &[(&[u8], &'a [u8])],
+    kind: &[u8; 4],
+) -> Option<&'a [u8]> {
+    boxes
+        .iter()
+        .find(|(other_kind, _)| other_kind == kind)
+        .map(|(_, contents)| *contents)
+}
+
+// The types and contents of the boxes that `data` consists of, in the ISO
+// base media file format that AVIF is based on, or None unless they fill it
+// exactly
+fn bmff_boxes(mut data: &[u8]) -> Option<Vec<(&[u8], &[u8])>> {
+    let mut boxes = Vec::new();
+    while !data.is_empty() {
+        let size = u32::from_be_bytes(data.get(..4)?.try_into().ok()?);
+        let kind = data.get(4..8)?;
+        let (header_size, size) = match size {
+            // Extends to the end
+            0 => (8, data.len()),
+            1 => {
+                let size = data.get(8..16)?.try_into().ok()?;
+                (16, usize::try_from(u64::from_be_bytes(size)).ok()?)
+            }
+            size => (8, size as usize),
+        };
+        boxes.push((kind, data.get(header_size..size)?));
+        data = &data[size..];
+    }
+    Some(boxes)
 }
 
 fn rotate_image(image: DynamicImage, orientation: u16) -> Option<DynamicImage> {
@@ -88,51 +477,1399 @@ fn rotate_image(image: DynamicImage, orientation: u16) -> Option<DynamicImage> {
     }
 }
 
-fn dimensions_from_settings(settings: &Settings) -> (u32, u32) {
-    match settings.image_quality {
-        ImageQuality::Mobile => (1024, 1024),
-        ImageQuality::Television => (1920, 1080),
-        ImageQuality::Thumbnail => (300, 300),
+// `full_size` is the size before any reduced decoding, so that the result
+// doesn't depend on how the image was decoded
+fn resize_image(
+    image: DynamicImage,
+    full_size: (u32, u32),
+    preset: &Preset,
+) -> DynamicImage {
+    let (width, height) = scaled_size(full_size, preset);
+    let scaled = if (width, height) == image.dimensions() {
+        image
+    } else {
+        scale_image(image, width, height, preset.filter, preset.pre_shrink)
+    };
+
+    match preset.resize {
+        ResizePolicy::Fill => {
+            let crop_width = preset.max_width.min(width);
+            let crop_height = preset.max_height.min(height);
+            let (x, y) =
+                crop_offset(&scaled, crop_width, crop_height, preset.crop);
+            scaled.crop_imm(x, y, crop_width, crop_height)
+        }
+        _ => scaled,
+    }
+}
+
+// How many times the target size a large image is first shrunk to
+const PRE_SHRINK_FACTOR: u32 = 2;
+
+// The cost of the filters grows with the source size, so with `pre_shrink`
+// a large photo is first shrunk by averaging blocks of pixels, which is
+// cheap, to a few times the target size, and only that is filtered
+fn scale_image(
+    image: DynamicImage,
+    width: u32,
+    height: u32,
+    filter: ResizeFilter,
+    pre_shrink: bool,
+) -> DynamicImage {
+    let image = if pre_shrink
+        && filter != ResizeFilter::Nearest
+        && image.width() > width * PRE_SHRINK_FACTOR * 2
+        && image.height() > height * PRE_SHRINK_FACTOR * 2
+    {
+        image.thumbnail_exact(
+            width * PRE_SHRINK_FACTOR,
+            height * PRE_SHRINK_FACTOR,
+        )
+    } else {
+        image
+    };
+    resize::resize(&image, width, height, filter)
+}
+
+// Where the crop of `crop_width` x `crop_height` starts. After scaling to
+// fill, the image overflows in one direction at most.
+fn crop_offset(
+    image: &DynamicImage,
+    crop_width: u32,
+    crop_height: u32,
+    mode: CropMode,
+) -> (u32, u32) {
+    let (width, height) = image.dimensions();
+    let centered = ((width - crop_width) / 2, (height - crop_height) / 2);
+
+    match mode {
+        CropMode::Center => centered,
+        CropMode::Smart if width > crop_width => {
+            let energy = edge_energy(&image.to_luma8(), true);
+            (best_window(&energy, crop_width as usize) as u32, centered.1)
+        }
+        CropMode::Smart if height > crop_height => {
+            let energy = edge_energy(&image.to_luma8(), false);
+            (
+                centered.0,
+                best_window(&energy, crop_height as usize) as u32,
+            )
+        }
+        CropMode::Smart => centered,
+    }
+}
+
+// The sums of the differences between neighbouring pixels in each column,
+// or in each row if `columns` is false. Detailed parts of a photo have
+// more of them than sky, walls and blurred background.
+fn edge_energy(gray: &GrayImage, columns: bool) -> Vec<u64> {
+    let (width, height) = gray.dimensions();
+    let mut energy = vec![0; if columns { width } else { height } as usize];
+
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = gray.get_pixel(x, y)[0];
+            let mut difference = 0;
+            if x + 1 < width {
+                difference +=
+                    pixel.abs_diff(gray.get_pixel(x + 1, y)[0]) as u64;
+            }
+            if y + 1 < height {
+                difference +=
+                    pixel.abs_diff(gray.get_pixel(x, y + 1)[0]) as u64;
+            }
+            energy[if columns { x } else { y } as usize] += difference;
+        }
+    }
+
+    energy
+}
+
+// The start of the `length` consecutive entries with the highest sum. Of
+// equal sums, the most central one wins, so that an image without any
+// edges is cropped like with CropMode::Center.
+fn best_window(energy: &[u64], length: usize) -> usize {
+    let last_start = energy.len() - length;
+    let center = last_start / 2;
+    let distance = |start: usize| start.abs_diff(center);
+
+    let mut sum: u64 = energy[..length].iter().sum();
+    let mut best = (sum, 0);
+    for start in 1..=last_start {
+        sum = sum + energy[start + length - 1] - energy[start - 1];
+        if sum > best.0 || (sum == best.0 && distance(start) < distance(best.1))
+        {
+            best = (sum, start);
+        }
+    }
+
+    best.1
+}
+
+// The size that an image of `size` is scaled to, before any cropping
+fn scaled_size(size: (u32, u32), preset: &Preset) -> (u32, u32) {
+    let (width, height) = (size.0 as f64, size.1 as f64);
+    let (max_width, max_height) =
+        (preset.max_width as f64, preset.max_height as f64);
+
+    let scale = match preset.resize {
+        ResizePolicy::Fit => {
+            (max_width / width).min(max_height / height).min(1.0)
+        }
+        ResizePolicy::FitUpscale => {
+            (max_width / width).min(max_height / height)
+        }
+        ResizePolicy::Fill => (max_width / width).max(max_height / height),
+        ResizePolicy::LongEdge => max_width.max(max_height) / width.max(height),
+        ResizePolicy::ShortEdge => {
+            max_width.min(max_height) / width.min(height)
+        }
+    };
+
+    let scaled = |length: f64| ((length * scale).round() as u32).max(1);
+    (scaled(width), scaled(height))
+}
+
+// Converts the pixels from the colour space of the ICC profile `icc` to
+// sRGB. The image is left as it is if the profile can't be read or isn't an
+// RGB one, or if the image is grayscale.
+fn convert_to_srgb(image: DynamicImage, icc: &[u8]) -> DynamicImage {
+    let profile = match qcms::Profile::new_from_slice(icc, false) {
+        Some(profile) => profile,
+        None => return image,
+    };
+    let mut srgb = qcms::Profile::new_sRGB();
+    srgb.precache_output_transform();
+    let transform = |data_type| {
+        qcms::Transform::new(
+            &profile,
+            &srgb,
+            data_type,
+            qcms::Intent::default(),
+        )
+    };
+
+    if !image.color().has_color() {
+        image
+    } else if image.color().has_alpha() {
+        let mut rgba = image.to_rgba8();
+        match transform(DataType::RGBA8) {
+            Some(transform) => transform.apply(&mut rgba),
+            None => return image,
+        }
+        DynamicImage::ImageRgba8(rgba)
+    } else {
+        let mut rgb = image.to_rgb8();
+        match transform(DataType::RGB8) {
+            Some(transform) => transform.apply(&mut rgb),
+            None => return image,
+        }
+        DynamicImage::ImageRgb8(rgb)
     }
 }
 
-fn resize_image(image: DynamicImage, dimensions: (u32, u32)) -> DynamicImage {
-    let (width, height) = dimensions;
-    image.resize(width, height, Gaussian)
+// What is embedded in the destination image besides the pixels
+struct Metadata {
+    exif: Option<Vec<u8>>,
+    icc: Option<Vec<u8>>,
 }
 
+// Returns the quality that the image was encoded with
 fn encode_and_save_image(
     image: DynamicImage,
+    kept: Metadata,
     destination_path: &Path,
     settings: &Settings,
-) -> bool {
-    let color = image.color();
-    let width = image.width();
-    let height = image.height();
-    let pixels = image.as_bytes();
-
-    let mut file = unwrap!(
-        File::create(destination_path),
-        "Could not create the image \"{}\"",
-        destination_path.display()
+    fs: &dyn FileSystem,
+) -> Result<u8, ImageError> {
+    let preset = settings.image_quality.preset();
+    let encode = |quality| encode_image(&image, &kept, quality, &preset);
+    let (encoded, quality) = match (preset.target_size, preset.target_ssim) {
+        (Some(target_size), _) => encode_to_size(encode, target_size),
+        (None, Some(target_ssim)) => {
+            let reference = luma_on_background(&image, preset.background);
+            let similarity = |encoded: &[u8]| {
+                let decoded = decode_image(encoded, preset.format)?;
+                let decoded = luma_on_background(&decoded, preset.background);
+                Ok(ssim::ssim(&reference, &decoded))
+            };
+            encode_to_ssim(encode, similarity, target_ssim)
+        }
+        (None, None) => {
+            encode(preset.quality).map(|encoded| (encoded, preset.quality))
+        }
+    }
+    .map_err(|e| ImageError::Encode(destination_path.to_path_buf(), e))?;
+
+    fs.write(destination_path, &encoded).map_err(|e| {
+        let _ = fs.remove_file(destination_path);
+        ImageError::Create(destination_path.to_path_buf(), e)
+    })?;
+    Ok(quality)
+}
+
+// How far below the target size an image may be for the search to stop
+const TARGET_SIZE_TOLERANCE: f64 = 0.05;
+
+// Searches the range of qualities of `target_size` for the highest one that
+// `encode` keeps within its size, but stops at the first one within the
+// tolerance, since the size is only roughly proportional to the quality.
+// Returns the encoded image and its quality.
+fn encode_to_size(
+    encode: impl Fn(u8) -> Result<Vec<u8>, image::ImageError>,
+    target_size: TargetSize,
+) -> Result<(Vec<u8>, u8), image::ImageError> {
+    let good_enough =
+        target_size.max_bytes as f64 * (1.0 - TARGET_SIZE_TOLERANCE);
+    let mut low = target_size.min_quality as i32;
+    let mut high = target_size.max_quality as i32;
+    let mut fitting = None;
+    let mut too_large = None;
+
+    while low <= high {
+        let quality = (low + (high - low) / 2) as u8;
+        let encoded = encode(quality)?;
+        let size = encoded.len() as u64;
+        if size <= target_size.max_bytes {
+            fitting = Some((encoded, quality));
+            if size as f64 >= good_enough {
+                break;
+            }
+            low = quality as i32 + 1;
+        } else {
+            too_large = Some((encoded, quality));
+            high = quality as i32 - 1;
+        }
+    }
+
+    // If nothing fits, the last one tried is at the minimum quality. There
+    // is none if the range is empty.
+    match fitting.or(too_large) {
+        Some(encoded) => Ok(encoded),
+        None => Ok((encode(target_size.min_quality)?, target_size.min_quality)),
+    }
+}
+
+// Searches the range of qualities of `target_ssim` for the lowest one at
+// which the `similarity` of the image that `encode` gives is at least its
+// SSIM. Returns the encoded image and its quality.
+fn encode_to_ssim(
+    encode: impl Fn(u8) -> Result<Vec<u8>, image::ImageError>,
+    similarity: impl Fn(&[u8]) -> Result<f64, image::ImageError>,
+    target_ssim: TargetSsim,
+) -> Result<(Vec<u8>, u8), image::ImageError> {
+    let mut low = target_ssim.min_quality as i32;
+    let mut high = target_ssim.max_quality as i32;
+    let mut similar = None;
+    let mut dissimilar = None;
+
+    while low <= high {
+        let quality = (low + (high - low) / 2) as u8;
+        let encoded = encode(quality)?;
+        if similarity(&encoded)? >= target_ssim.min_ssim {
+            similar = Some((encoded, quality));
+            high = quality as i32 - 1;
+        } else {
+            dissimilar = Some((encoded, quality));
+            low = quality as i32 + 1;
+        }
+    }
+
+    // If none is similar enough, the last one tried is at the maximum
+    // quality. There is none if the range is empty.
+    match similar.or(dissimilar) {
+        Some(encoded) => Ok(encoded),
+        None => Ok((encode(target_ssim.max_quality)?, target_ssim.max_quality)),
+    }
+}
+
+// Decodes an encoded destination image, to compare it with its source. The
+// WebP decoder of the image crate gets the colours of extended files wrong,
+// so libwebp decodes those.
+fn decode_image(
+    encoded: &[u8],
+    format: OutputFormat,
+) -> Result<DynamicImage, image::ImageError> {
+    let webp_error = || {
+        image::ImageError::Decoding(DecodingError::new(
+            ImageFormat::WebP.into(),
+            "Could not decode the encoded image",
+        ))
+    };
+
+    match format {
+        OutputFormat::Jpeg => {
+            image::load_from_memory_with_format(encoded, ImageFormat::Jpeg)
+        }
+        OutputFormat::Webp => {
+            let decoded = webp::Decoder::new(encoded)
+                .decode()
+                .ok_or_else(webp_error)?;
+            let (width, height) = (decoded.width(), decoded.height());
+            let pixels = decoded.to_vec();
+            let image = if decoded.is_alpha() {
+                RgbaImage::from_raw(width, height, pixels)
+                    .map(DynamicImage::ImageRgba8)
+            } else {
+                RgbImage::from_raw(width, height, pixels)
+                    .map(DynamicImage::ImageRgb8)
+            };
+            image.ok_or_else(webp_error)
+        }
+        OutputFormat::Avif => {
+            Err(image::ImageError::Decoding(DecodingError::new(
+                ImageFormat::Avif.into(),
+                "AVIF images can't be decoded",
+            )))
+        }
+    }
+}
+
+// The brightness of `image`, as shown on `background` where it's transparent
+fn luma_on_background(image: &DynamicImage, background: [u8; 3]) -> GrayImage {
+    if image.color().has_alpha() {
+        DynamicImage::ImageRgb8(flatten(&image.to_rgba8(), background))
+            .to_luma8()
+    } else {
+        image.to_luma8()
+    }
+}
+
+// The metadata is embedded in the image, except the colour profile in AVIFs,
+// since the encoder can't
+fn encode_image(
+    image: &DynamicImage,
+    kept: &Metadata,
+    quality: u8,
+    preset: &Preset,
+) -> Result<Vec<u8>, image::ImageError> {
+    let mut encoded = match preset.format {
+        OutputFormat::Jpeg => match preset.jpeg.encoder {
+            JpegEncoder::Image => {
+                encode_jpeg(image, quality, preset.background)
+            }
+            JpegEncoder::Mozjpeg => {
+                encode_mozjpeg(image, quality, preset.background, preset.jpeg)
+            }
+        },
+        OutputFormat::Webp => encode_webp(image, quality),
+        OutputFormat::Avif => encode_avif(image, quality, kept.exif.as_deref()),
+    }?;
+
+    if let Some(icc) = &kept.icc {
+        encoded = match preset.format {
+            OutputFormat::Jpeg => metadata::embed_icc_in_jpeg(&encoded, icc),
+            OutputFormat::Webp => metadata::embed_icc_in_webp(
+                &encoded,
+                icc,
+                image.width(),
+                image.height(),
+            ),
+            OutputFormat::Avif => encoded,
+        };
+    }
+    if let Some(exif) = &kept.exif {
+        encoded = match preset.format {
+            OutputFormat::Jpeg => metadata::embed_exif_in_jpeg(&encoded, exif),
+            OutputFormat::Webp => metadata::embed_exif_in_webp(
+                &encoded,
+                exif,
+                image.width(),
+                image.height(),
+            ),
+            // Already embedded by the encoder
+            OutputFormat::Avif => encoded,
+        };
+    }
+    Ok(encoded)
+}
+
+fn encode_jpeg(
+    image: &DynamicImage,
+    quality: u8,
+    background: [u8; 3],
+) -> Result<Vec<u8>, image::ImageError> {
+    let image = to_jpeg_color_type(image, background);
+    let mut encoded = Vec::new();
+    let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(
+        &mut encoded,
+        quality,
     );
-    let factor = match settings.image_quality {
-        ImageQuality::Mobile => 30,
-        ImageQuality::Television => 70,
-        ImageQuality::Thumbnail => 30,
+    encoder.encode(
+        image.as_bytes(),
+        image.width(),
+        image.height(),
+        image.color(),
+    )?;
+    Ok(encoded)
+}
+
+// MozJPEG optimizes the Huffman tables of all JPEGs, and the other options
+// are up to `options`. It reports errors by panicking, so the panics are
+// caught and returned as errors.
+fn encode_mozjpeg(
+    image: &DynamicImage,
+    quality: u8,
+    background: [u8; 3],
+    options: JpegOptions,
+) -> Result<Vec<u8>, image::ImageError> {
+    let image = to_jpeg_color_type(image, background);
+    let color_space = match image.as_ref() {
+        DynamicImage::ImageLuma8(_) => mozjpeg::ColorSpace::JCS_GRAYSCALE,
+        _ => mozjpeg::ColorSpace::JCS_RGB,
     };
 
-    let mut encoder = JpegEncoder::new_with_quality(&mut file, factor);
-    match encoder.encode(&pixels, width, height, color) {
-        Ok(()) => true,
-        Err(e) => {
-            println!(
-                "Could not convert the image \"{}\". Error \"{}\"",
-                destination_path.display(),
+    let encoded = panic::catch_unwind(|| {
+        let mut compress = mozjpeg::Compress::new(color_space);
+        if !options.trellis {
+            // Which also leaves out the progressive scans, so they are
+            // added back below
+            compress.set_fastest_defaults();
+        } else if !options.progressive {
+            compress.set_optimize_scans(false);
+        }
+        if options.progressive && !options.trellis {
+            compress.set_progressive_mode();
+        }
+        compress.set_optimize_coding(true);
+        compress.set_quality(quality as f32);
+        let pixel_size = match options.chroma_subsampling {
+            ChromaSubsampling::Yuv420 => (2, 2),
+            ChromaSubsampling::Yuv422 => (2, 1),
+            ChromaSubsampling::Yuv444 => (1, 1),
+        };
+        // Grayscale images have no colour to subsample
+        if color_space == mozjpeg::ColorSpace::JCS_RGB {
+            compress.set_chroma_sampling_pixel_sizes(pixel_size, pixel_size);
+        }
+        compress.set_size(image.width() as usize, image.height() as usize);
+
+        let mut started = compress.start_compress(Vec::new())?;
+        started.write_scanlines(image.as_bytes())?;
+        started.finish()
+    });
+
+    match encoded {
+        Ok(Ok(encoded)) => Ok(encoded),
+        Ok(Err(e)) => Err(encoding_error(ImageFormat::Jpeg, e)),
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_else(|| "MozJPEG failed".to_string());
+            Err(encoding_error(ImageFormat::Jpeg, message))
+        }
+    }
+}
+
+// JPEG has neither transparency nor more than 8 bits per sample, and the
+// encoder fails on such images
+fn to_jpeg_color_type(
+    image: &DynamicImage,
+    background: [u8; 3],
+) -> Cow<'_, DynamicImage> {
+    match image {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageRgb8(_) => {
+            Cow::Borrowed(image)
+        }
+        _ if image.color().has_alpha() => Cow::Owned(DynamicImage::ImageRgb8(
+            flatten(&image.to_rgba8(), background),
+        )),
+        _ if image.color().has_color() => {
+            Cow::Owned(DynamicImage::ImageRgb8(image.to_rgb8()))
+        }
+        _ => Cow::Owned(DynamicImage::ImageLuma8(image.to_luma8())),
+    }
+}
+
+// Blends the pixels onto the opaque `background` colour
+fn flatten(image: &RgbaImage, background: [u8; 3]) -> RgbImage {
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let [red, green, blue, alpha] = image.get_pixel(x, y).0;
+        let alpha = alpha as u32;
+        let blend = |color: u8, background: u8| {
+            ((color as u32 * alpha + background as u32 * (255 - alpha) + 127)
+                / 255) as u8
+        };
+        Rgb([
+            blend(red, background[0]),
+            blend(green, background[1]),
+            blend(blue, background[2]),
+        ])
+    })
+}
+
+// Keeps the transparency, if any
+fn encode_webp(
+    image: &DynamicImage,
+    quality: u8,
+) -> Result<Vec<u8>, image::ImageError> {
+    let encoded = if image.color().has_alpha() {
+        let rgba = image.to_rgba8();
+        webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+            .encode_simple(false, quality as f32)
+    } else {
+        let rgb = image.to_rgb8();
+        webp::Encoder::from_rgb(&rgb, rgb.width(), rgb.height())
+            .encode_simple(false, quality as f32)
+    };
+    encoded
+        .map(|encoded| encoded.to_vec())
+        .map_err(|e| encoding_error(ImageFormat::WebP, format!("{:?}", e)))
+}
+
+// From 1, the slowest and smallest, to 10. The default of ravif, 4, takes
+// many seconds for a TV sized image.
+const AVIF_SPEED: u8 = 6;
+
+// Keeps the transparency, if any
+fn encode_avif(
+    image: &DynamicImage,
+    quality: u8,
+    exif: Option<&[u8]>,
+) -> Result<Vec<u8>, image::ImageError> {
+    let mut encoder = ravif::Encoder::new()
+        .with_quality(quality as f32)
+        .with_speed(AVIF_SPEED);
+    if let Some(exif) = exif {
+        encoder = encoder.with_exif(exif);
+    }
+    let encoded = if image.color().has_alpha() {
+        let rgba = image.to_rgba8();
+        encoder.encode_rgba(ravif::Img::new(
+            rgba.as_raw().as_rgba(),
+            rgba.width() as usize,
+            rgba.height() as usize,
+        ))
+    } else {
+        let rgb = image.to_rgb8();
+        encoder.encode_rgb(ravif::Img::new(
+            rgb.as_raw().as_rgb(),
+            rgb.width() as usize,
+            rgb.height() as usize,
+        ))
+    };
+    encoded
+        .map(|encoded| encoded.avif_file)
+        .map_err(|e| encoding_error(ImageFormat::Avif, e))
+}
+
+fn encoding_error(
+    format: ImageFormat,
+    e: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> image::ImageError {
+    image::ImageError::Encoding(EncodingError::new(format.into(), e))
+}
+
+/// Why an image could not be converted. Each variant holds the path of the
+/// image that failed.
+#[derive(Debug)]
+pub enum ImageError {
+    Open(PathBuf, image::ImageError),
+    UnsupportedOrientation(PathBuf, u16),
+    Create(PathBuf, std::io::Error),
+    Encode(PathBuf, image::ImageError),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Open(path, e) => write!(
+                f,
+                "Could not open the image \"{}\" due to \"{}\"",
+                path.display(),
+                e
+            ),
+            ImageError::UnsupportedOrientation(path, orientation) => write!(
+                f,
+                "Unsupported orientation {} for \"{}\"",
+                orientation,
+                path.display()
+            ),
+            ImageError::Create(path, e) => write!(
+                f,
+                "Could not create the image \"{}\" due to \"{}\"",
+                path.display(),
                 e
+            ),
+            ImageError::Encode(path, e) => write!(
+                f,
+                "Could not convert the image \"{}\" due to \"{}\"",
+                path.display(),
+                e
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ImageError::Open(_, e) | ImageError::Encode(_, e) => Some(e),
+            ImageError::Create(_, e) => Some(e),
+            ImageError::UnsupportedOrientation(_, _) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::InMemoryFileSystem;
+    use crate::settings::{ExifGroup, ImageQuality};
+    use image::{ImageDecoder, Luma};
+
+    #[test]
+    fn scaled_size_follows_resize_policy() {
+        // 1920 x 1080
+        let preset = |resize| Preset {
+            resize,
+            ..ImageQuality::Television.preset()
+        };
+
+        let small = (640, 480);
+        assert_eq!((640, 480), scaled_size(small, &preset(ResizePolicy::Fit)));
+        assert_eq!(
+            (1440, 1080),
+            scaled_size(small, &preset(ResizePolicy::FitUpscale))
+        );
+        assert_eq!(
+            (1920, 1440),
+            scaled_size(small, &preset(ResizePolicy::Fill))
+        );
+
+        let portrait = (3000, 4000);
+        assert_eq!(
+            (810, 1080),
+            scaled_size(portrait, &preset(ResizePolicy::Fit))
+        );
+        assert_eq!(
+            (1440, 1920),
+            scaled_size(portrait, &preset(ResizePolicy::LongEdge))
+        );
+        assert_eq!(
+            (1080, 1440),
+            scaled_size(portrait, &preset(ResizePolicy::ShortEdge))
+        );
+    }
+
+    #[test]
+    fn fill_crops_to_exact_size() {
+        let image = DynamicImage::new_rgb8(640, 480);
+        let preset = Preset {
+            max_width: 200,
+            max_height: 200,
+            resize: ResizePolicy::Fill,
+            ..ImageQuality::Thumbnail.preset()
+        };
+
+        let resized = resize_image(image, (640, 480), &preset);
+
+        assert_eq!((200, 200), resized.dimensions());
+    }
+
+    #[test]
+    fn every_filter_scales_to_exact_size() {
+        for filter in all_filters() {
+            for pre_shrink in [false, true] {
+                let image = DynamicImage::new_rgb8(4000, 3000);
+                assert_eq!(
+                    (300, 225),
+                    scale_image(image, 300, 225, filter, pre_shrink)
+                        .dimensions()
+                );
+
+                let image = DynamicImage::new_rgb8(100, 50);
+                assert_eq!(
+                    (300, 150),
+                    scale_image(image, 300, 150, filter, pre_shrink)
+                        .dimensions()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pre_shrink_keeps_colors() {
+        let image =
+            DynamicImage::ImageLuma8(GrayImage::from_fn(1000, 1000, |x, _| {
+                if x < 500 {
+                    Luma([0])
+                } else {
+                    Luma([255])
+                }
+            }));
+
+        let scaled =
+            scale_image(image, 100, 100, ResizeFilter::CatmullRom, true)
+                .to_luma8();
+
+        assert_eq!(0, scaled.get_pixel(10, 50)[0]);
+        assert_eq!(255, scaled.get_pixel(90, 50)[0]);
+    }
+
+    #[test]
+    fn images_are_only_pre_shrunk_when_asked() {
+        let image =
+            DynamicImage::ImageLuma8(GrayImage::from_fn(1000, 1000, |x, y| {
+                Luma([((x * 31 + y * 17) % 256) as u8])
+            }));
+        let filter = ResizeFilter::default();
+
+        let filtered = resize::resize(&image, 100, 100, filter);
+
+        assert_eq!(
+            filtered,
+            scale_image(image.clone(), 100, 100, filter, false)
+        );
+        assert_ne!(filtered, scale_image(image, 100, 100, filter, true));
+    }
+
+    fn all_filters() -> [ResizeFilter; 5] {
+        [
+            ResizeFilter::Nearest,
+            ResizeFilter::Bilinear,
+            ResizeFilter::CatmullRom,
+            ResizeFilter::Gaussian,
+            ResizeFilter::Lanczos3,
+        ]
+    }
+
+    #[test]
+    fn large_jpeg_is_decoded_reduced() {
+        let contents = std::fs::read(
+            Path::new("test_resources").join("large-with-exif.jpg"),
+        )
+        .unwrap();
+        let preset = ImageQuality::Thumbnail.preset();
+
+        let (image, full_size) =
+            read_reduced_image(Path::new("large.jpg"), &contents, |size| {
+                scaled_size(size, &preset)
+            })
+            .unwrap();
+
+        assert_eq!((4928, 3264), full_size);
+        assert_eq!((616, 408), image.dimensions());
+        assert_eq!(
+            (300, 199),
+            resize_image(image, full_size, &preset).dimensions()
+        );
+    }
+
+    #[test]
+    fn jpeg_is_decoded_reduced_when_only_one_side_is_large_enough() {
+        let contents = std::fs::read(
+            Path::new("test_resources").join("large-with-exif.jpg"),
+        )
+        .unwrap();
+
+        // An eighth is 616x408, wide enough but not tall enough
+        let (image, full_size) =
+            read_reduced_image(Path::new("large.jpg"), &contents, |_| {
+                (500, 700)
+            })
+            .unwrap();
+
+        assert_eq!((4928, 3264), full_size);
+        assert_eq!((1232, 816), image.dimensions());
+    }
+
+    #[test]
+    fn jpeg_is_decoded_whole_when_target_is_large() {
+        let contents = std::fs::read(
+            Path::new("test_resources").join("large-with-exif.jpg"),
+        )
+        .unwrap();
+
+        let (image, full_size) =
+            read_reduced_image(Path::new("large.jpg"), &contents, |size| size)
+                .unwrap();
+
+        assert_eq!(full_size, image.dimensions());
+    }
+
+    #[test]
+    fn images_are_encoded_in_each_format() {
+        let image =
+            DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |x, y| {
+                ::image::Rgb([(x * 4) as u8, (y * 5) as u8, 128])
+            }));
+
+        let jpeg = encode_jpeg(&image, 70, [255, 255, 255]).unwrap();
+        assert_eq!(ImageFormat::Jpeg, image::guess_format(&jpeg).unwrap());
+
+        let webp = encode_webp(&image, 70).unwrap();
+        let decoded = image::load_from_memory(&webp).unwrap();
+        assert_eq!((64, 48), decoded.dimensions());
+
+        let avif = encode_avif(&image, 70, None).unwrap();
+        assert!(is_avif(&avif));
+        assert_eq!(Some((64, 48)), avif_dimensions(&avif));
+    }
+
+    #[test]
+    fn mozjpeg_writes_progressive_jpegs() {
+        let image =
+            DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |x, y| {
+                ::image::Rgb([(x * 4) as u8, (y * 5) as u8, 128])
+            }));
+
+        for trellis in [false, true] {
+            for progressive in [false, true] {
+                let options = JpegOptions {
+                    encoder: JpegEncoder::Mozjpeg,
+                    progressive,
+                    trellis,
+                    chroma_subsampling: ChromaSubsampling::Yuv444,
+                };
+
+                let jpeg = encode_mozjpeg(&image, 70, [255, 255, 255], options)
+                    .unwrap();
+
+                // The start of frame marker of progressive JPEGs
+                let is_progressive =
+                    jpeg.windows(2).any(|marker| marker == [0xFF, 0xC2]);
+                assert_eq!(progressive, is_progressive);
+                let decoded = image::load_from_memory(&jpeg).unwrap();
+                assert_eq!((64, 48), decoded.dimensions());
+            }
+        }
+    }
+
+    #[test]
+    fn mozjpeg_is_smaller_than_image_encoder() {
+        let contents =
+            std::fs::read("test_resources/large-without-exif.jpg").unwrap();
+        let image = image::load_from_memory(&contents)
+            .unwrap()
+            .thumbnail(800, 800);
+        let options = JpegOptions {
+            encoder: JpegEncoder::Mozjpeg,
+            ..JpegOptions::default()
+        };
+
+        let baseline = encode_jpeg(&image, 70, [255, 255, 255]).unwrap();
+        let optimized =
+            encode_mozjpeg(&image, 70, [255, 255, 255], options).unwrap();
+
+        assert!(optimized.len() < baseline.len());
+    }
+
+    #[test]
+    fn mozjpeg_encodes_grayscale_images() {
+        let image =
+            DynamicImage::ImageLuma8(GrayImage::from_fn(32, 32, |x, _| {
+                Luma([(x * 8) as u8])
+            }));
+
+        let jpeg =
+            encode_mozjpeg(&image, 70, [255, 255, 255], JpegOptions::default())
+                .unwrap();
+
+        let decoded = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!(::image::ColorType::L8, decoded.color());
+    }
+
+    #[test]
+    fn mozjpeg_failures_are_errors() {
+        let image = DynamicImage::new_rgb8(0, 0);
+
+        let result =
+            encode_mozjpeg(&image, 70, [255, 255, 255], JpegOptions::default());
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn encode_to_size_finds_highest_fitting_quality() {
+        // 100 bytes per step of quality
+        let encode = |quality| Ok(vec![0; quality as usize * 100]);
+        let target_size = |max_bytes| TargetSize {
+            max_bytes,
+            min_quality: 10,
+            max_quality: 90,
+        };
+
+        let quality = |max_bytes| {
+            let (encoded, quality) =
+                encode_to_size(encode, target_size(max_bytes)).unwrap();
+            assert_eq!(quality as usize * 100, encoded.len());
+            quality
+        };
+        assert_eq!(50, quality(5050));
+        assert_eq!(19, quality(2000));
+        assert_eq!(90, quality(50000));
+        // Nothing fits
+        assert_eq!(10, quality(500));
+    }
+
+    #[test]
+    fn encode_to_ssim_finds_lowest_similar_quality() {
+        let encode = |quality| Ok(vec![quality]);
+        let similarity = |encoded: &[u8]| Ok(encoded[0] as f64 / 100.0);
+        let target_ssim = |min_ssim| TargetSsim {
+            min_ssim,
+            min_quality: 10,
+            max_quality: 90,
+        };
+
+        let quality = |min_ssim| {
+            encode_to_ssim(encode, similarity, target_ssim(min_ssim))
+                .unwrap()
+                .1
+        };
+        assert_eq!(75, quality(0.75));
+        assert_eq!(10, quality(0.05));
+        // Nothing is similar enough
+        assert_eq!(90, quality(0.99));
+    }
+
+    #[test]
+    fn images_are_compressed_to_target_size() {
+        let fs = InMemoryFileSystem::new();
+        let contents =
+            std::fs::read("test_resources/large-without-exif.jpg").unwrap();
+        fs.add_file(Path::new("/large.jpg"), &contents);
+        let convert = |target_size| {
+            let mut preset = ImageQuality::Mobile.preset();
+            preset.target_size = target_size;
+            let settings =
+                Settings::builder(ImageQuality::Custom(preset)).build();
+            open_compress_and_save_image_in(
+                Path::new("/large.jpg"),
+                Path::new("/converted.jpg"),
+                &settings,
+                &fs,
+            )
+            .unwrap();
+            fs.read(Path::new("/converted.jpg")).unwrap().len() as u64
+        };
+
+        let at_quality_90 = convert(Some(TargetSize {
+            max_bytes: u64::MAX,
+            min_quality: 90,
+            max_quality: 90,
+        }));
+        let max_bytes = at_quality_90 / 2;
+        let at_target_size = convert(Some(TargetSize {
+            max_bytes,
+            min_quality: 10,
+            max_quality: 90,
+        }));
+
+        assert!(at_target_size <= max_bytes);
+        assert!(at_target_size as f64 >= max_bytes as f64 * 0.8);
+    }
+
+    #[test]
+    fn images_are_compressed_to_target_ssim() {
+        let fs = InMemoryFileSystem::new();
+        let contents =
+            std::fs::read("test_resources/large-without-exif.jpg").unwrap();
+        fs.add_file(Path::new("/large.jpg"), &contents);
+        let convert = |target_ssim| {
+            let mut preset = ImageQuality::Thumbnail.preset();
+            preset.target_ssim = target_ssim;
+            let settings =
+                Settings::builder(ImageQuality::Custom(preset)).build();
+            open_compress_and_save_image_in(
+                Path::new("/large.jpg"),
+                Path::new("/converted.jpg"),
+                &settings,
+                &fs,
+            )
+            .unwrap()
+        };
+
+        let strict = convert(Some(TargetSsim::new(0.99)));
+        let lenient = convert(Some(TargetSsim::new(0.9)));
+        let converted = image::load_from_memory(
+            &fs.read(Path::new("/converted.jpg")).unwrap(),
+        )
+        .unwrap();
+        let reference = resize::resize(
+            &image::load_from_memory(&contents).unwrap(),
+            converted.width(),
+            converted.height(),
+            ImageQuality::Thumbnail.preset().filter,
+        );
+
+        assert!(lenient < strict);
+        assert!(
+            ssim::ssim(&reference.to_luma8(), &converted.to_luma8()) > 0.85
+        );
+    }
+
+    #[test]
+    fn raw_images_are_converted_from_their_preview_and_rotated() {
+        let fs = InMemoryFileSystem::new();
+        let contents =
+            std::fs::read("test_resources/small-with-preview.nef").unwrap();
+        fs.add_file(Path::new("/photo.nef"), &contents);
+        let settings = Settings::builder(ImageQuality::Thumbnail).build();
+
+        open_compress_and_save_image_in(
+            Path::new("/photo.nef"),
+            Path::new("/photo.jpg"),
+            &settings,
+            &fs,
+        )
+        .unwrap();
+
+        // The 45x30 preview, rotated by the orientation of the RAW file
+        let converted =
+            image::load_from_memory(&fs.read(Path::new("/photo.jpg")).unwrap())
+                .unwrap();
+        assert_eq!((30, 45), converted.dimensions());
+    }
+
+    #[test]
+    fn raw_images_without_preview_are_developed() {
+        let fs = InMemoryFileSystem::new();
+        let contents =
+            std::fs::read("test_resources/small-without-preview.dng").unwrap();
+        fs.add_file(Path::new("/photo.dng"), &contents);
+        fs.add_file(Path::new("/broken.dng"), b"II*\0\x08\0\0\0\0\0");
+        let settings = Settings::builder(ImageQuality::Thumbnail).build();
+        let convert = |path: &str| {
+            open_compress_and_save_image_in(
+                Path::new(path),
+                Path::new("/photo.jpg"),
+                &settings,
+                &fs,
+            )
+        };
+
+        convert("/photo.dng").unwrap();
+        let converted =
+            image::load_from_memory(&fs.read(Path::new("/photo.jpg")).unwrap())
+                .unwrap()
+                .to_rgb8();
+        assert_eq!((32, 24), converted.dimensions());
+        let left = converted.get_pixel(4, 12);
+        assert!(left[0] > 200 && left[2] < 60, "{:?}", left);
+
+        assert!(matches!(
+            convert("/broken.dng"),
+            Err(ImageError::Open(path, _)) if path == Path::new("/broken.dng")
+        ));
+    }
+
+    #[test]
+    fn transparent_png_is_flattened_onto_background() {
+        // Opaque red at the top, transparent at the bottom
+        let image = RgbaImage::from_fn(32, 32, |_, y| {
+            if y < 16 {
+                ::image::Rgba([255, 0, 0, 255])
+            } else {
+                ::image::Rgba([255, 0, 0, 0])
+            }
+        });
+        let fs = InMemoryFileSystem::new();
+        add_png(&fs, "/logo.png", &DynamicImage::ImageRgba8(image));
+        let mut preset = ImageQuality::Thumbnail.preset();
+        preset.background = [0, 0, 255];
+        let settings = Settings::builder(ImageQuality::Custom(preset)).build();
+
+        open_compress_and_save_image_in(
+            Path::new("/logo.png"),
+            Path::new("/logo.jpg"),
+            &settings,
+            &fs,
+        )
+        .unwrap();
+
+        let converted =
+            image::load_from_memory(&fs.read(Path::new("/logo.jpg")).unwrap())
+                .unwrap()
+                .to_rgb8();
+        let is_close = |pixel: &Rgb<u8>, expected: [u8; 3]| {
+            pixel
+                .0
+                .iter()
+                .zip(expected)
+                .all(|(a, b)| a.abs_diff(b) < 20)
+        };
+        assert!(is_close(converted.get_pixel(16, 4), [255, 0, 0]));
+        assert!(is_close(converted.get_pixel(16, 28), [0, 0, 255]));
+    }
+
+    #[test]
+    fn sixteen_bit_png_is_converted() {
+        let image = ::image::ImageBuffer::from_fn(40, 30, |x, _| {
+            ::image::Rgb([x as u16 * 1000, 30000, 65535])
+        });
+        let fs = InMemoryFileSystem::new();
+        add_png(&fs, "/deep.png", &DynamicImage::ImageRgb16(image));
+        let gray = ::image::ImageBuffer::from_fn(40, 30, |x, _| {
+            ::image::LumaA([x as u16 * 1000, 65535])
+        });
+        add_png(&fs, "/gray.png", &DynamicImage::ImageLumaA16(gray));
+        let settings = Settings::builder(ImageQuality::Thumbnail).build();
+
+        for name in ["deep", "gray"] {
+            let source = format!("/{}.png", name);
+            let destination = format!("/{}.jpg", name);
+            open_compress_and_save_image_in(
+                Path::new(&source),
+                Path::new(&destination),
+                &settings,
+                &fs,
+            )
+            .unwrap();
+
+            let converted = fs.read(Path::new(&destination)).unwrap();
+            let converted = image::load_from_memory(&converted).unwrap();
+            assert_eq!((40, 30), converted.dimensions());
+        }
+    }
+
+    #[test]
+    fn kept_exif_is_in_converted_image() {
+        let fs = InMemoryFileSystem::new();
+        let contents =
+            std::fs::read("test_resources/small-with-exif.jpg").unwrap();
+        fs.add_file(Path::new("/small.jpg"), &contents);
+        let settings = Settings::builder(ImageQuality::Thumbnail)
+            .keep_exif(&[ExifGroup::Date])
+            .build();
+
+        open_compress_and_save_image_in(
+            Path::new("/small.jpg"),
+            Path::new("/converted.jpg"),
+            &settings,
+            &fs,
+        )
+        .unwrap();
+
+        let source = read_exif_summary(Path::new("/small.jpg"), &fs);
+        let converted = read_exif_summary(Path::new("/converted.jpg"), &fs);
+        assert!(source.capture_time.is_some());
+        assert_eq!(source.capture_time, converted.capture_time);
+        assert_eq!(None, converted.camera_model);
+        assert_eq!(Some(1), converted.orientation);
+        assert!(verify_image(Path::new("/converted.jpg"), &fs).is_ok());
+    }
+
+    #[test]
+    fn kept_exif_is_in_avif_images() {
+        let fs = InMemoryFileSystem::new();
+        let contents =
+            std::fs::read("test_resources/small-with-exif.jpg").unwrap();
+        fs.add_file(Path::new("/small.jpg"), &contents);
+        let preset = Preset {
+            format: OutputFormat::Avif,
+            ..ImageQuality::Thumbnail.preset()
+        };
+        let settings = Settings::builder(ImageQuality::Custom(preset))
+            .keep_exif(&[ExifGroup::Date])
+            .build();
+
+        open_compress_and_save_image_in(
+            Path::new("/small.jpg"),
+            Path::new("/converted.avif"),
+            &settings,
+            &fs,
+        )
+        .unwrap();
+
+        let source = read_exif_summary(Path::new("/small.jpg"), &fs);
+        let converted = read_exif_summary(Path::new("/converted.avif"), &fs);
+        assert!(source.capture_time.is_some());
+        assert_eq!(source.capture_time, converted.capture_time);
+        assert_eq!(None, converted.camera_model);
+    }
+
+    #[test]
+    fn webp_keeps_transparency() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+            16,
+            16,
+            ::image::Rgba([0, 128, 0, 100]),
+        ));
+
+        let webp = encode_webp(&image, 90).unwrap();
+
+        let decoded = image::load_from_memory(&webp).unwrap();
+        assert!(decoded.color().has_alpha());
+        assert!(decoded.to_rgba8().get_pixel(8, 8)[3].abs_diff(100) < 10);
+    }
+
+    fn add_png(fs: &InMemoryFileSystem, path: &str, image: &DynamicImage) {
+        let mut contents = Cursor::new(Vec::new());
+        image
+            .write_to(&mut contents, ::image::ImageOutputFormat::Png)
+            .unwrap();
+        fs.add_file(Path::new(path), contents.get_ref());
+    }
+
+    #[test]
+    fn truncated_avif_fails_verification() {
+        let image = DynamicImage::new_rgb8(32, 32);
+        let avif = encode_avif(&image, 50, None).unwrap();
+        let fs = InMemoryFileSystem::new();
+        fs.add_file(Path::new("/whole.avif"), &avif);
+        fs.add_file(Path::new("/truncated.avif"), &avif[..avif.len() - 10]);
+
+        assert_eq!(Ok(()), verify_image(Path::new("/whole.avif"), &fs));
+        assert_eq!(
+            Ok((32, 32)),
+            image_dimensions(Path::new("/whole.avif"), &fs)
+                .map_err(|e| e.to_string())
+        );
+        assert!(verify_image(Path::new("/truncated.avif"), &fs).is_err());
+    }
+
+    #[test]
+    fn colors_are_converted_to_srgb() {
+        let fs = InMemoryFileSystem::new();
+        add_red_jpeg_with_swapped_profile(&fs, "/red.jpg");
+        let settings = Settings::builder(ImageQuality::Thumbnail).build();
+
+        open_compress_and_save_image_in(
+            Path::new("/red.jpg"),
+            Path::new("/converted.jpg"),
+            &settings,
+            &fs,
+        )
+        .unwrap();
+
+        let converted = fs.read(Path::new("/converted.jpg")).unwrap();
+        let pixel = *image::load_from_memory(&converted)
+            .unwrap()
+            .to_rgb8()
+            .get_pixel(8, 8);
+        assert!(pixel[0] < 30 && pixel[1] > 225 && pixel[2] < 30);
+        assert_eq!(None, metadata::icc_profile(&converted));
+    }
+
+    #[test]
+    fn color_profile_is_kept() {
+        let fs = InMemoryFileSystem::new();
+        add_red_jpeg_with_swapped_profile(&fs, "/red.jpg");
+
+        for format in [OutputFormat::Jpeg, OutputFormat::Webp] {
+            let mut preset = ImageQuality::Thumbnail.preset();
+            preset.format = format;
+            let settings = Settings::builder(ImageQuality::Custom(preset))
+                .color_profile(ColorProfile::Keep)
+                .build();
+
+            open_compress_and_save_image_in(
+                Path::new("/red.jpg"),
+                Path::new("/kept"),
+                &settings,
+                &fs,
+            )
+            .unwrap();
+
+            let kept = fs.read(Path::new("/kept")).unwrap();
+            let icc = match format {
+                OutputFormat::Jpeg => {
+                    image::codecs::jpeg::JpegDecoder::new(Cursor::new(&kept))
+                        .unwrap()
+                        .icc_profile()
+                }
+                _ => image::codecs::webp::WebPDecoder::new(Cursor::new(&kept))
+                    .unwrap()
+                    .icc_profile(),
+            };
+            assert_eq!(Some(swapped_profile()), icc);
+            // The WebP decoder of the image crate gets the colours of
+            // extended files wrong
+            let pixel = match format {
+                OutputFormat::Jpeg => {
+                    image::load_from_memory(&kept)
+                        .unwrap()
+                        .to_rgb8()
+                        .get_pixel(8, 8)
+                        .0
+                }
+                _ => {
+                    let decoded = webp::Decoder::new(&kept).decode().unwrap();
+                    [decoded[0], decoded[1], decoded[2]]
+                }
+            };
+            assert!(pixel[0] > 225 && pixel[1] < 30 && pixel[2] < 30);
+        }
+    }
+
+    // A red JPEG with a profile that says its red is really green
+    fn add_red_jpeg_with_swapped_profile(fs: &InMemoryFileSystem, path: &str) {
+        let image = RgbImage::from_pixel(16, 16, Rgb([255, 0, 0]));
+        let jpeg =
+            encode_jpeg(&DynamicImage::ImageRgb8(image), 90, [0; 3]).unwrap();
+        let jpeg = metadata::embed_icc_in_jpeg(&jpeg, &swapped_profile());
+        fs.add_file(Path::new(path), &jpeg);
+    }
+
+    // The D50 colorants of sRGB, with the red and green ones swapped
+    fn swapped_profile() -> Vec<u8> {
+        let xyz = |x: f64, y: f64, z: f64| {
+            let mut tag = b"XYZ \0\0\0\0".to_vec();
+            for value in [x, y, z] {
+                let fixed = (value * 65536.0).round() as i32;
+                tag.extend_from_slice(&fixed.to_be_bytes());
+            }
+            tag
+        };
+        // A gamma of 2.2
+        let curve = b"curv\0\0\0\0\0\0\0\x01\x02\x33\0\0".to_vec();
+        let tags: Vec<(&[u8], Vec<u8>)> = vec![
+            (b"rXYZ", xyz(0.3851, 0.7169, 0.0971)),
+            (b"gXYZ", xyz(0.4361, 0.2225, 0.0139)),
+            (b"bXYZ", xyz(0.1431, 0.0606, 0.7141)),
+            (b"wtpt", xyz(0.9642, 1.0, 0.8249)),
+            (b"rTRC", curve.clone()),
+            (b"gTRC", curve.clone()),
+            (b"bTRC", curve),
+        ];
+
+        let mut data = Vec::new();
+        let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+        let data_start = 128 + 4 + 12 * tags.len();
+        for (signature, tag) in &tags {
+            table.extend_from_slice(signature);
+            table.extend_from_slice(
+                &((data_start + data.len()) as u32).to_be_bytes(),
             );
-            let _ = std::fs::remove_file(destination_path);
-            false
+            table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+            data.extend_from_slice(tag);
         }
+
+        let mut header = vec![0; 128];
+        let size = (128 + table.len() + data.len()) as u32;
+        header[0..4].copy_from_slice(&size.to_be_bytes());
+        header[8..12].copy_from_slice(&[2, 0x10, 0, 0]);
+        header[12..16].copy_from_slice(b"mntr");
+        header[16..20].copy_from_slice(b"RGB ");
+        header[20..24].copy_from_slice(b"XYZ ");
+        header[36..40].copy_from_slice(b"acsp");
+        [header, table, data].concat()
+    }
+
+    #[test]
+    fn smart_crop_keeps_detailed_part() {
+        // Flat, except for a checkerboard in the right quarter
+        let image = GrayImage::from_fn(400, 100, |x, y| {
+            if x >= 300 && (x + y) % 2 == 0 {
+                Luma([255])
+            } else {
+                Luma([0])
+            }
+        });
+        let image = DynamicImage::ImageLuma8(image);
+
+        assert_eq!((150, 0), crop_offset(&image, 100, 100, CropMode::Center));
+        assert_eq!((300, 0), crop_offset(&image, 100, 100, CropMode::Smart));
+    }
+
+    #[test]
+    fn smart_crop_of_flat_image_is_centered() {
+        let image = DynamicImage::new_luma8(100, 400);
+
+        assert_eq!((0, 150), crop_offset(&image, 100, 100, CropMode::Smart));
     }
 }
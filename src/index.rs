@@ -0,0 +1,146 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use unwrap::unwrap;
+
+use crate::file_names;
+use crate::file_system::FileSystem;
+use crate::media_handler::HandlerRegistry;
+
+/// Written as `index.json` to each destination directory, so that viewers
+/// don't have to list the directory and parse the file names.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Index {
+    /// Sorted by name, which sorts images by capture time
+    pub files: Vec<FileEntry>,
+    pub directories: Vec<DirectoryEntry>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileEntry {
+    /// The name in the destination
+    pub name: String,
+    pub source_name: String,
+    /// In bytes
+    pub size: u64,
+    /// "yyyy-mm-dd hh:mm:ss"
+    pub capture_time: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// The exif orientation of the source. It's already applied to the
+    /// destination file.
+    pub orientation: Option<u16>,
+    pub camera_model: Option<String>,
+}
+
+impl FileEntry {
+    /// An entry with only the names and the size filled in
+    pub fn new(
+        source_path: &Path,
+        destination_path: &Path,
+        fs: &dyn FileSystem,
+    ) -> io::Result<FileEntry> {
+        Ok(FileEntry {
+            name: file_name(destination_path),
+            source_name: file_name(source_path),
+            size: fs.file_size(destination_path)?,
+            ..FileEntry::default()
+        })
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DirectoryEntry {
+    pub name: String,
+    /// The number of files directly inside it
+    pub files: usize,
+    /// The number of directories directly inside it
+    pub directories: usize,
+}
+
+pub fn index_path(destination_path: &Path) -> PathBuf {
+    destination_path.join(file_names::INDEX_FILE_NAME)
+}
+
+/// Describes the files in `destination_path`, which must already be mapped.
+/// `source_of` tells which source file a destination file was created from.
+pub fn build_index(
+    destination_path: &Path,
+    source_of: &dyn Fn(&Path) -> Option<PathBuf>,
+    registry: &HandlerRegistry,
+    fs: &dyn FileSystem,
+) -> io::Result<Index> {
+    let mut index = Index::default();
+
+    for destination_entry_path in sorted_entries(destination_path, fs)? {
+        if fs.is_dir(&destination_entry_path) {
+            let entry_paths = sorted_entries(&destination_entry_path, fs)?;
+            let directories =
+                entry_paths.iter().filter(|path| fs.is_dir(path)).count();
+            index.directories.push(DirectoryEntry {
+                name: file_name(&destination_entry_path),
+                files: entry_paths.len() - directories,
+                directories,
+            });
+            continue;
+        }
+
+        // Files that failed are left out
+        let source_file_path = match source_of(&destination_entry_path) {
+            Some(source_file_path) => source_file_path,
+            None => continue,
+        };
+        if let Some(handler) = registry.handler_for_source(&source_file_path) {
+            index.files.push(handler.index_entry(
+                &source_file_path,
+                &destination_entry_path,
+                fs,
+            )?);
+        }
+    }
+
+    Ok(index)
+}
+
+pub fn read_index(
+    destination_path: &Path,
+    fs: &dyn FileSystem,
+) -> Option<Index> {
+    let contents = fs.read(&index_path(destination_path)).ok()?;
+    serde_json::from_slice(&contents).ok()
+}
+
+pub fn write_index(
+    destination_path: &Path,
+    index: &Index,
+    fs: &dyn FileSystem,
+) -> io::Result<()> {
+    let contents = serde_json::to_vec_pretty(index)?;
+    fs.write(&index_path(destination_path), &contents)
+}
+
+// The entries that aren't reserved, sorted by name
+fn sorted_entries(
+    path: &Path,
+    fs: &dyn FileSystem,
+) -> io::Result<Vec<PathBuf>> {
+    let mut entry_paths: Vec<PathBuf> = fs
+        .read_dir(path)?
+        .into_iter()
+        .filter(|entry_path| {
+            !file_names::is_reserved_destination_name(&file_name(entry_path))
+        })
+        .collect();
+    entry_paths.sort();
+    Ok(entry_paths)
+}
+
+fn file_name(path: &Path) -> String {
+    let file_name = unwrap!(
+        path.file_name(),
+        "Could not get the file name of \"{}\"",
+        path.display()
+    );
+    file_name.to_string_lossy().into_owned()
+}
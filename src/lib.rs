@@ -0,0 +1,59 @@
+//! Maps a directory structure of images and videos into an equivalent
+//! structure where images are downscaled, compressed and get their exif
+//! date/time prepended to their file names.
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use image_mapper::{map_directory, ImageQuality, Settings};
+//!
+//! let settings = Settings::builder(ImageQuality::Television)
+//!     .include_videos(true)
+//!     .build();
+//! let summary =
+//!     map_directory(Path::new("/photos"), Path::new("/tv"), settings)?;
+//! println!("Created {} files", summary.created_files);
+//! # Ok::<(), image_mapper::MapperError>(())
+//! ```
+//!
+//! The mapper logs what it does through the [`log`] crate, so attach a
+//! logger to see it, or use [`map_directory_with_progress`].
+
+pub mod config;
+pub mod duplicates;
+pub mod file_names;
+pub mod file_system;
+pub mod gallery;
+pub mod image;
+pub mod index;
+pub mod mapper;
+pub mod marker;
+pub mod media_handler;
+pub mod metadata;
+pub mod raw;
+pub mod resize;
+pub mod settings;
+pub mod ssim;
+pub mod verifier;
+
+pub use crate::config::{read_config, Config, ConfigError};
+pub use crate::duplicates::{
+    find_duplicates, find_duplicates_in, DuplicatesReport, DuplicatesSettings,
+};
+pub use crate::file_system::{FileSystem, InMemoryFileSystem, RealFileSystem};
+pub use crate::image::ImageError;
+pub use crate::mapper::{
+    map_directory, map_directory_with_handlers, map_directory_with_progress,
+    MapperError, Progress, Summary,
+};
+pub use crate::media_handler::{Conversion, HandlerRegistry, MediaHandler};
+pub use crate::settings::{
+    parse_color, ChromaSubsampling, ColorProfile, CropMode, ExifGroup,
+    ImageQuality, JpegEncoder, JpegOptions, Layout, OutputFormat, Preset,
+    PresetOverrides, ResizeFilter, ResizePolicy, Settings, SettingsBuilder,
+    TargetSize, TargetSsim,
+};
+pub use crate::verifier::{
+    verify_directory, verify_directory_with_handlers, DamagedFile,
+    VerifyReport, VerifySettings,
+};
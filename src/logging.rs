@@ -0,0 +1,316 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+use unwrap::unwrap;
+
+pub struct LogConfig {
+    pub level: LevelFilter,
+    pub format: LogFormat,
+    pub file: Option<PathBuf>,
+    pub max_file_size: u64,
+    pub kept_files: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+    file: Option<Mutex<LogFile>>,
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    kept_files: usize,
+}
+
+pub fn init(config: LogConfig) {
+    let max_file_size = config.max_file_size;
+    let kept_files = config.kept_files;
+    let file = config.file.map(|path| {
+        let log_file = unwrap!(
+            LogFile::open(path.clone(), max_file_size, kept_files),
+            "Could not open the log file \"{}\"",
+            path.display()
+        );
+        Mutex::new(log_file)
+    });
+
+    let logger = Logger {
+        level: config.level,
+        format: config.format,
+        file,
+    };
+
+    log::set_max_level(config.level);
+    log::set_boxed_logger(Box::new(logger))
+        .expect("The logger has already been initialized");
+}
+
+// -v and -q map onto the levels like this. Errors and warnings are always
+// printed unless -q is given.
+pub fn level_from_verbosity(verbose_count: u64, quiet: bool) -> LevelFilter {
+    if quiet {
+        return LevelFilter::Error;
+    }
+
+    match verbose_count {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = format_record(record, self.format, SystemTime::now());
+
+        // Errors and warnings go to stderr so that they can be told apart
+        // from routine output.
+        if record.level() <= Level::Warn {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap();
+            if let Err(e) = file.write_line(&line) {
+                eprintln!(
+                    "Could not write to the log file \"{}\" due to \"{}\"",
+                    file.path.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().file.flush();
+        }
+    }
+}
+
+fn format_record(
+    record: &Record,
+    format: LogFormat,
+    time: SystemTime,
+) -> String {
+    let timestamp = format_timestamp(time);
+
+    match format {
+        LogFormat::Text => {
+            format!("{} {:<5} {}", timestamp, record.level(), record.args())
+        }
+        LogFormat::Json => json!({
+            "timestamp": timestamp,
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        })
+        .to_string(),
+    }
+}
+
+// Formats the time as "yyyy-mm-ddThh:mm:ssZ" in UTC.
+fn format_timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let days = (seconds / 86400) as i64;
+    let seconds_of_day = seconds % 86400;
+
+    // Converts days since the epoch to a civil date. See
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524
+        - day_of_era / 146096)
+        / 365;
+    let day_of_year =
+        day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+impl LogFile {
+    fn open(
+        path: PathBuf,
+        max_size: u64,
+        kept_files: usize,
+    ) -> std::io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(LogFile {
+            path,
+            file,
+            size,
+            max_size,
+            kept_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let length = line.len() as u64 + 1;
+        if self.size > 0 && self.size + length > self.max_size {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += length;
+        Ok(())
+    }
+
+    // Shifts "log" to "log.1", "log.1" to "log.2" and so on, dropping the
+    // oldest file once there are more than kept_files of them.
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.kept_files == 0 {
+            self.file = File::create(&self.path)?;
+            self.size = 0;
+            return Ok(());
+        }
+
+        let oldest = rotated_path(&self.path, self.kept_files);
+        if oldest.exists() {
+            fs::remove_file(&oldest)?;
+        }
+        for index in (1..self.kept_files).rev() {
+            let from = rotated_path(&self.path, index);
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, index + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated_path(&self.path, 1))?;
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{}", index));
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn level_from_verbosity_maps_flags_to_levels() {
+        assert_eq!(level_from_verbosity(0, false), LevelFilter::Warn);
+        assert_eq!(level_from_verbosity(1, false), LevelFilter::Info);
+        assert_eq!(level_from_verbosity(2, false), LevelFilter::Debug);
+        assert_eq!(level_from_verbosity(3, false), LevelFilter::Trace);
+        assert_eq!(level_from_verbosity(2, true), LevelFilter::Error);
+    }
+
+    #[test]
+    fn format_timestamp_is_correct() {
+        let time = UNIX_EPOCH + Duration::from_secs(1268565753);
+        assert_eq!(format_timestamp(time), "2010-03-14T11:22:33Z");
+
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn format_record_as_json() {
+        let time = UNIX_EPOCH + Duration::from_secs(1268565753);
+        let line = format_record(
+            &Record::builder()
+                .args(format_args!("Created image \"a.jpg\""))
+                .level(Level::Info)
+                .target("image_mapper::mapper")
+                .build(),
+            LogFormat::Json,
+            time,
+        );
+
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["timestamp"], "2010-03-14T11:22:33Z");
+        assert_eq!(value["level"], "INFO");
+        assert_eq!(value["target"], "image_mapper::mapper");
+        assert_eq!(value["message"], "Created image \"a.jpg\"");
+    }
+
+    #[test]
+    fn format_record_as_text() {
+        let time = UNIX_EPOCH + Duration::from_secs(1268565753);
+        let line = format_record(
+            &Record::builder()
+                .args(format_args!("Could not open the image"))
+                .level(Level::Error)
+                .build(),
+            LogFormat::Text,
+            time,
+        );
+
+        assert_eq!(line, "2010-03-14T11:22:33Z ERROR Could not open the image");
+    }
+
+    #[test]
+    fn log_file_is_rotated_when_full() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("image_mapper.log");
+        let mut log_file = LogFile::open(path.clone(), 10, 2).unwrap();
+
+        log_file.write_line("first").unwrap();
+        log_file.write_line("second").unwrap();
+        log_file.write_line("third").unwrap();
+        log_file.write_line("fourth").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 1)).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 2)).unwrap(),
+            "second\n"
+        );
+        assert!(!rotated_path(&path, 3).exists());
+    }
+}
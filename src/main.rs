@@ -1,39 +1,237 @@
 //#![allow(dead_code, unused_variables, unused_imports)]
 
-use crate::settings::Settings;
+use std::process;
 
-mod file_names;
-mod image;
-mod mapper;
-mod settings;
+use clap::ArgMatches;
+use image_mapper::{duplicates, mapper, verifier, MapperError};
+use log::{error, info};
 
-use mapper::MapperError;
+mod cli;
+mod logging;
+
+// The exit codes of the program. They are also listed in the README.
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_INVALID_ARGUMENTS: i32 = 2;
+const EXIT_SRC_OR_DST_MISSING: i32 = 3;
+const EXIT_OVERLAPPING_PATHS: i32 = 4;
+const EXIT_SAFETY_STOP: i32 = 5;
+const EXIT_PARTIAL_FAILURE: i32 = 6;
+const EXIT_LOCKED: i32 = 7;
+const EXIT_NOT_OWNED: i32 = 8;
+const EXIT_DAMAGED_FILES: i32 = 9;
 
 fn main() {
-    let matches = settings::get_matches();
-    let settings = Settings::new_from_matches(&matches);
-    let source_path = settings::source_path_from_matches(&matches);
-    let destination_path = settings::destination_path_from_matches(&matches);
+    let matches = match cli::get_matches() {
+        Ok(matches) => matches,
+        // Logging isn't set up before the arguments are parsed
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(EXIT_INVALID_ARGUMENTS);
+        }
+    };
+
+    let exit_code = match matches.subcommand() {
+        ("verify", Some(verify_matches)) => {
+            logging::init(cli::log_config_from_matches(verify_matches));
+            verify(verify_matches)
+        }
+        ("duplicates", Some(duplicates_matches)) => {
+            logging::init(cli::log_config_from_matches(duplicates_matches));
+            find_duplicates(duplicates_matches)
+        }
+        _ => {
+            logging::init(cli::log_config_from_matches(&matches));
+            map(&matches)
+        }
+    };
+
+    log::logger().flush();
+    process::exit(exit_code);
+}
+
+fn map(matches: &ArgMatches) -> i32 {
+    let settings = match cli::settings_from_matches(matches) {
+        Ok(settings) => settings,
+        Err(message) => {
+            error!("{}", message);
+            return EXIT_INVALID_ARGUMENTS;
+        }
+    };
+    let source_path = cli::source_path_from_matches(matches);
+    let destination_path = cli::destination_path_from_matches(matches);
 
     let result =
         mapper::map_directory(&source_path, &destination_path, settings);
 
     match result {
-        Ok(_) => {}
+        Ok(summary) => {
+            info!(
+                "Done. Created {} files, deleted {} entries and failed on {} entries",
+                summary.created_files,
+                summary.deleted_entries,
+                summary.failed_entries
+            );
+            if summary.duplicate_files > 0 {
+                info!(
+                    "{} of the created files were duplicates, which saved {} bytes",
+                    summary.duplicate_files, summary.saved_bytes
+                );
+            }
+            let qualities = &summary.chosen_qualities;
+            if let (Some(min), Some(max)) =
+                (qualities.iter().min(), qualities.iter().max())
+            {
+                let total: usize = qualities.iter().map(|&q| q as usize).sum();
+                info!(
+                    "Chose qualities from {} to {}, {} on average, for {} images",
+                    min,
+                    max,
+                    total / qualities.len(),
+                    qualities.len()
+                );
+            }
+            if summary.failed_entries > 0 {
+                EXIT_PARTIAL_FAILURE
+            } else {
+                EXIT_SUCCESS
+            }
+        }
         Err(MapperError::SrcDoesNotExist) => {
-            println!("Error: The specified source directory '{}' does not exist or is not a directory", source_path.display());
+            error!("The specified source directory '{}' does not exist or is not a directory", source_path.display());
+            EXIT_SRC_OR_DST_MISSING
         }
         Err(MapperError::DstDoesNotExist) => {
-            println!("Error: The specified destination directory '{}' does not exist or is not a directory", destination_path.display());
+            error!("The specified destination directory '{}' does not exist or is not a directory", destination_path.display());
+            EXIT_SRC_OR_DST_MISSING
         }
         Err(MapperError::SrcInsideDst) => {
-            println!("Error: The specified source '{}' lies inside the specified destination directory '{}'", source_path.display(), destination_path.display());
+            error!("The specified source '{}' lies inside the specified destination directory '{}'", source_path.display(), destination_path.display());
+            EXIT_OVERLAPPING_PATHS
         }
         Err(MapperError::DstInsideSrc) => {
-            println!("Error: The specified destination '{}' lies inside the specified source directory '{}'", destination_path.display(), source_path.display());
+            error!("The specified destination '{}' lies inside the specified source directory '{}'", destination_path.display(), source_path.display());
+            EXIT_OVERLAPPING_PATHS
         }
         Err(MapperError::DstTopLevelEntryNotInSrc(missing_path)) => {
-            println!("Error: The entry '{}' exists as a top-level entry in the specified destination directory, but it does NOT exist in the specified source directory. Running the program like this would cause it to be deleted, so as a safety precaution, the program stops here, because this error might indicate that an incorrect destination directory was specified. Proceding might cause many files to be deleted by mistake. Double check the destination directory and delete the entry manually instead. Note, this check is only done for top-level entries, NOT for entries inside sub directories.", missing_path.display());
+            error!("The entry '{}' exists as a top-level entry in the specified destination directory, but it does NOT exist in the specified source directory. Running the program like this would cause it to be deleted, so as a safety precaution, the program stops here, because this error might indicate that an incorrect destination directory was specified. Proceding might cause many files to be deleted by mistake. Double check the destination directory and delete the entry manually instead. Note, this check is only done for top-level entries, NOT for entries inside sub directories.", missing_path.display());
+            EXIT_SAFETY_STOP
+        }
+        Err(MapperError::DstLocked) => {
+            error!("The specified destination directory '{}' is locked by another running instance", destination_path.display());
+            EXIT_LOCKED
+        }
+        Err(MapperError::DstNotMarked) => {
+            error!("The specified destination directory '{}' is not empty, but it has no marker saying that it was mapped from the specified source directory. It might be an unrelated directory, whose contents would be deleted, so the program stops here. If it really is the destination of '{}', for example one created by an older version, run again with --adopt.", destination_path.display(), source_path.display());
+            EXIT_NOT_OWNED
+        }
+        Err(MapperError::DstMarkedForOtherSrc(other_source_path)) => {
+            error!("The specified destination directory '{}' was mapped from another source directory '{}'. Running the program like this would replace its contents, so the program stops here. If '{}' really should be mapped to it instead, run again with --adopt.", destination_path.display(), other_source_path.display(), source_path.display());
+            EXIT_NOT_OWNED
+        }
+    }
+}
+
+fn verify(matches: &ArgMatches) -> i32 {
+    let settings = cli::verify_settings_from_matches(matches);
+    let destination_path = cli::destination_path_from_matches(matches);
+
+    let result = verifier::verify_directory(&destination_path, settings);
+
+    match result {
+        Ok(report) => {
+            let deleted_files = report
+                .damaged_files
+                .iter()
+                .filter(|damaged_file| damaged_file.deleted)
+                .count();
+            info!(
+                "Done. Checked {} files, found {} damaged files and deleted {} of them",
+                report.checked_files,
+                report.damaged_files.len(),
+                deleted_files
+            );
+            if report.damaged_files.is_empty() {
+                EXIT_SUCCESS
+            } else {
+                EXIT_DAMAGED_FILES
+            }
+        }
+        Err(MapperError::SrcDoesNotExist) => {
+            error!("The source directory that the destination directory '{}' was mapped from does not exist anymore", destination_path.display());
+            EXIT_SRC_OR_DST_MISSING
+        }
+        Err(MapperError::DstDoesNotExist) => {
+            error!("The specified destination directory '{}' does not exist or is not a directory", destination_path.display());
+            EXIT_SRC_OR_DST_MISSING
+        }
+        Err(MapperError::DstLocked) => {
+            error!("The specified destination directory '{}' is locked by another running instance", destination_path.display());
+            EXIT_LOCKED
+        }
+        Err(MapperError::DstNotMarked) => {
+            error!("The specified destination directory '{}' has no marker saying which source directory it was mapped from. Map it once before verifying it.", destination_path.display());
+            EXIT_NOT_OWNED
+        }
+        // The other errors are only returned by the mapping
+        Err(e) => {
+            error!("{}", e);
+            EXIT_SRC_OR_DST_MISSING
+        }
+    }
+}
+
+fn find_duplicates(matches: &ArgMatches) -> i32 {
+    let settings = cli::duplicates_settings_from_matches(matches);
+    let source_path = cli::source_path_from_matches(matches);
+
+    let result = duplicates::find_duplicates(&source_path, settings);
+
+    match result {
+        Ok(report) => {
+            if cli::json_output_from_matches(matches) {
+                match serde_json::to_string_pretty(&report) {
+                    Ok(json) => println!("{}", json),
+                    Err(e) => {
+                        error!("Could not print the report due to \"{}\"", e)
+                    }
+                }
+            } else {
+                let groups: Vec<String> = report
+                    .groups
+                    .iter()
+                    .map(|group| {
+                        let paths: Vec<String> = group
+                            .files
+                            .iter()
+                            .map(|path| path.display().to_string())
+                            .collect();
+                        paths.join("\n")
+                    })
+                    .collect();
+                if !groups.is_empty() {
+                    println!("{}", groups.join("\n\n"));
+                }
+            }
+            info!(
+                "Done. Checked {} images, found {} groups of near-duplicates and failed on {} entries",
+                report.checked_files,
+                report.groups.len(),
+                report.failed_files.len()
+            );
+            if report.failed_files.is_empty() {
+                EXIT_SUCCESS
+            } else {
+                EXIT_PARTIAL_FAILURE
+            }
+        }
+        Err(MapperError::SrcDoesNotExist) => {
+            error!("The specified source directory '{}' does not exist or is not a directory", source_path.display());
+            EXIT_SRC_OR_DST_MISSING
+        }
+        // The other errors are only returned by the mapping
+        Err(e) => {
+            error!("{}", e);
+            EXIT_SRC_OR_DST_MISSING
         }
     }
 }
@@ -1,140 +1,316 @@
-use std::fs;
-use std::fs::ReadDir;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::result::Result;
 
+use log::{debug, error, info, warn};
 use unwrap::unwrap;
 
 use crate::file_names;
-use crate::image;
-use crate::settings::Settings;
-
+use crate::file_system::{FileSystem, LockGuard, RealFileSystem};
+use crate::gallery;
+use crate::index;
+use crate::marker::{self, Marker};
+use crate::media_handler::{HandlerRegistry, MediaHandler};
+use crate::settings::{ImageQuality, Layout, Preset, Settings};
+
+use self::date_layout::Plan;
+use self::deduplication::Deduplicator;
+
+pub(crate) mod date_layout;
+mod deduplication;
 #[cfg(test)]
 mod tests;
 
+/// Maps `source_path` to `destination_path`. See the README for what that
+/// means.
+///
+/// Entries that fail are skipped and counted in the returned [`Summary`].
+/// Errors that are detected before anything is changed are returned as a
+/// [`MapperError`].
 pub fn map_directory(
     source_path: &Path,
     destination_path: &Path,
     settings: Settings,
-) -> Result<(), MapperError> {
-    map_directory_custom_opts(
+) -> Result<Summary, MapperError> {
+    map_directory_with_progress(
         source_path,
         destination_path,
         settings,
-        image::open_compress_and_save_image,
+        &|_| {},
     )
 }
 
-// Custom options for test purposes
-fn map_directory_custom_opts(
+/// Like [`map_directory`], but calls `progress` for every action taken.
+pub fn map_directory_with_progress(
     source_path: &Path,
     destination_path: &Path,
     settings: Settings,
-    open_compress_and_save_image: fn(&Path, &Path, &Settings) -> bool,
-) -> Result<(), MapperError> {
-    if !source_path.is_dir() {
+    progress: &dyn Fn(&Progress),
+) -> Result<Summary, MapperError> {
+    let registry = HandlerRegistry::with_defaults(&settings);
+    map_directory_with_handlers(
+        source_path,
+        destination_path,
+        settings,
+        registry,
+        &RealFileSystem,
+        progress,
+    )
+}
+
+/// Like [`map_directory_with_progress`], but only the files accepted by the
+/// handlers in `registry` are kept in the destination, and all file system
+/// access goes through `fs`.
+pub fn map_directory_with_handlers(
+    source_path: &Path,
+    destination_path: &Path,
+    settings: Settings,
+    registry: HandlerRegistry,
+    fs: &dyn FileSystem,
+    progress: &dyn Fn(&Progress),
+) -> Result<Summary, MapperError> {
+    if !fs.is_dir(source_path) {
         return Err(MapperError::SrcDoesNotExist);
     }
-    if !destination_path.is_dir() {
+    if !fs.is_dir(destination_path) {
         return Err(MapperError::DstDoesNotExist);
     }
-    if is_path_subdir_of(&source_path, &destination_path) {
+
+    // Held until the mapping is done, so that two instances never write to
+    // the same destination at the same time, and the checks below never see
+    // a destination that another instance is changing.
+    let _lock = match lock_destination(destination_path, fs) {
+        Some(lock) => lock,
+        None => return Err(MapperError::DstLocked),
+    };
+
+    // Either can vanish or become unreadable after the checks above
+    let canonical_source_path = fs
+        .canonicalize(source_path)
+        .map_err(|_| MapperError::SrcDoesNotExist)?;
+    let canonical_destination_path = fs
+        .canonicalize(destination_path)
+        .map_err(|_| MapperError::DstDoesNotExist)?;
+    if canonical_source_path.starts_with(&canonical_destination_path) {
         return Err(MapperError::SrcInsideDst);
     }
-    if is_path_subdir_of(&destination_path, &source_path) {
+    if canonical_destination_path.starts_with(&canonical_source_path) {
         return Err(MapperError::DstInsideSrc);
     }
-    if let Some(missing_entry) =
-        top_level_entry_in_destination_missing_from_source(
-            &source_path,
-            &destination_path,
-        )
-    {
+
+    // The date layouts need to know where every source file goes before
+    // anything in the destination can be compared with the source.
+    let plan = match settings.layout {
+        Layout::Mirror => None,
+        Layout::YearMonth | Layout::YearDate => Some(Plan::new(
+            source_path,
+            destination_path,
+            &settings,
+            &registry,
+            fs,
+        )),
+    };
+    let missing_entry = match &plan {
+        Some(plan) => plan.top_level_entry_not_planned(destination_path, fs),
+        None => top_level_entry_in_destination_missing_from_source(
+            source_path,
+            destination_path,
+            &registry,
+            fs,
+        ),
+    };
+    if let Some(missing_entry) = missing_entry {
         return Err(MapperError::DstTopLevelEntryNotInSrc(missing_entry));
     }
+    check_destination_marker(
+        &canonical_source_path,
+        destination_path,
+        &settings,
+        fs,
+    )?;
 
     let opts = MapperOptions {
+        source_root: source_path,
+        destination_root: destination_path,
         settings,
-        open_compress_and_save_image,
+        registry,
+        fs,
+        progress,
+        summary: RefCell::new(Summary::default()),
+        deduplicator: Deduplicator::default(),
     };
 
-    map_directory_int(source_path, destination_path, &opts);
+    update_destination_marker(&canonical_source_path, destination_path, &opts);
+    match &plan {
+        Some(plan) => date_layout::map_directory(destination_path, plan, &opts),
+        None => map_directory_int(source_path, destination_path, &opts),
+    }
 
-    Ok(())
+    Ok(opts.summary.into_inner())
 }
 
-fn is_path_subdir_of(path_to_check: &Path, path_to_compare: &Path) -> bool {
-    let path_to_check = fs::canonicalize(path_to_check).unwrap();
-    let path_to_compare = fs::canonicalize(path_to_compare).unwrap();
-    path_to_check.starts_with(&path_to_compare)
+// Returns None if another process holds the lock
+pub(crate) fn lock_destination(
+    destination_path: &Path,
+    fs: &dyn FileSystem,
+) -> Option<LockGuard> {
+    let lock_path = destination_path.join(file_names::LOCK_FILE_NAME);
+    unwrap!(
+        fs.try_lock(&lock_path),
+        "Could not lock the lock file \"{}\"",
+        lock_path.display()
+    )
 }
 
 fn top_level_entry_in_destination_missing_from_source(
     source_path: &Path,
     destination_path: &Path,
+    registry: &HandlerRegistry,
+    fs: &dyn FileSystem,
 ) -> Option<PathBuf> {
-    let destination_entries = open_dir_to_iterator(destination_path);
-
-    for destination_entry in destination_entries {
-        let destination_entry =
-            unwrap!(destination_entry, "Could not open a destination entry");
+    let destination_entry_paths = unwrap!(
+        fs.read_dir(destination_path),
+        "Could not open the directory \"{}\"",
+        destination_path.display()
+    );
 
-        let file_name = destination_entry.file_name();
-        let file_name = file_name.to_str().expect("Could not convert to str");
+    for destination_entry_path in destination_entry_paths {
+        let file_name = destination_entry_path
+            .file_name()
+            .expect("Could not get a file name")
+            .to_str()
+            .expect("Could not convert to str");
 
-        if source_path.join(file_name).exists() {
+        if fs.exists(&source_path.join(file_name))
+            || file_names::is_reserved_destination_name(file_name)
+        {
             continue;
         }
 
-        // Try again assuming the file is a converted image
-        let file_name =
-            file_names::destination_image_name_to_source_image_name(file_name);
+        // Try again assuming the file is a converted file
+        let file_name = registry.source_name(file_name);
         if file_name.is_some()
-            && source_path.join(file_name.as_ref().unwrap()).exists()
+            && fs.exists(&source_path.join(file_name.as_ref().unwrap()))
         {
             continue;
         }
 
-        return Some(destination_entry.path());
+        return Some(destination_entry_path);
     }
 
     None
 }
 
+// The destination must either be empty, or be marked as mapped from this
+// source. Otherwise it might be some unrelated directory, whose contents
+// would be deleted. `source_path` is canonical.
+fn check_destination_marker(
+    source_path: &Path,
+    destination_path: &Path,
+    settings: &Settings,
+    fs: &dyn FileSystem,
+) -> Result<(), MapperError> {
+    if settings.adopt {
+        return Ok(());
+    }
+
+    match marker::read_marker(destination_path, fs) {
+        Some(marker) if marker.source == source_path => Ok(()),
+        Some(marker) => Err(MapperError::DstMarkedForOtherSrc(marker.source)),
+        None if is_destination_empty(destination_path, fs) => Ok(()),
+        None => Err(MapperError::DstNotMarked),
+    }
+}
+
+fn is_destination_empty(destination_path: &Path, fs: &dyn FileSystem) -> bool {
+    let destination_entry_paths = unwrap!(
+        fs.read_dir(destination_path),
+        "Could not open the directory \"{}\"",
+        destination_path.display()
+    );
+
+    destination_entry_paths
+        .iter()
+        .all(|path| is_reserved_destination_path(path))
+}
+
+// `source_path` is canonical
+fn update_destination_marker(
+    source_path: &Path,
+    destination_path: &Path,
+    opts: &MapperOptions,
+) {
+    let new_marker = Marker::new(source_path, &opts.settings);
+    let old_marker = marker::read_marker(destination_path, opts.fs);
+
+    if let Some(old_marker) = &old_marker {
+        if old_marker.settings != new_marker.settings {
+            warn!("The destination was mapped with other settings before. Files that already exist in it are not converted again.");
+        }
+    }
+
+    if old_marker.as_ref() != Some(&new_marker) {
+        let marker_path = marker::marker_path(destination_path);
+        if let Err(e) =
+            marker::write_marker(destination_path, &new_marker, opts.fs)
+        {
+            let message = format!(
+                "Could not write the marker file \"{}\" due to \"{}\"",
+                marker_path.display(),
+                e
+            );
+            opts.report_failure(&marker_path, message);
+        }
+    }
+}
+
 fn map_directory_int(
     source_path: &Path,
     destination_path: &Path,
     opts: &MapperOptions,
 ) {
-    if opts.settings.verbose_print {
-        println!(
-            "Entered source: \"{}\" and destination: \"{}\"",
-            source_path.display(),
-            destination_path.display()
+    opts.report(Progress::EnteredDirectory {
+        source: source_path,
+        destination: destination_path,
+    });
+
+    if let Err(e) = ensure_path_is_directory(destination_path, opts.fs) {
+        let message = format!(
+            "Could not create the directory \"{}\" due to \"{}\", so skipping it.",
+            destination_path.display(),
+            e
         );
+        opts.report_failure(destination_path, message);
+        return;
     }
 
-    ensure_path_is_directory(destination_path);
-
+    let change_count = opts.change_count();
     iterate_source_entries(source_path, destination_path, opts);
     iterate_destination_entries(source_path, destination_path, opts);
+
+    let source_of = |destination_file_path: &Path| {
+        let destination_file_name = destination_file_path.file_name()?;
+        opts.registry
+            .source_name(&destination_file_name.to_string_lossy())
+            .map(|source_file_name| source_path.join(source_file_name))
+    };
+    update_generated_files(destination_path, change_count, &source_of, opts);
 }
 
-fn ensure_path_is_directory(destination_path: &Path) {
-    if destination_path.is_file() {
-        unwrap!(
-            fs::remove_file(destination_path),
-            "Could not delete the directory \"{}\"",
-            destination_path.display()
-        );
+fn ensure_path_is_directory(
+    destination_path: &Path,
+    fs: &dyn FileSystem,
+) -> io::Result<()> {
+    if fs.is_file(destination_path) {
+        fs.remove_file(destination_path)?;
     }
-    if !destination_path.exists() {
-        unwrap!(
-            fs::create_dir(destination_path),
-            "Could not create the directory \"{}\"",
-            destination_path.display()
-        );
+    if !fs.exists(destination_path) {
+        fs.create_dir(destination_path)?;
     }
+    Ok(())
 }
 
 fn iterate_source_entries(
@@ -142,15 +318,21 @@ fn iterate_source_entries(
     destination_path: &Path,
     opts: &MapperOptions,
 ) {
-    let source_entries = open_dir_to_iterator(source_path);
-
-    for source_entry in source_entries {
-        let source_entry =
-            unwrap!(source_entry, "Could not open a source entry");
-
-        let source_entry_path = &source_entry.path();
+    let source_entry_paths = match opts.fs.read_dir(source_path) {
+        Ok(source_entry_paths) => source_entry_paths,
+        Err(e) => {
+            let message = format!(
+                "Could not read the source directory \"{}\" due to \"{}\", so skipping it.",
+                source_path.display(),
+                e
+            );
+            opts.report_failure(source_path, message);
+            return;
+        }
+    };
 
-        if source_entry_path.is_dir() {
+    for source_entry_path in &source_entry_paths {
+        if opts.fs.is_dir(source_entry_path) {
             handle_source_dir(source_entry_path, destination_path, opts);
         } else {
             handle_source_file(source_entry_path, destination_path, opts);
@@ -158,14 +340,6 @@ fn iterate_source_entries(
     }
 }
 
-fn open_dir_to_iterator(path: &Path) -> ReadDir {
-    unwrap!(
-        fs::read_dir(path),
-        "Could not open the directory \"{}\"",
-        path.display()
-    )
-}
-
 fn handle_source_dir(
     source_dir_path: &Path,
     destination_path: &Path,
@@ -186,75 +360,125 @@ fn handle_source_file(
     destination_path: &Path,
     opts: &MapperOptions,
 ) {
-    if let Some(extension) = source_file_path.extension() {
-        if file_names::extension_is_image_extension(extension) {
-            handle_source_image(source_file_path, destination_path, opts);
-        } else if file_names::extension_is_video_extension(extension)
-            && opts.settings.include_videos
-        {
-            handle_source_video(source_file_path, destination_path, opts);
-        }
+    // Files that no handler accepts are not wanted in the destination, so
+    // ignore them.
+    if let Some(handler) = opts.registry.handler_for_source(source_file_path) {
+        let destination_file_name =
+            handler.destination_name(source_file_path, opts.fs);
+        let destination_file_path =
+            &destination_path.join(destination_file_name);
+
+        create_destination_file(
+            handler,
+            source_file_path,
+            destination_file_path,
+            opts,
+        );
     }
-    // Some files dont have extensions, so if one is missing, its
-    // not an image or a video, so ignore it.
 }
 
-fn handle_source_image(
-    source_image_path: &Path,
-    destination_path: &Path,
+fn create_destination_file(
+    handler: &dyn MediaHandler,
+    source_file_path: &Path,
+    destination_file_path: &Path,
     opts: &MapperOptions,
 ) {
-    let destination_image_name =
-        file_names::destination_image_name_from_image_path(source_image_path);
-    let destination_image_path = &destination_path.join(destination_image_name);
-
-    if !destination_image_path.exists() {
-        let successful = (opts.open_compress_and_save_image)(
-            source_image_path,
-            destination_image_path,
+    let quality = if opts.fs.exists(destination_file_path) {
+        opts.report(Progress::AlreadyExists(destination_file_path));
+        None
+    } else if let Some(quality) =
+        create_duplicate(source_file_path, destination_file_path, opts)
+    {
+        quality
+    } else {
+        let result = handler.convert(
+            source_file_path,
+            destination_file_path,
             &opts.settings,
+            opts.fs,
         );
 
-        if opts.settings.verbose_print && successful {
-            println!("Created image \"{}\"", destination_image_path.display());
+        match result {
+            Ok(conversion) => {
+                opts.report(Progress::Created(
+                    destination_file_path,
+                    conversion.quality,
+                ));
+                conversion.quality
+            }
+            Err(e) => {
+                opts.report_failure(source_file_path, e.to_string());
+                return;
+            }
         }
-    } else if opts.settings.verbose_print {
-        println!(
-            "Image \"{}\" aleady exists",
-            destination_image_path.display()
+    };
+
+    if opts.settings.deduplicate {
+        opts.deduplicator.add(
+            source_file_path,
+            destination_file_path,
+            quality,
+            opts.fs,
         );
     }
 }
 
-fn handle_source_video(
-    source_video_path: &Path,
-    destination_path: &Path,
+// If the destination file was created from a destination file whose source
+// has the same contents, instead of by converting the source, returns the
+// quality that was chosen for that destination file, if any
+fn create_duplicate(
+    source_file_path: &Path,
+    destination_file_path: &Path,
     opts: &MapperOptions,
-) {
-    let destination_video_name = unwrap!(
-        source_video_path.file_name(),
-        "Could not get the file name of a video \"{}\"",
-        source_video_path.display()
-    );
-    let destination_video_path = &destination_path.join(destination_video_name);
-
-    if !destination_video_path.exists() {
-        unwrap!(
-            fs::copy(source_video_path, destination_video_path),
-            "Could not copy a video \"{}\" to \"{}\"",
-            source_video_path.display(),
-            destination_video_path.display()
-        );
+) -> Option<Option<u8>> {
+    if !opts.settings.deduplicate {
+        return None;
+    }
 
-        if opts.settings.verbose_print {
-            println!("Created video \"{}\"", destination_video_path.display());
+    let (original_path, quality) = match opts.deduplicator.find_duplicate(
+        source_file_path,
+        destination_file_path,
+        opts.fs,
+    ) {
+        Ok(Some(original)) => original,
+        Ok(None) => return None,
+        // Converting reports the error, if it happens again
+        Err(e) => {
+            debug!(
+                "Could not look for duplicates of \"{}\" due to \"{}\"",
+                source_file_path.display(),
+                e
+            );
+            return None;
         }
-    } else if opts.settings.verbose_print {
-        println!(
-            "Video \"{}\" aleady exists",
-            destination_video_path.display()
-        );
-    }
+    };
+
+    let saved_bytes = match opts
+        .fs
+        .hard_link(&original_path, destination_file_path)
+    {
+        Ok(()) => opts.fs.file_size(destination_file_path).unwrap_or(0),
+        Err(e) => {
+            debug!(
+                    "Could not hard link \"{}\" to \"{}\" due to \"{}\", so copying it",
+                    destination_file_path.display(),
+                    original_path.display(),
+                    e
+                );
+            if opts.fs.copy(&original_path, destination_file_path).is_err() {
+                return None;
+            }
+            0
+        }
+    };
+
+    opts.report(Progress::Duplicate {
+        original: &original_path,
+        destination: destination_file_path,
+        quality,
+        saved_bytes,
+    });
+    Some(quality)
 }
 
 fn iterate_destination_entries(
@@ -262,15 +486,25 @@ fn iterate_destination_entries(
     destination_path: &Path,
     opts: &MapperOptions,
 ) {
-    let destination_entries = open_dir_to_iterator(destination_path);
-
-    for destination_entry in destination_entries {
-        let destination_entry =
-            unwrap!(destination_entry, "Could not open a destination entry");
+    let destination_entry_paths = match opts.fs.read_dir(destination_path) {
+        Ok(destination_entry_paths) => destination_entry_paths,
+        Err(e) => {
+            let message = format!(
+                "Could not read the destination directory \"{}\" due to \"{}\"",
+                destination_path.display(),
+                e
+            );
+            opts.report_failure(destination_path, message);
+            return;
+        }
+    };
 
-        let destination_entry_path = &destination_entry.path();
+    for destination_entry_path in &destination_entry_paths {
+        if is_reserved_destination_path(destination_entry_path) {
+            continue;
+        }
 
-        if destination_entry_path.is_dir() {
+        if opts.fs.is_dir(destination_entry_path) {
             handle_destination_dir(destination_entry_path, source_path, opts);
         } else {
             handle_destination_file(destination_entry_path, source_path, opts);
@@ -278,6 +512,193 @@ fn iterate_destination_entries(
     }
 }
 
+pub(crate) fn is_reserved_destination_path(destination_path: &Path) -> bool {
+    destination_path
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .map(file_names::is_reserved_destination_name)
+        .unwrap_or(false)
+}
+
+// The index and the gallery page are only rewritten when something in the
+// directory, or below it, has changed since `change_count` was taken.
+fn update_generated_files(
+    destination_path: &Path,
+    change_count: usize,
+    source_of: &dyn Fn(&Path) -> Option<PathBuf>,
+    opts: &MapperOptions,
+) {
+    let index_path = index::index_path(destination_path);
+    let page_path = gallery::page_path(destination_path);
+    let changed = opts.change_count() != change_count;
+
+    let write_index =
+        opts.settings.write_index && (changed || !opts.fs.is_file(&index_path));
+    let write_page = opts.settings.write_gallery
+        && (changed || !opts.fs.is_file(&page_path));
+
+    if !opts.settings.write_index && opts.fs.is_file(&index_path) {
+        delete_destination_entry(&index_path, opts);
+    }
+    if !opts.settings.write_gallery && opts.fs.is_file(&page_path) {
+        delete_destination_entry(&page_path, opts);
+    }
+    let thumbnails_path = gallery::thumbnails_path(destination_path);
+    if !opts.settings.write_gallery && opts.fs.is_dir(&thumbnails_path) {
+        delete_destination_entry(&thumbnails_path, opts);
+    }
+    if !write_index && !write_page {
+        return;
+    }
+
+    let index = match index::build_index(
+        destination_path,
+        source_of,
+        &opts.registry,
+        opts.fs,
+    ) {
+        Ok(index) => index,
+        Err(e) => {
+            let message = format!(
+                "Could not describe the directory \"{}\" due to \"{}\", so its index and gallery page are not updated",
+                destination_path.display(),
+                e
+            );
+            opts.report_failure(destination_path, message);
+            return;
+        }
+    };
+
+    if write_index {
+        let result = index::write_index(destination_path, &index, opts.fs);
+        report_generated_file(&index_path, result, opts);
+    }
+    if write_page {
+        let title = destination_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| destination_path.display().to_string());
+        let has_parent = destination_path != opts.destination_root;
+        let thumbnails =
+            update_thumbnails(destination_path, &index, source_of, opts);
+        let page =
+            gallery::render_page(&title, &index, has_parent, &thumbnails);
+        let result = gallery::write_page(destination_path, &page, opts.fs);
+        report_generated_file(&page_path, result, opts);
+    }
+}
+
+// Converts the missing thumbnails of the gallery from the sources of the
+// images, since the images themselves can be large, and deletes those of
+// images that are gone. Returns the names of the images that have one. The
+// page shows the image itself if its thumbnail can't be written, so that's
+// only a warning.
+fn update_thumbnails(
+    destination_path: &Path,
+    index: &index::Index,
+    source_of: &dyn Fn(&Path) -> Option<PathBuf>,
+    opts: &MapperOptions,
+) -> BTreeSet<String> {
+    let thumbnails_path = gallery::thumbnails_path(destination_path);
+    let settings = thumbnail_settings(&opts.settings);
+    let mut thumbnails = BTreeSet::new();
+
+    for file in index.files.iter().filter(|file| gallery::is_image(file)) {
+        let thumbnail_path =
+            gallery::thumbnail_path(destination_path, &file.name);
+        if opts.fs.is_file(&thumbnail_path) {
+            thumbnails.insert(file.name.clone());
+            continue;
+        }
+
+        let source_file_path =
+            match source_of(&destination_path.join(&file.name)) {
+                Some(source_file_path) => source_file_path,
+                None => continue,
+            };
+        let handler = match opts.registry.handler_for_source(&source_file_path)
+        {
+            Some(handler) => handler,
+            None => continue,
+        };
+        let result = ensure_path_is_directory(&thumbnails_path, opts.fs)
+            .map_err(|e| e.into())
+            .and_then(|()| {
+                handler.convert(
+                    &source_file_path,
+                    &thumbnail_path,
+                    &settings,
+                    opts.fs,
+                )
+            });
+        match result {
+            Ok(_) => {
+                debug!("Wrote \"{}\"", thumbnail_path.display());
+                thumbnails.insert(file.name.clone());
+            }
+            Err(e) => warn!(
+                "Could not create the thumbnail \"{}\" due to \"{}\"",
+                thumbnail_path.display(),
+                e
+            ),
+        }
+    }
+
+    if let Ok(thumbnail_paths) = opts.fs.read_dir(&thumbnails_path) {
+        for thumbnail_path in thumbnail_paths {
+            let is_used = thumbnail_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".jpg"))
+                .map(|name| thumbnails.contains(name))
+                .unwrap_or(false);
+            if !is_used {
+                match opts.fs.remove_file(&thumbnail_path) {
+                    Ok(()) => {
+                        debug!("Deleted \"{}\"", thumbnail_path.display())
+                    }
+                    Err(e) => warn!(
+                        "Could not delete \"{}\" due to \"{}\"",
+                        thumbnail_path.display(),
+                        e
+                    ),
+                }
+            }
+        }
+    }
+
+    thumbnails
+}
+
+// The settings of the images, such as the kept EXIF fields and the colour
+// profile, at the size of the `Thumbnail` quality. They're always JPEGs.
+fn thumbnail_settings(settings: &Settings) -> Settings {
+    let preset = settings.image_quality.preset();
+    let thumbnail_preset = Preset {
+        filter: preset.filter,
+        background: preset.background,
+        ..ImageQuality::Thumbnail.preset()
+    };
+    Settings {
+        image_quality: ImageQuality::Custom(thumbnail_preset),
+        ..settings.clone()
+    }
+}
+
+fn report_generated_file(
+    path: &Path,
+    result: io::Result<()>,
+    opts: &MapperOptions,
+) {
+    match result {
+        Ok(()) => debug!("Wrote \"{}\"", path.display()),
+        Err(e) => opts.report_failure(
+            path,
+            format!("Could not write \"{}\" due to \"{}\"", path.display(), e),
+        ),
+    }
+}
+
 fn handle_destination_dir(
     destination_dir_path: &Path,
     source_path: &Path,
@@ -291,16 +712,8 @@ fn handle_destination_dir(
     let corresponding_source_entry_path =
         source_path.join(destination_dir_name);
 
-    if !corresponding_source_entry_path.is_dir() {
-        unwrap!(
-            fs::remove_dir_all(destination_dir_path),
-            "Could not remove a destination directory \"{}\"",
-            destination_dir_path.display()
-        );
-
-        if opts.settings.verbose_print {
-            println!("Deleted \"{}\"", destination_dir_path.display());
-        }
+    if !opts.fs.is_dir(&corresponding_source_entry_path) {
+        delete_destination_entry(destination_dir_path, opts);
     }
     // No need to recursively call map_directory_int. If a destination dir
     // has a name that matches the source dir, then it will already have
@@ -312,112 +725,239 @@ fn handle_destination_file(
     source_path: &Path,
     opts: &MapperOptions,
 ) {
-    if let Some(extension) = destination_file_path.extension() {
-        if file_names::extension_is_destination_image_extension(extension) {
-            handle_destination_image(destination_file_path, source_path, opts);
-        } else if file_names::extension_is_video_extension(extension) {
-            handle_destination_video(destination_file_path, source_path, opts);
-        } else {
-            handle_destination_other_file(destination_file_path, opts);
-        }
-    } else {
-        handle_destination_other_file(destination_file_path, opts);
-    }
-}
-
-fn handle_destination_image(
-    destination_image_path: &Path,
-    source_path: &Path,
-    opts: &MapperOptions,
-) {
-    let destination_image_name = destination_image_path
+    let destination_file_name = destination_file_path
         .file_name()
         .expect("Could not get a file name.")
         .to_str()
         .expect("Could not convert to str.");
-    let mut deleted = false;
 
-    if let Some(corresponding_source_entry_name) =
-        file_names::destination_image_name_to_source_image_name(
-            destination_image_name,
-        )
-    {
-        let corresponding_source_entry_path =
-            source_path.join(corresponding_source_entry_name);
-
-        // The corresponding source entry must be a file, otherwise
-        // it doesnt exist or is a dir.
-        if !corresponding_source_entry_path.is_file() {
-            fs::remove_file(destination_image_path).unwrap();
-            deleted = true;
-        }
-    } else {
-        // Some weird file_name that is very short. For sure invalid.
-        fs::remove_file(destination_image_path)
-            .expect("Could not remove a file.");
-        deleted = true;
+    // A destination file is kept only if it was created from a source file
+    // that still exists and is still wanted, and it's named the way its
+    // handler names outputs now. Otherwise it was for example written in
+    // another output format before.
+    let keep = opts
+        .registry
+        .source_name(destination_file_name)
+        .map(|source_file_name| source_path.join(source_file_name))
+        .filter(|source_file_path| opts.fs.is_file(source_file_path))
+        .and_then(|source_file_path| {
+            opts.registry.handler_for_source(&source_file_path)
+        })
+        .map(|handler| handler.is_current_name(destination_file_name))
+        .unwrap_or(false);
+
+    if !keep {
+        delete_destination_entry(destination_file_path, opts);
     }
+}
+
+fn delete_destination_entry(destination_path: &Path, opts: &MapperOptions) {
+    let result = if opts.fs.is_dir(destination_path) {
+        opts.fs.remove_dir_all(destination_path)
+    } else {
+        opts.fs.remove_file(destination_path)
+    };
 
-    if opts.settings.verbose_print && deleted {
-        println!("Deleted {}", destination_image_path.display());
+    match result {
+        Ok(()) => opts.report(Progress::Deleted(destination_path)),
+        Err(e) => opts.report_failure(
+            destination_path,
+            format!(
+                "Could not delete \"{}\" due to \"{}\"",
+                destination_path.display(),
+                e
+            ),
+        ),
     }
 }
 
-fn handle_destination_video(
-    destination_video_path: &Path,
-    source_path: &Path,
-    opts: &MapperOptions,
-) {
-    let destination_video_name = unwrap!(
-        destination_video_path.file_name(),
-        "Could not get the file name from \"{}\"",
-        destination_video_path.display()
-    );
-    let corresponding_source_entry_path =
-        source_path.join(destination_video_name);
-
-    // The corresponding source entry must be a file, otherwise
-    // it doesnt exist or is a dir.
-    // We must also want to have videos in the destination.
-    if !(corresponding_source_entry_path.is_file()
-        && opts.settings.include_videos)
-    {
-        unwrap!(
-            fs::remove_file(destination_video_path),
-            "Could not delete \"{}\"",
-            destination_video_path.display()
-        );
+struct MapperOptions<'a> {
+    source_root: &'a Path,
+    destination_root: &'a Path,
+    settings: Settings,
+    registry: HandlerRegistry,
+    fs: &'a dyn FileSystem,
+    progress: &'a dyn Fn(&Progress),
+    summary: RefCell<Summary>,
+    deduplicator: Deduplicator,
+}
 
-        if opts.settings.verbose_print {
-            println!("Deleted \"{}\"", destination_video_path.display());
+impl MapperOptions<'_> {
+    fn report(&self, progress: Progress) {
+        match &progress {
+            Progress::EnteredDirectory {
+                source,
+                destination,
+            } => debug!(
+                "Entered source: \"{}\" and destination: \"{}\"",
+                source.display(),
+                destination.display()
+            ),
+            Progress::Created(path, None) => {
+                info!("Created \"{}\"", path.display());
+                self.summary.borrow_mut().created_files += 1;
+            }
+            Progress::Created(path, Some(quality)) => {
+                info!(
+                    "Created \"{}\" with quality {}",
+                    path.display(),
+                    quality
+                );
+                let mut summary = self.summary.borrow_mut();
+                summary.created_files += 1;
+                summary.chosen_qualities.push(*quality);
+            }
+            Progress::Duplicate {
+                original,
+                destination,
+                quality,
+                saved_bytes,
+            } => {
+                info!(
+                    "Created \"{}\" as a duplicate of \"{}\"",
+                    destination.display(),
+                    original.display()
+                );
+                let mut summary = self.summary.borrow_mut();
+                summary.created_files += 1;
+                summary.duplicate_files += 1;
+                summary.saved_bytes += saved_bytes;
+                summary.chosen_qualities.extend(quality);
+            }
+            Progress::AlreadyExists(path) => {
+                debug!("\"{}\" already exists", path.display())
+            }
+            Progress::Deleted(path) => {
+                info!("Deleted \"{}\"", path.display());
+                self.summary.borrow_mut().deleted_entries += 1;
+            }
+            Progress::Failed(_, message) => {
+                error!("{}", message);
+                self.summary.borrow_mut().failed_entries += 1;
+            }
         }
+
+        (self.progress)(&progress);
     }
-}
 
-fn handle_destination_other_file(
-    destination_file_path: &Path,
-    opts: &MapperOptions,
-) {
-    unwrap!(
-        fs::remove_file(destination_file_path),
-        "Could not delete \"{}\"",
-        destination_file_path.display()
-    );
-    if opts.settings.verbose_print {
-        println!("Deleted \"{}\"", destination_file_path.display());
+    fn report_failure(&self, path: &Path, message: String) {
+        self.report(Progress::Failed(path, &message));
+    }
+
+    fn change_count(&self) -> usize {
+        let summary = self.summary.borrow();
+        summary.created_files + summary.deleted_entries
     }
 }
 
-struct MapperOptions {
-    settings: Settings,
-    open_compress_and_save_image: fn(&Path, &Path, &Settings) -> bool,
+/// An action taken by the mapper, as reported to the callback given to
+/// [`map_directory_with_progress`].
+#[derive(Debug, PartialEq)]
+pub enum Progress<'a> {
+    EnteredDirectory {
+        source: &'a Path,
+        destination: &'a Path,
+    },
+    /// A file was converted or copied to this destination path. Images whose
+    /// quality was chosen for them by a target size or SSIM have it too.
+    Created(&'a Path, Option<u8>),
+    /// A source file had the same contents as the one that `original` was
+    /// created from, so `destination` was created as a hard link to
+    /// `original`, or as a copy of it where hard links aren't supported.
+    /// Hard links save the `saved_bytes` that another copy would have taken.
+    /// Like `original`, it has the quality that was chosen for it, if any.
+    Duplicate {
+        original: &'a Path,
+        destination: &'a Path,
+        quality: Option<u8>,
+        saved_bytes: u64,
+    },
+    /// This destination path was already up to date
+    AlreadyExists(&'a Path),
+    /// This destination entry had no corresponding source entry
+    Deleted(&'a Path),
+    /// The entry at the path could not be handled. The message tells why.
+    /// The mapping continues with the other entries.
+    Failed(&'a Path, &'a str),
+}
+
+/// The outcome of a successful [`map_directory`] run.
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub created_files: usize,
+    pub deleted_entries: usize,
+    // Entries that could not be created, copied or deleted. The mapping
+    // continues past them, so the rest of the destination is still correct.
+    pub failed_entries: usize,
+    // Created files that are duplicates of other destination files, and
+    // the disk space saved by hard linking them. Both are also counted in
+    // `created_files`.
+    pub duplicate_files: usize,
+    pub saved_bytes: u64,
+    // The qualities that a target size or SSIM chose for the created images,
+    // one per image, duplicates included
+    pub chosen_qualities: Vec<u8>,
 }
 
+/// Errors that stop the mapping before anything in the destination is
+/// changed.
 #[derive(Debug, PartialEq)]
 pub enum MapperError {
     SrcDoesNotExist,
     DstDoesNotExist,
     SrcInsideDst,
     DstInsideSrc,
+    /// The destination has this top-level entry, but the source doesn't.
+    /// Could mean that the wrong destination was given.
     DstTopLevelEntryNotInSrc(PathBuf),
+    /// Another instance is mapping to the same destination
+    DstLocked,
+    /// The destination is not empty, and has no marker saying that it was
+    /// mapped from the source
+    DstNotMarked,
+    /// The destination is marked as mapped from this other source
+    DstMarkedForOtherSrc(PathBuf),
+}
+
+impl fmt::Display for MapperError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapperError::SrcDoesNotExist => write!(
+                f,
+                "The source directory does not exist or is not a directory"
+            ),
+            MapperError::DstDoesNotExist => write!(
+                f,
+                "The destination directory does not exist or is not a directory"
+            ),
+            MapperError::SrcInsideDst => write!(
+                f,
+                "The source directory lies inside the destination directory"
+            ),
+            MapperError::DstInsideSrc => write!(
+                f,
+                "The destination directory lies inside the source directory"
+            ),
+            MapperError::DstTopLevelEntryNotInSrc(path) => write!(
+                f,
+                "The top-level destination entry \"{}\" does not exist in the source directory",
+                path.display()
+            ),
+            MapperError::DstLocked => write!(
+                f,
+                "The destination directory is locked by another instance"
+            ),
+            MapperError::DstNotMarked => write!(
+                f,
+                "The destination directory is not empty and not marked as mapped from the source directory"
+            ),
+            MapperError::DstMarkedForOtherSrc(path) => write!(
+                f,
+                "The destination directory is marked as mapped from another source directory \"{}\"",
+                path.display()
+            ),
+        }
+    }
 }
+
+impl std::error::Error for MapperError {}
@@ -0,0 +1,387 @@
+// Maps the source into directories named after the capture times of the
+// files, instead of mirroring the source directories. Since a destination
+// directory then has no single corresponding source directory, all source
+// files are first planned to destination paths, and the destination is
+// then made to match the plan.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use super::{
+    create_destination_file, delete_destination_entry,
+    ensure_path_is_directory, is_reserved_destination_path,
+    update_generated_files, MapperOptions, Progress,
+};
+use crate::file_system::FileSystem;
+use crate::media_handler::HandlerRegistry;
+use crate::settings::{Layout, Settings};
+
+pub(crate) struct Plan {
+    // Destination file path -> the source file path it's created from
+    outputs: BTreeMap<PathBuf, PathBuf>,
+    // The destination directories that have outputs in or below them
+    directories: BTreeSet<PathBuf>,
+    // Source entries that couldn't be read, and why. Their outputs can't be
+    // told apart from outputs of removed files, so nothing is deleted if
+    // there are any.
+    pub(crate) failures: Vec<(PathBuf, String)>,
+}
+
+impl Plan {
+    pub(crate) fn new(
+        source_root: &Path,
+        destination_root: &Path,
+        settings: &Settings,
+        registry: &HandlerRegistry,
+        fs: &dyn FileSystem,
+    ) -> Plan {
+        let mut plan = Plan {
+            outputs: BTreeMap::new(),
+            directories: BTreeSet::new(),
+            failures: Vec::new(),
+        };
+        let planner = Planner {
+            destination_root,
+            settings,
+            registry,
+            fs,
+        };
+        planner.plan_source_directory(source_root, &mut plan);
+        plan
+    }
+
+    pub(crate) fn source_of(
+        &self,
+        destination_file_path: &Path,
+    ) -> Option<PathBuf> {
+        self.outputs.get(destination_file_path).cloned()
+    }
+
+    /// Destination file paths and the source file paths they're created
+    /// from
+    pub(crate) fn outputs(&self) -> impl Iterator<Item = (&Path, &Path)> {
+        self.outputs.iter().map(|(destination, source)| {
+            (destination.as_path(), source.as_path())
+        })
+    }
+
+    // The same as the top-level check of the mirror layout, but entries are
+    // compared with the plan instead of with the source. The check guards
+    // against deleting, so it's not needed when nothing will be deleted.
+    pub(crate) fn top_level_entry_not_planned(
+        &self,
+        destination_root: &Path,
+        fs: &dyn FileSystem,
+    ) -> Option<PathBuf> {
+        if !self.failures.is_empty() {
+            return None;
+        }
+
+        let destination_entry_paths = unwrap::unwrap!(
+            fs.read_dir(destination_root),
+            "Could not open the directory \"{}\"",
+            destination_root.display()
+        );
+
+        destination_entry_paths.into_iter().find(|path| {
+            !is_reserved_destination_path(path) && !self.is_planned(path, fs)
+        })
+    }
+
+    fn is_planned(&self, destination_path: &Path, fs: &dyn FileSystem) -> bool {
+        if fs.is_dir(destination_path) {
+            self.directories.contains(destination_path)
+        } else {
+            self.outputs.contains_key(destination_path)
+        }
+    }
+
+    // Since paths are ordered component by component, everything below a
+    // directory comes right after it.
+    fn files_in<'a>(
+        &'a self,
+        directory_path: &'a Path,
+    ) -> impl Iterator<Item = (&'a PathBuf, &'a PathBuf)> {
+        self.outputs
+            .range::<Path, _>((
+                Bound::Excluded(directory_path),
+                Bound::Unbounded,
+            ))
+            .take_while(move |(path, _)| path.starts_with(directory_path))
+            .filter(move |(path, _)| path.parent() == Some(directory_path))
+    }
+
+    fn subdirectories_of<'a>(
+        &'a self,
+        directory_path: &'a Path,
+    ) -> impl Iterator<Item = &'a PathBuf> {
+        self.directories
+            .range::<Path, _>((
+                Bound::Excluded(directory_path),
+                Bound::Unbounded,
+            ))
+            .take_while(move |path| path.starts_with(directory_path))
+            .filter(move |path| path.parent() == Some(directory_path))
+    }
+}
+
+struct Planner<'a> {
+    destination_root: &'a Path,
+    settings: &'a Settings,
+    registry: &'a HandlerRegistry,
+    fs: &'a dyn FileSystem,
+}
+
+impl Planner<'_> {
+    fn plan_source_directory(&self, source_path: &Path, plan: &mut Plan) {
+        let mut source_entry_paths = match self.fs.read_dir(source_path) {
+            Ok(source_entry_paths) => source_entry_paths,
+            Err(e) => {
+                let message = format!(
+                    "Could not read the source directory \"{}\" due to \"{}\", so skipping it.",
+                    source_path.display(),
+                    e
+                );
+                plan.failures.push((source_path.to_path_buf(), message));
+                return;
+            }
+        };
+        // So that the same file wins every time if two files would get the
+        // same destination path
+        source_entry_paths.sort();
+
+        for source_entry_path in source_entry_paths {
+            if self.fs.is_dir(&source_entry_path) {
+                self.plan_source_directory(&source_entry_path, plan);
+            } else {
+                self.plan_source_file(source_entry_path, plan);
+            }
+        }
+    }
+
+    fn plan_source_file(&self, source_file_path: PathBuf, plan: &mut Plan) {
+        let handler = match self.registry.handler_for_source(&source_file_path)
+        {
+            Some(handler) => handler,
+            None => return,
+        };
+
+        // Its capture time can't be read either, and it would be planned as
+        // undated
+        if let Err(e) = self.fs.open(&source_file_path) {
+            let message = format!(
+                "Could not read the source file \"{}\" due to \"{}\", so skipping it.",
+                source_file_path.display(),
+                e
+            );
+            plan.failures.push((source_file_path, message));
+            return;
+        }
+
+        let capture_time = handler.capture_time(&source_file_path, self.fs);
+        let destination_file_path = self
+            .destination_root
+            .join(date_directory(capture_time.as_deref(), self.settings))
+            .join(handler.destination_name(&source_file_path, self.fs));
+
+        if let Some(other_source_file_path) =
+            plan.outputs.get(&destination_file_path)
+        {
+            info!(
+                "Skipping \"{}\", since \"{}\" is already mapped to \"{}\"",
+                source_file_path.display(),
+                other_source_file_path.display(),
+                destination_file_path.display()
+            );
+            return;
+        }
+
+        for ancestor in destination_file_path.ancestors().skip(1) {
+            if ancestor == self.destination_root {
+                break;
+            }
+            plan.directories.insert(ancestor.to_path_buf());
+        }
+        plan.outputs.insert(destination_file_path, source_file_path);
+    }
+}
+
+// The directory, relative to the destination root, that a file captured at
+// `capture_time` is put in
+fn date_directory(capture_time: Option<&str>, settings: &Settings) -> PathBuf {
+    let date = capture_time.and_then(parse_date);
+
+    match (settings.layout, date) {
+        (Layout::Mirror, _) => PathBuf::new(),
+        (_, None) => PathBuf::from(&settings.undated_folder),
+        (Layout::YearMonth, Some((year, month, _))) => {
+            Path::new(year).join(month)
+        }
+        (Layout::YearDate, Some((year, month, day))) => {
+            Path::new(year).join(format!("{}-{}-{}", year, month, day))
+        }
+    }
+}
+
+// Splits "yyyy-mm-dd hh:mm:ss" into year, month and day. Cameras without a
+// set clock write zeros, which count as no date.
+fn parse_date(capture_time: &str) -> Option<(&str, &str, &str)> {
+    let date = capture_time.get(..10)?;
+    let mut parts = date.split('-');
+    let year = parts.next()?;
+    let month = parts.next()?;
+    let day = parts.next()?;
+
+    let is_number = |part: &str, length: usize| {
+        part.len() == length && part.bytes().all(|byte| byte.is_ascii_digit())
+    };
+    if is_number(year, 4)
+        && is_number(month, 2)
+        && is_number(day, 2)
+        && year != "0000"
+    {
+        Some((year, month, day))
+    } else {
+        None
+    }
+}
+
+pub(super) fn map_directory(
+    destination_path: &Path,
+    plan: &Plan,
+    opts: &MapperOptions,
+) {
+    for (source_path, message) in &plan.failures {
+        opts.report_failure(source_path, message.clone());
+    }
+
+    map_date_directory(destination_path, plan, opts);
+}
+
+fn map_date_directory(
+    destination_path: &Path,
+    plan: &Plan,
+    opts: &MapperOptions,
+) {
+    opts.report(Progress::EnteredDirectory {
+        source: opts.source_root,
+        destination: destination_path,
+    });
+
+    if let Err(e) = ensure_path_is_directory(destination_path, opts.fs) {
+        let message = format!(
+            "Could not create the directory \"{}\" due to \"{}\", so skipping it.",
+            destination_path.display(),
+            e
+        );
+        opts.report_failure(destination_path, message);
+        return;
+    }
+
+    let change_count = opts.change_count();
+
+    for (destination_file_path, source_file_path) in
+        plan.files_in(destination_path)
+    {
+        if let Some(handler) =
+            opts.registry.handler_for_source(source_file_path)
+        {
+            create_destination_file(
+                handler,
+                source_file_path,
+                destination_file_path,
+                opts,
+            );
+        }
+    }
+
+    for destination_dir_path in plan.subdirectories_of(destination_path) {
+        map_date_directory(destination_dir_path, plan, opts);
+    }
+
+    delete_unplanned_entries(destination_path, plan, opts);
+
+    let source_of =
+        |destination_file_path: &Path| plan.source_of(destination_file_path);
+    update_generated_files(destination_path, change_count, &source_of, opts);
+}
+
+fn delete_unplanned_entries(
+    destination_path: &Path,
+    plan: &Plan,
+    opts: &MapperOptions,
+) {
+    if !plan.failures.is_empty() {
+        info!(
+            "Not deleting anything in \"{}\", since some source entries couldn't be read",
+            destination_path.display()
+        );
+        return;
+    }
+
+    let destination_entry_paths = match opts.fs.read_dir(destination_path) {
+        Ok(destination_entry_paths) => destination_entry_paths,
+        Err(e) => {
+            let message = format!(
+                "Could not read the destination directory \"{}\" due to \"{}\"",
+                destination_path.display(),
+                e
+            );
+            opts.report_failure(destination_path, message);
+            return;
+        }
+    };
+
+    for destination_entry_path in &destination_entry_paths {
+        if !is_reserved_destination_path(destination_entry_path)
+            && !plan.is_planned(destination_entry_path, opts.fs)
+        {
+            delete_destination_entry(destination_entry_path, opts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::ImageQuality;
+
+    #[test]
+    fn date_directory_is_correct_for_each_layout() {
+        let time = Some("2010-03-14 11:22:33");
+        let settings = |layout| {
+            Settings::builder(ImageQuality::Mobile)
+                .layout(layout)
+                .undated_folder("No date")
+                .build()
+        };
+
+        assert_eq!(
+            date_directory(time, &settings(Layout::YearMonth)),
+            Path::new("2010").join("03")
+        );
+        assert_eq!(
+            date_directory(time, &settings(Layout::YearDate)),
+            Path::new("2010").join("2010-03-14")
+        );
+        assert_eq!(
+            date_directory(None, &settings(Layout::YearDate)),
+            PathBuf::from("No date")
+        );
+    }
+
+    #[test]
+    fn parse_date_rejects_invalid_dates() {
+        assert_eq!(
+            parse_date("2010-03-14 11:22:33"),
+            Some(("2010", "03", "14"))
+        );
+        assert_eq!(parse_date("0000-00-00 00:00:00"), None);
+        assert_eq!(parse_date("    -  -     :  :  "), None);
+        assert_eq!(parse_date("2010:03:14 11:22:33"), None);
+        assert_eq!(parse_date("2010"), None);
+    }
+}
@@ -0,0 +1,105 @@
+// Finds source files whose contents are identical to ones that are already
+// in the destination, so that they don't have to be converted again. The
+// sizes are compared first, so that only files of the same size are
+// hashed, and each file is hashed at most once.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::file_system::FileSystem;
+use crate::media_handler;
+
+#[derive(Clone)]
+struct Output {
+    source_file_path: PathBuf,
+    destination_file_path: PathBuf,
+    quality: Option<u8>,
+}
+
+#[derive(Default)]
+pub(super) struct Deduplicator {
+    // Source file size -> the source files of that size that are in the
+    // destination, their destination files, and the qualities that were
+    // chosen for them
+    outputs_by_size: RefCell<HashMap<u64, Vec<Output>>>,
+    hashes: RefCell<HashMap<PathBuf, Vec<u8>>>,
+}
+
+impl Deduplicator {
+    /// Remembers that `destination_file_path` was created from
+    /// `source_file_path`, with `quality` if it was chosen for it
+    pub(super) fn add(
+        &self,
+        source_file_path: &Path,
+        destination_file_path: &Path,
+        quality: Option<u8>,
+        fs: &dyn FileSystem,
+    ) {
+        // A file that can't be read can't be a duplicate either
+        if let Ok(size) = fs.file_size(source_file_path) {
+            self.outputs_by_size
+                .borrow_mut()
+                .entry(size)
+                .or_default()
+                .push(Output {
+                    source_file_path: source_file_path.to_path_buf(),
+                    destination_file_path: destination_file_path.to_path_buf(),
+                    quality,
+                });
+        }
+    }
+
+    /// A destination file created from a source file with the same contents
+    /// as `source_file_path`, that `destination_file_path` can be a copy of,
+    /// and the quality that was chosen for it. Only destination files with
+    /// the same extension qualify, since the same contents might be
+    /// converted differently otherwise.
+    pub(super) fn find_duplicate(
+        &self,
+        source_file_path: &Path,
+        destination_file_path: &Path,
+        fs: &dyn FileSystem,
+    ) -> io::Result<Option<(PathBuf, Option<u8>)>> {
+        let size = fs.file_size(source_file_path)?;
+        let candidates = match self.outputs_by_size.borrow().get(&size) {
+            Some(candidates) => candidates.clone(),
+            None => return Ok(None),
+        };
+
+        let hash = self.hash(source_file_path, fs)?;
+        for other in candidates {
+            if other.destination_file_path.extension()
+                != destination_file_path.extension()
+                || !fs.is_file(&other.destination_file_path)
+            {
+                continue;
+            }
+            // The other source might have changed or vanished since
+            match self.hash(&other.source_file_path, fs) {
+                Ok(other_hash) if other_hash == hash => {
+                    return Ok(Some((
+                        other.destination_file_path,
+                        other.quality,
+                    )));
+                }
+                _ => continue,
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn hash(&self, path: &Path, fs: &dyn FileSystem) -> io::Result<Vec<u8>> {
+        if let Some(hash) = self.hashes.borrow().get(path) {
+            return Ok(hash.clone());
+        }
+
+        let hash = media_handler::hash_file(path, fs)?;
+        self.hashes
+            .borrow_mut()
+            .insert(path.to_path_buf(), hash.clone());
+        Ok(hash)
+    }
+}
@@ -1,12 +1,30 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::ffi::OsStr;
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use tempfile::TempDir;
 
+use crate::file_names;
+use crate::file_system::{
+    FileSystem, InMemoryFileSystem, Operation, RealFileSystem,
+};
+use crate::image::ImageError;
+use crate::index::{self, DirectoryEntry, FileEntry, Index};
 use crate::mapper;
-use crate::mapper::MapperError;
-use crate::settings::{ImageQuality, Settings};
+use crate::mapper::{MapperError, Progress, Summary};
+use crate::marker::{self, Marker};
+use crate::media_handler::{
+    Conversion, HandlerRegistry, ImageConverter, ImageHandler, MediaHandler,
+    VideoHandler,
+};
+use crate::settings::{
+    ColorProfile, ExifGroup, ImageQuality, Layout, OutputFormat, Preset,
+    ResizeFilter, Settings, TargetSize,
+};
 
 #[test]
 fn test_ensure_path_is_directory_removes_file() {
@@ -14,7 +32,8 @@ fn test_ensure_path_is_directory_removes_file() {
     let destination_path = &temp_dir.path().join("dst");
     File::create(destination_path).unwrap();
 
-    mapper::ensure_path_is_directory(destination_path);
+    mapper::ensure_path_is_directory(destination_path, &RealFileSystem)
+        .unwrap();
 
     assert!(destination_path.is_dir());
 }
@@ -24,7 +43,8 @@ fn test_ensure_path_is_directory_adds_directory() {
     let temp_dir = tempdir();
     let destination_path = &temp_dir.path().join("dst");
 
-    mapper::ensure_path_is_directory(destination_path);
+    mapper::ensure_path_is_directory(destination_path, &RealFileSystem)
+        .unwrap();
 
     assert!(destination_path.is_dir());
 }
@@ -38,7 +58,8 @@ fn test_ensure_path_is_directory_does_not_remove_directory() {
     let destination_file = &destination_path.join("file");
     File::create(destination_file).unwrap();
 
-    mapper::ensure_path_is_directory(destination_path);
+    mapper::ensure_path_is_directory(destination_path, &RealFileSystem)
+        .unwrap();
 
     assert!(destination_file.exists());
 }
@@ -377,11 +398,9 @@ fn test_map_directory_with_image_conversion() {
         let exp_src_entries = vec!["small-with-exif.jpg"];
         assert_dir_entries(&exp_src_entries, src_path);
 
-        let settings = Settings {
-            image_quality,
-            verbose_print: false,
-            include_videos: true,
-        };
+        let settings = Settings::builder(image_quality)
+            .include_videos(true)
+            .build();
 
         mapper::map_directory(src_path, dst_path, settings).unwrap();
 
@@ -404,7 +423,7 @@ fn test_source_does_not_exist() {
     let dst_path = &dst_dir.path();
     assert!(dst_path.is_dir());
 
-    let result = mapper::map_directory(&src_path, &dst_path, SETTINGS);
+    let result = mapper::map_directory(&src_path, dst_path, settings());
 
     assert_eq!(Err(MapperError::SrcDoesNotExist), result);
 }
@@ -421,7 +440,7 @@ fn test_source_is_not_a_dir() {
     let dst_path = &dst_dir.path();
     assert!(dst_path.is_dir());
 
-    let result = mapper::map_directory(&src_path, &dst_path, SETTINGS);
+    let result = mapper::map_directory(&src_path, dst_path, settings());
 
     assert_eq!(Err(MapperError::SrcDoesNotExist), result);
 }
@@ -436,7 +455,7 @@ fn test_destination_does_not_exist() {
     let mut dst_path = dst_dir.path().to_path_buf();
     dst_path.push("does_not_exist");
 
-    let result = mapper::map_directory(&src_path, &dst_path, SETTINGS);
+    let result = mapper::map_directory(src_path, &dst_path, settings());
 
     assert_eq!(Err(MapperError::DstDoesNotExist), result);
 }
@@ -453,7 +472,7 @@ fn tst_destination_is_not_a_dir() {
     fs::write(&dst_path, b"content").unwrap();
     assert!(dst_path.is_file());
 
-    let result = mapper::map_directory(&src_path, &dst_path, SETTINGS);
+    let result = mapper::map_directory(src_path, &dst_path, settings());
 
     assert_eq!(Err(MapperError::DstDoesNotExist), result);
 }
@@ -466,7 +485,7 @@ fn test_destination_dir_inside_source_dir() {
     let dst_path = src_path.join("subdir");
     fs::create_dir(&dst_path).unwrap();
 
-    let result = mapper::map_directory(&src_path, &dst_path, SETTINGS);
+    let result = mapper::map_directory(src_path, &dst_path, settings());
 
     assert_eq!(Err(MapperError::DstInsideSrc), result);
 }
@@ -476,7 +495,7 @@ fn test_source_and_destination_dir_are_the_same() {
     let dir = tempdir();
     let path = &dir.path();
 
-    let result = mapper::map_directory(&path, &path, SETTINGS);
+    let result = mapper::map_directory(path, path, settings());
 
     assert_eq!(Err(MapperError::SrcInsideDst), result);
 }
@@ -493,7 +512,7 @@ fn test_source_dir_inside_destination_dir() {
     fs::create_dir_all(&src_path).unwrap();
     fs::create_dir_all(&dst_path).unwrap();
 
-    let result = mapper::map_directory(&src_path, &dst_path, SETTINGS);
+    let result = mapper::map_directory(&src_path, &dst_path, settings());
 
     assert_eq!(Err(MapperError::SrcInsideDst), result);
 }
@@ -513,7 +532,7 @@ fn test_destination_dir_has_a_file_source_dir_empty() {
 
     assert_eq!(
         Err(MapperError::DstTopLevelEntryNotInSrc(dst_file_path.clone())),
-        mapper::map_directory(&src_path, &dst_path, SETTINGS),
+        mapper::map_directory(src_path, dst_path, settings()),
     );
 }
 
@@ -534,7 +553,7 @@ fn test_destination_dir_has_a_dir_source_dir_empty() {
         Err(MapperError::DstTopLevelEntryNotInSrc(
             dir_in_dst_path.clone()
         )),
-        mapper::map_directory(&src_path, &dst_path, SETTINGS),
+        mapper::map_directory(src_path, dst_path, settings()),
     );
 }
 
@@ -546,7 +565,7 @@ fn test_destination_dir_has_a_file_source_dir_does_not() {
     let dst_path = dst_dir.path();
 
     create_src_structure_in_dir(src_path);
-    map_directory_ok(&src_path, &dst_path, true);
+    map_directory_ok(src_path, dst_path, true);
 
     let file_only_in_dst = dst_path.join("some_file");
     fs::write(&file_only_in_dst, b"content").unwrap();
@@ -555,7 +574,7 @@ fn test_destination_dir_has_a_file_source_dir_does_not() {
         Err(MapperError::DstTopLevelEntryNotInSrc(
             file_only_in_dst.clone()
         )),
-        mapper::map_directory(&src_path, &dst_path, SETTINGS),
+        mapper::map_directory(src_path, dst_path, settings()),
     );
 }
 
@@ -567,7 +586,7 @@ fn test_destination_dir_has_a_dir_source_dir_does_not() {
     let dst_path = dst_dir.path();
 
     create_src_structure_in_dir(src_path);
-    map_directory_ok(&src_path, &dst_path, true);
+    map_directory_ok(src_path, dst_path, true);
 
     let dir_only_in_dst = dst_path.join("some_dir");
     fs::create_dir(&dir_only_in_dst).unwrap();
@@ -576,7 +595,7 @@ fn test_destination_dir_has_a_dir_source_dir_does_not() {
         Err(MapperError::DstTopLevelEntryNotInSrc(
             dir_only_in_dst.clone()
         )),
-        mapper::map_directory(&src_path, &dst_path, SETTINGS),
+        mapper::map_directory(src_path, dst_path, settings()),
     );
 }
 
@@ -588,7 +607,7 @@ fn test_destination_dir_has_multiple_entries_source_dir_does_not() {
     let dst_path = dst_dir.path();
 
     create_src_structure_in_dir(src_path);
-    map_directory_ok(&src_path, &dst_path, true);
+    map_directory_ok(src_path, dst_path, true);
 
     let file_only_in_dst1 = dst_path.join("file1");
     fs::write(&file_only_in_dst1, b"content").unwrap();
@@ -599,10 +618,1159 @@ fn test_destination_dir_has_multiple_entries_source_dir_does_not() {
         Err(MapperError::DstTopLevelEntryNotInSrc(
             file_only_in_dst2.clone()
         )),
-        mapper::map_directory(&src_path, &dst_path, SETTINGS),
+        mapper::map_directory(src_path, dst_path, settings()),
+    );
+}
+
+#[test]
+fn test_destination_locked_by_another_instance() {
+    let src_dir = tempdir();
+    let src_path = src_dir.path();
+    let dst_dir = tempdir();
+    let dst_path = dst_dir.path();
+
+    let lock = mapper::lock_destination(dst_path, &RealFileSystem).unwrap();
+
+    assert_eq!(
+        Err(MapperError::DstLocked),
+        mapper::map_directory(src_path, dst_path, settings()),
+    );
+
+    drop(lock);
+    assert!(mapper::map_directory(src_path, dst_path, settings()).is_ok());
+}
+
+#[test]
+fn test_map_directory_summary_counts_created_and_deleted() {
+    let src_dir = tempdir();
+    let src_path = src_dir.path();
+    let dst_dir = tempdir();
+    let dst_path = dst_dir.path();
+    create_src_structure_in_dir(src_path);
+
+    let summary = map_directory_ok(src_path, dst_path, true);
+    assert_eq!(
+        Summary {
+            created_files: 7,
+            deleted_entries: 0,
+            failed_entries: 0,
+            duplicate_files: 0,
+            saved_bytes: 0,
+            chosen_qualities: vec![]
+        },
+        summary
+    );
+
+    File::create(dst_path.join("dir1").join("does not exist.txt")).unwrap();
+    fs::create_dir(dst_path.join("dir1").join("dir4")).unwrap();
+    let summary = map_directory_ok(src_path, dst_path, true);
+    assert_eq!(
+        Summary {
+            created_files: 0,
+            deleted_entries: 2,
+            failed_entries: 0,
+            duplicate_files: 0,
+            saved_bytes: 0,
+            chosen_qualities: vec![]
+        },
+        summary
+    );
+}
+
+#[test]
+fn test_map_directory_continues_after_failed_conversion() {
+    let src_dir = tempdir();
+    let src_path = src_dir.path();
+    let dst_dir = tempdir();
+    let dst_path = dst_dir.path();
+    create_src_structure_in_dir(src_path);
+
+    let settings = settings();
+    let summary = mapper::map_directory_with_handlers(
+        src_path,
+        dst_path,
+        settings,
+        registry(failing_convert_image, true),
+        &RealFileSystem,
+        &|_| {},
+    )
+    .unwrap();
+
+    assert_eq!(
+        Summary {
+            created_files: 1,
+            deleted_entries: 0,
+            failed_entries: 6,
+            duplicate_files: 0,
+            saved_bytes: 0,
+            chosen_qualities: vec![]
+        },
+        summary
+    );
+    assert_dir_entries(
+        &[
+            "dir1",
+            "dir2",
+            "dir2/subdir1",
+            "dir2/subdir2",
+            "dir3",
+            "video.m4v",
+        ],
+        dst_path,
+    );
+}
+
+#[test]
+fn test_map_directory_reports_progress() {
+    let src_dir = tempdir();
+    let src_path = src_dir.path();
+    let dst_dir = tempdir();
+    let dst_path = dst_dir.path();
+
+    fs::copy(
+        "test_resources/small-without-exif.jpg",
+        src_path.join("small-without-exif.jpg"),
+    )
+    .unwrap();
+    // Exists in the source too, so that the top-level check passes
+    File::create(src_path.join("text_file.txt")).unwrap();
+    File::create(dst_path.join("text_file.txt")).unwrap();
+
+    let events = RefCell::new(Vec::new());
+    let progress = |progress: &Progress| {
+        events.borrow_mut().push(format!("{:?}", progress));
+    };
+    mapper::map_directory_with_handlers(
+        src_path,
+        dst_path,
+        Settings::builder(ImageQuality::Mobile).adopt(true).build(),
+        registry(no_convert_image, false),
+        &RealFileSystem,
+        &progress,
+    )
+    .unwrap();
+
+    let exp_events = vec![
+        format!(
+            "{:?}",
+            Progress::EnteredDirectory {
+                source: src_path,
+                destination: dst_path
+            }
+        ),
+        format!(
+            "{:?}",
+            Progress::Created(
+                &dst_path.join("small-without-exif.jpg.jpg"),
+                None
+            )
+        ),
+        format!("{:?}", Progress::Deleted(&dst_path.join("text_file.txt"))),
+    ];
+    assert_eq!(exp_events, events.into_inner());
+}
+
+#[test]
+fn test_map_directory_with_custom_handler() {
+    let src_dir = tempdir();
+    let src_path = src_dir.path();
+    let dst_dir = tempdir();
+    let dst_path = dst_dir.path();
+    create_src_structure_in_dir(src_path);
+
+    let registry_with_text_handler = || {
+        let mut registry = registry(no_convert_image, false);
+        registry.register(Box::new(TextHandler));
+        registry
+    };
+
+    mapper::map_directory_with_handlers(
+        src_path,
+        dst_path,
+        settings(),
+        registry_with_text_handler(),
+        &RealFileSystem,
+        &|_| {},
+    )
+    .unwrap();
+    File::create(dst_path.join("dir1").join("other.txt")).unwrap();
+    mapper::map_directory_with_handlers(
+        src_path,
+        dst_path,
+        settings(),
+        registry_with_text_handler(),
+        &RealFileSystem,
+        &|_| {},
+    )
+    .unwrap();
+
+    assert_dir_entries(
+        &[
+            "   2010-03-14 11;22;33 small-with-exif.jpg.jpg",
+            "dir1",
+            "dir1/   2010-03-14 11;22;33 small-with-exif.jpg.jpg",
+            "dir2",
+            "dir2/subdir1",
+            "dir2/subdir1/   2010-03-14 11;22;33 small-with-exifåäöあ!@#$%^&*().jpg.jpg",
+            "dir2/subdir2",
+            "dir2/   2010-03-14 11;22;33 small-with-exif.jpg.jpg",
+            "dir3",
+            "small-without-exif.jpg.jpg",
+            "small-without-exif.png.jpg",
+            "text_file.txt.txt",
+        ],
+        dst_path,
+    );
+    assert_eq!(
+        "copied",
+        fs::read_to_string(dst_path.join("text_file.txt.txt")).unwrap()
+    );
+}
+
+#[test]
+fn test_destination_marker_is_written() {
+    let fs = in_memory_src_structure();
+
+    map_directory_in_memory(&fs, &settings());
+
+    let marker = marker::read_marker(Path::new("/dst"), &fs).unwrap();
+    assert_eq!(Marker::new(Path::new("/src"), &settings()), marker);
+}
+
+#[test]
+fn test_destination_not_marked() {
+    let fs = in_memory_src_structure();
+    // Same names as in the source, so the top-level check passes
+    fs.add_file(Path::new("/dst/image.png.jpg"), b"unrelated");
+    fs.add_dir(Path::new("/dst/dir1"));
+
+    let result = mapper::map_directory_with_handlers(
+        Path::new("/src"),
+        Path::new("/dst"),
+        settings(),
+        registry(no_convert_image, true),
+        &fs,
+        &|_| {},
+    );
+
+    assert_eq!(Err(MapperError::DstNotMarked), result);
+    assert_eq!(
+        fs.read(Path::new("/dst/image.png.jpg")).unwrap(),
+        b"unrelated"
+    );
+    assert!(marker::read_marker(Path::new("/dst"), &fs).is_none());
+}
+
+#[test]
+fn test_destination_not_marked_adopted() {
+    let fs = in_memory_src_structure();
+    fs.add_file(Path::new("/dst/image.png.jpg"), b"image");
+    fs.add_dir(Path::new("/dst/dir1"));
+
+    let adopting_settings = Settings {
+        adopt: true,
+        ..settings()
+    };
+    let summary = map_directory_in_memory(&fs, &adopting_settings);
+
+    assert_eq!(2, summary.created_files);
+    assert_in_memory_dst_structure_is_correct(&fs);
+    let marker = marker::read_marker(Path::new("/dst"), &fs).unwrap();
+    assert_eq!(Marker::new(Path::new("/src"), &settings()), marker);
+
+    // From now on, it's owned without --adopt
+    map_directory_in_memory(&fs, &settings());
+}
+
+#[test]
+fn test_destination_marked_for_other_source() {
+    let fs = in_memory_src_structure();
+    map_directory_in_memory(&fs, &settings());
+    fs.add_file(Path::new("/other_src/dir1/image.jpg"), b"image");
+    fs.add_file(Path::new("/other_src/image.png"), b"image");
+    fs.add_file(Path::new("/other_src/video.m4v"), b"video");
+    fs.add_dir(Path::new("/other_src/dir2"));
+
+    let map_other_source = |settings| {
+        mapper::map_directory_with_handlers(
+            Path::new("/other_src"),
+            Path::new("/dst"),
+            settings,
+            registry(no_convert_image, true),
+            &fs,
+            &|_| {},
+        )
+    };
+
+    assert_eq!(
+        Err(MapperError::DstMarkedForOtherSrc(PathBuf::from("/src"))),
+        map_other_source(settings())
+    );
+
+    let settings = Settings {
+        adopt: true,
+        ..settings()
+    };
+    assert!(map_other_source(settings).is_ok());
+    let marker = marker::read_marker(Path::new("/dst"), &fs).unwrap();
+    assert_eq!(PathBuf::from("/other_src"), marker.source);
+}
+
+#[test]
+fn test_index_is_written() {
+    let fs = InMemoryFileSystem::new();
+    let settings = Settings {
+        write_index: true,
+        ..settings()
+    };
+    fs.add_file(
+        Path::new("/src/dir/small-with-exif.jpg"),
+        &fs::read("test_resources/small-with-exif.jpg").unwrap(),
+    );
+    fs.add_file(
+        Path::new("/src/small-without-exif.png"),
+        &fs::read("test_resources/small-without-exif.png").unwrap(),
+    );
+    fs.add_file(Path::new("/src/video.mp4"), b"video");
+    fs.add_dir(Path::new("/dst"));
+
+    map_directory_in_memory(&fs, &settings);
+
+    let name = "   2010-03-14 11;22;33 small-with-exif.jpg.jpg";
+    let index = index::read_index(Path::new("/dst/dir"), &fs).unwrap();
+    assert_eq!(
+        Index {
+            files: vec![FileEntry {
+                name: name.to_string(),
+                source_name: "small-with-exif.jpg".to_string(),
+                size: fs.file_size(&Path::new("/dst/dir").join(name)).unwrap(),
+                capture_time: Some("2010-03-14 11:22:33".to_string()),
+                width: Some(45),
+                height: Some(30),
+                orientation: Some(1),
+                camera_model: None,
+            }],
+            directories: vec![],
+        },
+        index
+    );
+
+    let index = index::read_index(Path::new("/dst"), &fs).unwrap();
+    assert_eq!(
+        vec![DirectoryEntry {
+            name: "dir".to_string(),
+            files: 1,
+            directories: 0
+        }],
+        index.directories
+    );
+    let names: Vec<&str> =
+        index.files.iter().map(|file| file.name.as_str()).collect();
+    assert_eq!(vec!["small-without-exif.png.jpg", "video.mp4"], names);
+    assert_eq!(None, index.files[0].capture_time);
+    assert_eq!(Some(45), index.files[0].width);
+    assert_eq!(5, index.files[1].size);
+    assert_eq!(None, index.files[1].width);
+}
+
+#[test]
+fn test_index_is_only_rewritten_when_directory_changed() {
+    let fs = in_memory_src_structure();
+    let settings = Settings {
+        write_index: true,
+        ..settings()
+    };
+    map_directory_in_memory(&fs, &settings);
+    fs.write(Path::new("/dst/dir2/index.json"), b"unchanged")
+        .unwrap();
+    fs.write(Path::new("/dst/dir1/index.json"), b"unchanged")
+        .unwrap();
+
+    fs.add_file(Path::new("/src/dir1/new_image.jpg"), b"image");
+    map_directory_in_memory(&fs, &settings);
+
+    assert_eq!(
+        b"unchanged",
+        &fs.read(Path::new("/dst/dir2/index.json")).unwrap()[..]
+    );
+    let index = index::read_index(Path::new("/dst/dir1"), &fs).unwrap();
+    assert_eq!(2, index.files.len());
+    let index = index::read_index(Path::new("/dst"), &fs).unwrap();
+    assert_eq!(2, index.directories[0].files);
+}
+
+#[test]
+fn test_index_is_deleted_when_disabled() {
+    let fs = in_memory_src_structure();
+    map_directory_in_memory(
+        &fs,
+        &Settings {
+            write_index: true,
+            ..settings()
+        },
+    );
+    assert!(fs.is_file(Path::new("/dst/dir1/index.json")));
+
+    let summary = map_directory_in_memory(
+        &fs,
+        &Settings {
+            write_index: false,
+            ..settings()
+        },
+    );
+
+    assert_eq!(3, summary.deleted_entries);
+    assert_in_memory_dst_structure_is_correct(&fs);
+}
+
+#[test]
+fn test_gallery_pages_are_written() {
+    let fs = in_memory_src_structure();
+    let settings = Settings {
+        write_gallery: true,
+        ..settings()
+    };
+
+    map_directory_in_memory(&fs, &settings);
+
+    let root_page = fs.read(Path::new("/dst/index.html")).unwrap();
+    let root_page = String::from_utf8(root_page).unwrap();
+    assert!(root_page.contains("<a href=\"dir1/index.html\">dir1</a> (1)"));
+    assert!(root_page.contains("<a class=\"video\" href=\"video.m4v\">"));
+    assert!(!root_page.contains("../index.html"));
+    let dir_page = fs.read(Path::new("/dst/dir1/index.html")).unwrap();
+    let dir_page = String::from_utf8(dir_page).unwrap();
+    assert!(dir_page.contains("<a href=\"../index.html\">"));
+    assert!(fs.is_file(Path::new("/dst/dir2/index.html")));
+    assert!(!fs.exists(Path::new("/dst/index.json")));
+
+    // Unchanged directories keep their pages
+    fs.write(Path::new("/dst/dir2/index.html"), b"unchanged")
+        .unwrap();
+    fs.add_file(Path::new("/src/dir1/new_image.jpg"), b"image");
+    map_directory_in_memory(&fs, &settings);
+
+    assert_eq!(
+        b"unchanged",
+        &fs.read(Path::new("/dst/dir2/index.html")).unwrap()[..]
+    );
+    let root_page = fs.read(Path::new("/dst/index.html")).unwrap();
+    let root_page = String::from_utf8(root_page).unwrap();
+    assert!(root_page.contains("<a href=\"dir1/index.html\">dir1</a> (2)"));
+}
+
+#[test]
+fn test_gallery_thumbnails_are_written() {
+    let fs = in_memory_src_structure();
+    let image = fs::read("test_resources/small-without-exif.jpg").unwrap();
+    fs.write(Path::new("/src/dir1/image.jpg"), &image).unwrap();
+    let settings = Settings {
+        write_gallery: true,
+        ..settings()
+    };
+
+    let summary = map_directory_in_memory(&fs, &settings);
+
+    assert_eq!(0, summary.failed_entries);
+    let thumbnail_path = Path::new("/dst/dir1/.thumbs/image.jpg.jpg.jpg");
+    assert!(fs.is_file(thumbnail_path));
+    let dir_page = fs.read(Path::new("/dst/dir1/index.html")).unwrap();
+    let dir_page = String::from_utf8(dir_page).unwrap();
+    assert!(dir_page.contains("<img src=\".thumbs/image.jpg.jpg.jpg\""));
+    assert!(dir_page.contains("<img src=\"image.jpg.jpg\""));
+
+    // Thumbnails of deleted images are deleted
+    fs.remove_file(Path::new("/src/dir1/image.jpg")).unwrap();
+    let summary = map_directory_in_memory(&fs, &settings);
+
+    assert_eq!(1, summary.deleted_entries);
+    assert!(!fs.exists(thumbnail_path));
+
+    // And the thumbnails directories are deleted without the gallery
+    fs.add_file(Path::new("/src/dir1/image.jpg"), &image);
+    map_directory_in_memory(&fs, &settings);
+    assert!(fs.is_file(thumbnail_path));
+    let settings = Settings {
+        write_gallery: false,
+        ..settings
+    };
+    let summary = map_directory_in_memory(&fs, &settings);
+
+    assert_eq!(0, summary.failed_entries);
+    assert!(!fs.exists(Path::new("/dst/dir1/.thumbs")));
+}
+
+#[test]
+fn test_gallery_thumbnails_are_written_like_the_images() {
+    let mut preset = ImageQuality::Television.preset();
+    preset.filter = ResizeFilter::Lanczos3;
+    preset.format = OutputFormat::Webp;
+    let settings = Settings {
+        keep_exif: vec![ExifGroup::Date, ExifGroup::Gps],
+        strip_gps: true,
+        color_profile: ColorProfile::Keep,
+        ..Settings::builder(ImageQuality::Custom(preset)).build()
+    };
+
+    let thumbnail_settings = mapper::thumbnail_settings(&settings);
+
+    let thumbnail_preset = thumbnail_settings.image_quality.preset();
+    assert_eq!(300, thumbnail_preset.max_width);
+    assert_eq!(ResizeFilter::Lanczos3, thumbnail_preset.filter);
+    assert_eq!(OutputFormat::Jpeg, thumbnail_preset.format);
+    assert_eq!(settings.keep_exif, thumbnail_settings.keep_exif);
+    assert!(thumbnail_settings.strip_gps);
+    assert_eq!(ColorProfile::Keep, thumbnail_settings.color_profile);
+}
+
+#[test]
+fn test_gallery_thumbnail_failures_are_not_failed_entries() {
+    let fs = in_memory_src_structure();
+    let image = fs::read("test_resources/small-without-exif.jpg").unwrap();
+    fs.write(Path::new("/src/dir1/image.jpg"), &image).unwrap();
+    fs.fail(
+        Operation::Write,
+        Path::new("/dst/dir1/.thumbs/image.jpg.jpg.jpg"),
+        io::ErrorKind::StorageFull,
+    );
+    let settings = Settings {
+        write_gallery: true,
+        ..settings()
+    };
+
+    let summary = map_directory_in_memory(&fs, &settings);
+
+    assert_eq!(0, summary.failed_entries);
+    let dir_page = fs.read(Path::new("/dst/dir1/index.html")).unwrap();
+    let dir_page = String::from_utf8(dir_page).unwrap();
+    assert!(
+        dir_page.contains("<a href=\"#image-1\"><img src=\"image.jpg.jpg\"")
+    );
+}
+
+#[test]
+fn test_year_month_layout() {
+    let fs = in_memory_dated_src_structure();
+    let settings = Settings {
+        layout: Layout::YearMonth,
+        ..settings()
+    };
+
+    let summary = map_directory_in_memory(&fs, &settings);
+
+    assert_eq!(3, summary.created_files);
+    assert_in_memory_dst_paths(
+        &fs,
+        &[
+            "/dst/2010",
+            "/dst/2010/03",
+            "/dst/2010/03/   2010-03-14 11;22;33 image.jpg.jpg",
+            "/dst/Undated",
+            "/dst/Undated/image.png.jpg",
+            "/dst/Undated/video.m4v",
+        ],
+    );
+}
+
+#[test]
+fn test_year_date_layout() {
+    let fs = in_memory_dated_src_structure();
+    let settings = Settings {
+        layout: Layout::YearDate,
+        ..settings()
+    };
+
+    map_directory_in_memory(&fs, &settings);
+
+    assert!(fs.is_file(Path::new(
+        "/dst/2010/2010-03-14/   2010-03-14 11;22;33 image.jpg.jpg"
+    )));
+}
+
+#[test]
+fn test_date_layout_with_custom_undated_folder() {
+    let fs = in_memory_dated_src_structure();
+
+    let settings = Settings::builder(ImageQuality::Mobile)
+        .include_videos(true)
+        .layout(Layout::YearMonth)
+        .undated_folder("No date")
+        .build();
+    map_directory_in_memory(&fs, &settings);
+
+    assert!(fs.is_file(Path::new("/dst/No date/image.png.jpg")));
+    assert!(!fs.exists(Path::new("/dst/Undated")));
+}
+
+#[test]
+fn test_date_layout_keeps_existing_and_deletes_removed_files() {
+    let fs = in_memory_dated_src_structure();
+    let settings = Settings {
+        layout: Layout::YearMonth,
+        ..settings()
+    };
+    map_directory_in_memory(&fs, &settings);
+
+    fs.remove_file(Path::new("/src/image.png")).unwrap();
+    fs.add_file(Path::new("/dst/Undated/stray.jpg.jpg"), b"stray");
+    fs.add_file(Path::new("/dst/2010/04/stray.jpg.jpg"), b"stray");
+    let summary = map_directory_in_memory(&fs, &settings);
+
+    assert_eq!(
+        Summary {
+            created_files: 0,
+            deleted_entries: 3,
+            failed_entries: 0,
+            duplicate_files: 0,
+            saved_bytes: 0,
+            chosen_qualities: vec![]
+        },
+        summary
+    );
+    assert_in_memory_dst_paths(
+        &fs,
+        &[
+            "/dst/2010",
+            "/dst/2010/03",
+            "/dst/2010/03/   2010-03-14 11;22;33 image.jpg.jpg",
+            "/dst/Undated",
+            "/dst/Undated/video.m4v",
+        ],
+    );
+}
+
+#[test]
+fn test_date_layout_maps_first_of_colliding_files() {
+    let fs = in_memory_dated_src_structure();
+    let settings = Settings {
+        layout: Layout::YearMonth,
+        ..settings()
+    };
+    fs.add_file(Path::new("/src/later/image.png"), b"other image");
+
+    let summary = map_directory_in_memory(&fs, &settings);
+
+    assert_eq!(3, summary.created_files);
+    assert_eq!(
+        b"image".to_vec(),
+        fs.read(Path::new("/dst/Undated/image.png.jpg")).unwrap()
+    );
+}
+
+#[test]
+fn test_date_layout_top_level_entry_not_planned() {
+    let fs = in_memory_dated_src_structure();
+    let settings = Settings {
+        layout: Layout::YearMonth,
+        ..settings()
+    };
+    map_directory_in_memory(&fs, &settings);
+
+    fs.add_dir(Path::new("/dst/1999"));
+    let result = mapper::map_directory_with_handlers(
+        Path::new("/src"),
+        Path::new("/dst"),
+        settings,
+        registry(no_convert_image, true),
+        &fs,
+        &|_| {},
+    );
+
+    assert_eq!(
+        Err(MapperError::DstTopLevelEntryNotInSrc(PathBuf::from(
+            "/dst/1999"
+        ))),
+        result
     );
 }
 
+#[test]
+fn test_date_layout_source_dir_unreadable() {
+    let fs = in_memory_dated_src_structure();
+    let settings = Settings {
+        layout: Layout::YearMonth,
+        ..settings()
+    };
+    map_directory_in_memory(&fs, &settings);
+    fs.fail(
+        Operation::ReadDir,
+        Path::new("/src/dir1"),
+        io::ErrorKind::PermissionDenied,
+    );
+
+    let summary = map_directory_in_memory(&fs, &settings);
+
+    assert_eq!(
+        Summary {
+            created_files: 0,
+            deleted_entries: 0,
+            failed_entries: 1,
+            duplicate_files: 0,
+            saved_bytes: 0,
+            chosen_qualities: vec![]
+        },
+        summary
+    );
+    assert!(fs.exists(Path::new(
+        "/dst/2010/03/   2010-03-14 11;22;33 image.jpg.jpg"
+    )));
+}
+
+#[test]
+fn test_date_layout_source_file_unreadable() {
+    let fs = in_memory_dated_src_structure();
+    let settings = Settings {
+        layout: Layout::YearMonth,
+        ..settings()
+    };
+    map_directory_in_memory(&fs, &settings);
+    fs.fail(
+        Operation::Read,
+        Path::new("/src/dir1/image.jpg"),
+        io::ErrorKind::PermissionDenied,
+    );
+
+    let summary = map_directory_in_memory(&fs, &settings);
+
+    assert_eq!(1, summary.failed_entries);
+    assert_eq!(0, summary.deleted_entries);
+    assert!(fs.exists(Path::new(
+        "/dst/2010/03/   2010-03-14 11;22;33 image.jpg.jpg"
+    )));
+    assert!(!fs.exists(Path::new("/dst/Undated/image.jpg.jpg")));
+}
+
+#[test]
+fn test_duplicates_are_linked_instead_of_converted() {
+    let fs = in_memory_duplicates_src_structure();
+    let settings = Settings {
+        deduplicate: true,
+        ..settings()
+    };
+
+    let summary = map_directory_in_memory(&fs, &settings);
+
+    assert_eq!(
+        Summary {
+            created_files: 5,
+            deleted_entries: 0,
+            failed_entries: 0,
+            duplicate_files: 2,
+            saved_bytes: 10,
+            chosen_qualities: vec![]
+        },
+        summary
+    );
+    assert_eq!(
+        b"photo".to_vec(),
+        fs.read(Path::new("/dst/best of/copy.jpg.jpg")).unwrap()
+    );
+}
+
+#[test]
+fn test_chosen_qualities_are_summarized_with_duplicates() {
+    let fs = in_memory_duplicates_src_structure();
+    let mut preset = ImageQuality::Mobile.preset();
+    preset.target_size = Some(TargetSize::new(100_000));
+    let settings = Settings {
+        deduplicate: true,
+        ..Settings::builder(ImageQuality::Custom(preset.clone())).build()
+    };
+
+    let summary = mapper::map_directory_with_handlers(
+        Path::new("/src"),
+        Path::new("/dst"),
+        settings,
+        registry(no_convert_image, false),
+        &fs,
+        &|_| {},
+    )
+    .unwrap();
+
+    // The linked copies have the quality of their original
+    assert_eq!(2, summary.duplicate_files);
+    assert_eq!(vec![preset.quality; 4], summary.chosen_qualities);
+}
+
+#[test]
+fn test_duplicates_are_copied_when_hard_links_fail() {
+    let fs = in_memory_duplicates_src_structure();
+    let settings = Settings {
+        deduplicate: true,
+        ..settings()
+    };
+    fs.fail(
+        Operation::Link,
+        Path::new("/dst/best of/copy.jpg.jpg"),
+        io::ErrorKind::Unsupported,
+    );
+
+    let summary = map_directory_in_memory(&fs, &settings);
+
+    assert_eq!(2, summary.duplicate_files);
+    assert_eq!(5, summary.saved_bytes);
+    assert_eq!(
+        b"photo".to_vec(),
+        fs.read(Path::new("/dst/best of/copy.jpg.jpg")).unwrap()
+    );
+}
+
+#[test]
+fn test_duplicates_of_existing_files_are_linked() {
+    let fs = in_memory_duplicates_src_structure();
+    let settings = Settings {
+        deduplicate: true,
+        ..settings()
+    };
+    map_directory_in_memory(&fs, &settings);
+
+    fs.add_file(Path::new("/src/event/again.jpg"), b"photo");
+    let summary = map_directory_in_memory(&fs, &settings);
+
+    assert_eq!(1, summary.created_files);
+    assert_eq!(1, summary.duplicate_files);
+}
+
+#[test]
+fn test_duplicates_are_converted_without_deduplication() {
+    let fs = in_memory_duplicates_src_structure();
+    let settings = Settings {
+        deduplicate: false,
+        ..settings()
+    };
+
+    let summary = map_directory_in_memory(&fs, &settings);
+
+    assert_eq!(5, summary.created_files);
+    assert_eq!(0, summary.duplicate_files);
+}
+
+// -----------------------------------------------------------------------------
+// In-memory file system
+// -----------------------------------------------------------------------------
+
+#[test]
+fn test_in_memory_map_directory_fills_empty_dst() {
+    let fs = in_memory_src_structure();
+
+    let summary = map_directory_in_memory(&fs, &settings());
+
+    assert_eq!(
+        Summary {
+            created_files: 3,
+            deleted_entries: 0,
+            failed_entries: 0,
+            duplicate_files: 0,
+            saved_bytes: 0,
+            chosen_qualities: vec![]
+        },
+        summary
+    );
+    assert_in_memory_dst_structure_is_correct(&fs);
+}
+
+#[test]
+fn test_in_memory_map_directory_converts_images() {
+    let fs = InMemoryFileSystem::new();
+    fs.add_file(
+        Path::new("/src/small-with-exif.jpg"),
+        &fs::read("test_resources/small-with-exif.jpg").unwrap(),
+    );
+    fs.add_dir(Path::new("/dst"));
+
+    let settings = Settings::builder(ImageQuality::Thumbnail).build();
+    let registry = HandlerRegistry::with_defaults(&settings);
+    mapper::map_directory_with_handlers(
+        Path::new("/src"),
+        Path::new("/dst"),
+        settings,
+        registry,
+        &fs,
+        &|_| {},
+    )
+    .unwrap();
+
+    let converted = fs
+        .read(Path::new(
+            "/dst/   2010-03-14 11;22;33 small-with-exif.jpg.jpg",
+        ))
+        .unwrap();
+    let converted = ::image::load_from_memory(&converted).unwrap();
+    assert!(converted.width() <= 300 && converted.height() <= 300);
+}
+
+#[test]
+fn test_in_memory_map_directory_converts_images_to_custom_preset() {
+    let fs = InMemoryFileSystem::new();
+    fs.add_file(
+        Path::new("/src/small-with-exif.jpg"),
+        &fs::read("test_resources/small-with-exif.jpg").unwrap(),
+    );
+    fs.add_dir(Path::new("/dst"));
+
+    let image_quality = ImageQuality::Custom(Preset {
+        name: "Tiny".to_string(),
+        max_width: 40,
+        max_height: 20,
+        quality: 90,
+        ..ImageQuality::Mobile.preset()
+    });
+    let settings = Settings::builder(image_quality).build();
+    let registry = HandlerRegistry::with_defaults(&settings);
+    mapper::map_directory_with_handlers(
+        Path::new("/src"),
+        Path::new("/dst"),
+        settings,
+        registry,
+        &fs,
+        &|_| {},
+    )
+    .unwrap();
+
+    let converted = fs
+        .read(Path::new(
+            "/dst/   2010-03-14 11;22;33 small-with-exif.jpg.jpg",
+        ))
+        .unwrap();
+    let converted = ::image::load_from_memory(&converted).unwrap();
+    assert_eq!(20, converted.height());
+    assert!(converted.width() <= 40);
+}
+
+#[test]
+fn test_in_memory_map_directory_replaces_images_when_format_changes() {
+    let fs = InMemoryFileSystem::new();
+    fs.add_file(
+        Path::new("/src/small-with-exif.jpg"),
+        &fs::read("test_resources/small-with-exif.jpg").unwrap(),
+    );
+    fs.add_dir(Path::new("/dst"));
+    let map_with_format = |format| {
+        let mut preset = ImageQuality::Thumbnail.preset();
+        preset.format = format;
+        let settings = Settings::builder(ImageQuality::Custom(preset)).build();
+        let registry = HandlerRegistry::with_defaults(&settings);
+        mapper::map_directory_with_handlers(
+            Path::new("/src"),
+            Path::new("/dst"),
+            settings,
+            registry,
+            &fs,
+            &|_| {},
+        )
+        .unwrap()
+    };
+
+    map_with_format(OutputFormat::Jpeg);
+    let summary = map_with_format(OutputFormat::Webp);
+
+    assert_eq!(1, summary.created_files);
+    assert_eq!(1, summary.deleted_entries);
+    assert!(!fs.exists(Path::new(
+        "/dst/   2010-03-14 11;22;33 small-with-exif.jpg.jpg"
+    )));
+    let converted = fs
+        .read(Path::new(
+            "/dst/   2010-03-14 11;22;33 small-with-exif.jpg.webp",
+        ))
+        .unwrap();
+    assert_eq!(
+        ::image::ImageFormat::WebP,
+        ::image::guess_format(&converted).unwrap()
+    );
+}
+
+#[test]
+fn test_in_memory_map_directory_continues_after_permission_denied() {
+    let fs = in_memory_src_structure();
+    map_directory_in_memory(&fs, &settings());
+    fs.add_file(Path::new("/dst/dir1/stale.jpg.jpg"), b"stale");
+    fs.add_file(Path::new("/dst/dir1/other.txt"), b"other");
+    fs.fail(
+        Operation::Remove,
+        Path::new("/dst/dir1/stale.jpg.jpg"),
+        io::ErrorKind::PermissionDenied,
+    );
+
+    let summary = map_directory_in_memory(&fs, &settings());
+
+    assert_eq!(
+        Summary {
+            created_files: 0,
+            deleted_entries: 1,
+            failed_entries: 1,
+            duplicate_files: 0,
+            saved_bytes: 0,
+            chosen_qualities: vec![]
+        },
+        summary
+    );
+    assert!(fs.exists(Path::new("/dst/dir1/stale.jpg.jpg")));
+    assert!(!fs.exists(Path::new("/dst/dir1/other.txt")));
+}
+
+#[test]
+fn test_in_memory_map_directory_continues_after_disk_full() {
+    let fs = in_memory_src_structure();
+    fs.fail(
+        Operation::Write,
+        Path::new("/dst/video.m4v"),
+        io::ErrorKind::Other,
+    );
+
+    let summary = map_directory_in_memory(&fs, &settings());
+
+    assert_eq!(
+        Summary {
+            created_files: 2,
+            deleted_entries: 0,
+            failed_entries: 1,
+            duplicate_files: 0,
+            saved_bytes: 0,
+            chosen_qualities: vec![]
+        },
+        summary
+    );
+    assert!(!fs.exists(Path::new("/dst/video.m4v")));
+}
+
+#[test]
+fn test_in_memory_map_directory_source_file_vanishes() {
+    let fs = in_memory_src_structure();
+    fs.fail(
+        Operation::Read,
+        Path::new("/src/dir1/image.jpg"),
+        io::ErrorKind::NotFound,
+    );
+
+    let summary = map_directory_in_memory(&fs, &settings());
+
+    assert_eq!(
+        Summary {
+            created_files: 2,
+            deleted_entries: 0,
+            failed_entries: 1,
+            duplicate_files: 0,
+            saved_bytes: 0,
+            chosen_qualities: vec![]
+        },
+        summary
+    );
+    assert!(fs.is_dir(Path::new("/dst/dir1")));
+    assert!(!fs.exists(Path::new("/dst/dir1/image.jpg.jpg")));
+}
+
+#[test]
+fn test_in_memory_map_directory_source_dir_unreadable() {
+    let fs = in_memory_src_structure();
+    fs.fail(
+        Operation::ReadDir,
+        Path::new("/src/dir1"),
+        io::ErrorKind::PermissionDenied,
+    );
+
+    let summary = map_directory_in_memory(&fs, &settings());
+
+    assert_eq!(
+        Summary {
+            created_files: 2,
+            deleted_entries: 0,
+            failed_entries: 1,
+            duplicate_files: 0,
+            saved_bytes: 0,
+            chosen_qualities: vec![]
+        },
+        summary
+    );
+    assert!(fs.exists(Path::new("/dst/image.png.jpg")));
+}
+
+#[test]
+fn test_in_memory_map_directory_destination_dir_not_creatable() {
+    let fs = in_memory_src_structure();
+    fs.fail(
+        Operation::CreateDir,
+        Path::new("/dst/dir1"),
+        io::ErrorKind::PermissionDenied,
+    );
+
+    let summary = map_directory_in_memory(&fs, &settings());
+
+    assert_eq!(
+        Summary {
+            created_files: 2,
+            deleted_entries: 0,
+            failed_entries: 1,
+            duplicate_files: 0,
+            saved_bytes: 0,
+            chosen_qualities: vec![]
+        },
+        summary
+    );
+    assert!(!fs.exists(Path::new("/dst/dir1")));
+}
+
+#[test]
+fn test_in_memory_destination_locked() {
+    let fs = in_memory_src_structure();
+    fs.hold_lock(&Path::new("/dst").join(file_names::LOCK_FILE_NAME));
+
+    let result = mapper::map_directory_with_handlers(
+        Path::new("/src"),
+        Path::new("/dst"),
+        settings(),
+        registry(no_convert_image, true),
+        &fs,
+        &|_| {},
+    );
+
+    assert_eq!(Err(MapperError::DstLocked), result);
+    assert_eq!(
+        fs.read_dir(Path::new("/dst")).unwrap(),
+        Vec::<PathBuf>::new()
+    );
+}
+
+#[test]
+fn test_in_memory_source_that_cannot_be_resolved() {
+    let fs = in_memory_src_structure();
+    fs.fail(
+        Operation::Canonicalize,
+        Path::new("/src"),
+        io::ErrorKind::PermissionDenied,
+    );
+
+    let result = mapper::map_directory_with_handlers(
+        Path::new("/src"),
+        Path::new("/dst"),
+        settings(),
+        registry(no_convert_image, true),
+        &fs,
+        &|_| {},
+    );
+
+    assert_eq!(Err(MapperError::SrcDoesNotExist), result);
+}
+
+#[test]
+fn test_in_memory_destination_locked_before_it_is_checked() {
+    // The other instance might be halfway through writing it
+    let fs = in_memory_src_structure();
+    fs.add_file(Path::new("/dst/image.png.jpg"), b"image");
+    fs.hold_lock(&Path::new("/dst").join(file_names::LOCK_FILE_NAME));
+
+    let result = mapper::map_directory_with_handlers(
+        Path::new("/src"),
+        Path::new("/dst"),
+        settings(),
+        registry(no_convert_image, true),
+        &fs,
+        &|_| {},
+    );
+
+    assert_eq!(Err(MapperError::DstLocked), result);
+}
+
 // -----------------------------------------------------------------------------
 // Helpers
 // -----------------------------------------------------------------------------
@@ -714,8 +1882,8 @@ fn check_that_dst_structure_is_correct(dst_path: &Path, videos: bool) {
     assert_dir_entries(&exp_dir_entries, dst_path);
 }
 
-fn assert_dir_entries(exp_dir_entries: &Vec<&str>, path: &Path) {
-    let mut exp_dir_entries = exp_dir_entries.clone();
+fn assert_dir_entries(exp_dir_entries: &[&str], path: &Path) {
+    let mut exp_dir_entries = exp_dir_entries.to_vec();
     exp_dir_entries.sort();
 
     let mut dir_entries = get_dir_entries(path);
@@ -755,32 +1923,186 @@ fn tempdir() -> TempDir {
     tempfile::tempdir().unwrap()
 }
 
-const SETTINGS: Settings = Settings {
-    image_quality: ImageQuality::Mobile,
-    verbose_print: false,
-    include_videos: true,
-};
+fn settings() -> Settings {
+    Settings::builder(ImageQuality::Mobile)
+        .include_videos(true)
+        .build()
+}
 
-fn map_directory_ok(src_path: &Path, dst_path: &Path, include_videos: bool) {
-    let settings = Settings {
-        image_quality: ImageQuality::Mobile,
-        verbose_print: false,
-        include_videos,
-    };
-    mapper::map_directory_custom_opts(
+fn map_directory_ok(
+    src_path: &Path,
+    dst_path: &Path,
+    include_videos: bool,
+) -> Summary {
+    let settings = Settings::builder(ImageQuality::Mobile)
+        .include_videos(include_videos)
+        .build();
+    mapper::map_directory_with_handlers(
         src_path,
         dst_path,
         settings,
-        no_convert_image,
+        registry(no_convert_image, include_videos),
+        &RealFileSystem,
+        &|_| {},
     )
-    .unwrap();
+    .unwrap()
+}
+
+fn in_memory_src_structure() -> InMemoryFileSystem {
+    let fs = InMemoryFileSystem::new();
+    fs.add_file(Path::new("/src/dir1/image.jpg"), b"image");
+    fs.add_dir(Path::new("/src/dir2"));
+    fs.add_file(Path::new("/src/image.png"), b"image");
+    fs.add_file(Path::new("/src/text_file.txt"), b"text");
+    fs.add_file(Path::new("/src/video.m4v"), b"video");
+    fs.add_dir(Path::new("/dst"));
+    fs
+}
+
+fn in_memory_dated_src_structure() -> InMemoryFileSystem {
+    let fs = InMemoryFileSystem::new();
+    fs.add_file(
+        Path::new("/src/dir1/image.jpg"),
+        &fs::read("test_resources/small-with-exif.jpg").unwrap(),
+    );
+    fs.add_dir(Path::new("/src/dir2"));
+    fs.add_file(Path::new("/src/image.png"), b"image");
+    fs.add_file(Path::new("/src/text_file.txt"), b"text");
+    fs.add_file(Path::new("/src/video.m4v"), b"video");
+    fs.add_dir(Path::new("/dst"));
+    fs
+}
+
+// Checks the destination entries, apart from the marker and the lock
+fn assert_in_memory_dst_paths(fs: &InMemoryFileSystem, exp_paths: &[&str]) {
+    let exp_paths: Vec<PathBuf> = exp_paths.iter().map(PathBuf::from).collect();
+    let paths: Vec<PathBuf> = fs
+        .paths()
+        .into_iter()
+        .filter(|path| path.starts_with("/dst") && path != Path::new("/dst"))
+        .filter(|path| !mapper::is_reserved_destination_path(path))
+        .collect();
+    assert_eq!(exp_paths, paths);
+}
+
+// Two copies of the same image, and an image and a video of the same size
+fn in_memory_duplicates_src_structure() -> InMemoryFileSystem {
+    let fs = InMemoryFileSystem::new();
+    fs.add_file(Path::new("/src/event/photo.jpg"), b"photo");
+    fs.add_file(Path::new("/src/best of/copy.jpg"), b"photo");
+    fs.add_file(Path::new("/src/best of/copy 2.jpg"), b"photo");
+    fs.add_file(Path::new("/src/event/other.jpg"), b"other");
+    fs.add_file(Path::new("/src/event/video.m4v"), b"photo");
+    fs.add_dir(Path::new("/dst"));
+    fs
+}
+
+fn assert_in_memory_dst_structure_is_correct(fs: &InMemoryFileSystem) {
+    let exp_paths: Vec<PathBuf> = [
+        "/dst",
+        "/dst/.image_mapper.json",
+        "/dst/.image_mapper.lock",
+        "/dst/dir1",
+        "/dst/dir1/image.jpg.jpg",
+        "/dst/dir2",
+        "/dst/image.png.jpg",
+        "/dst/video.m4v",
+    ]
+    .iter()
+    .map(PathBuf::from)
+    .collect();
+    let paths: Vec<PathBuf> = fs
+        .paths()
+        .into_iter()
+        .filter(|path| path.starts_with("/dst"))
+        .collect();
+    assert_eq!(exp_paths, paths);
+}
+
+fn map_directory_in_memory(
+    fs: &InMemoryFileSystem,
+    settings: &Settings,
+) -> Summary {
+    mapper::map_directory_with_handlers(
+        Path::new("/src"),
+        Path::new("/dst"),
+        settings.clone(),
+        registry(no_convert_image, true),
+        fs,
+        &|_| {},
+    )
+    .unwrap()
+}
+
+fn registry(
+    image_converter: ImageConverter,
+    include_videos: bool,
+) -> HandlerRegistry {
+    let mut registry = HandlerRegistry::new();
+    registry.register(Box::new(ImageHandler::with_converter(image_converter)));
+    if include_videos {
+        registry.register(Box::new(VideoHandler));
+    }
+    registry
 }
 
 pub fn no_convert_image(
     source_path: &Path,
     destination_path: &Path,
+    settings: &Settings,
+    fs: &dyn FileSystem,
+) -> Result<u8, ImageError> {
+    fs.copy(source_path, destination_path).map_err(|e| {
+        ImageError::Open(
+            source_path.to_path_buf(),
+            ::image::ImageError::IoError(e),
+        )
+    })?;
+    Ok(settings.image_quality.preset().quality)
+}
+
+fn failing_convert_image(
+    source_path: &Path,
+    _destination_path: &Path,
     _settings: &Settings,
-) -> bool {
-    fs::copy(source_path, destination_path).unwrap();
-    true
+    _fs: &dyn FileSystem,
+) -> Result<u8, ImageError> {
+    Err(ImageError::UnsupportedOrientation(
+        source_path.to_path_buf(),
+        9,
+    ))
+}
+
+// Keeps .txt files, with .txt appended to their names
+struct TextHandler;
+
+impl MediaHandler for TextHandler {
+    fn handles_source(&self, source_path: &Path) -> bool {
+        source_path.extension() == Some(OsStr::new("txt"))
+    }
+
+    fn destination_name(
+        &self,
+        source_path: &Path,
+        _fs: &dyn FileSystem,
+    ) -> String {
+        format!("{}.txt", source_path.file_name().unwrap().to_str().unwrap())
+    }
+
+    fn convert(
+        &self,
+        _source_path: &Path,
+        destination_path: &Path,
+        _settings: &Settings,
+        fs: &dyn FileSystem,
+    ) -> Result<Conversion, Box<dyn Error>> {
+        fs.write(destination_path, b"copied")?;
+        Ok(Conversion::default())
+    }
+
+    fn source_name(&self, destination_name: &str) -> Option<String> {
+        destination_name
+            .strip_suffix(".txt")
+            .map(|name| name.to_string())
+    }
 }
@@ -0,0 +1,89 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::file_names;
+use crate::file_system::FileSystem;
+use crate::settings::Settings;
+
+/// Written to the root of a destination, so that later runs can tell that
+/// the directory is a destination, and which source it was mapped from.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Marker {
+    /// The canonical path of the source directory
+    pub source: PathBuf,
+    /// The settings of the latest run
+    pub settings: Settings,
+}
+
+impl Marker {
+    pub fn new(source_path: &Path, settings: &Settings) -> Marker {
+        Marker {
+            source: source_path.to_path_buf(),
+            settings: Settings {
+                adopt: false,
+                ..settings.clone()
+            },
+        }
+    }
+}
+
+pub fn marker_path(destination_path: &Path) -> PathBuf {
+    destination_path.join(file_names::MARKER_FILE_NAME)
+}
+
+/// The marker of the destination, or None if it has none or it can't be
+/// parsed
+pub fn read_marker(
+    destination_path: &Path,
+    fs: &dyn FileSystem,
+) -> Option<Marker> {
+    let contents = fs.read(&marker_path(destination_path)).ok()?;
+    serde_json::from_slice(&contents).ok()
+}
+
+pub fn write_marker(
+    destination_path: &Path,
+    marker: &Marker,
+    fs: &dyn FileSystem,
+) -> io::Result<()> {
+    let contents = serde_json::to_vec_pretty(marker)?;
+    fs.write(&marker_path(destination_path), &contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::InMemoryFileSystem;
+    use crate::settings::ImageQuality;
+
+    #[test]
+    fn marker_can_be_read_back() {
+        let fs = InMemoryFileSystem::new();
+        fs.add_dir(Path::new("/dst"));
+        let settings = Settings::builder(ImageQuality::Television)
+            .include_videos(true)
+            .adopt(true)
+            .build();
+        let marker = Marker::new(Path::new("/src"), &settings);
+
+        write_marker(Path::new("/dst"), &marker, &fs).unwrap();
+
+        let read = read_marker(Path::new("/dst"), &fs).unwrap();
+        assert_eq!(read, marker);
+        assert_eq!(read.source, PathBuf::from("/src"));
+        assert!(read.settings.include_videos);
+        assert!(!read.settings.adopt);
+    }
+
+    #[test]
+    fn missing_or_broken_marker_is_none() {
+        let fs = InMemoryFileSystem::new();
+        fs.add_dir(Path::new("/dst"));
+        assert_eq!(read_marker(Path::new("/dst"), &fs), None);
+
+        fs.add_file(&marker_path(Path::new("/dst")), b"{ not json");
+        assert_eq!(read_marker(Path::new("/dst"), &fs), None);
+    }
+}
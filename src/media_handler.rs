@@ -0,0 +1,377 @@
+use std::error::Error;
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+use unwrap::unwrap;
+
+use crate::file_names;
+use crate::file_system::FileSystem;
+use crate::image::{self, ImageError};
+use crate::index::FileEntry;
+use crate::settings::{OutputFormat, Settings};
+use crate::verifier::VerifySettings;
+
+/// Decides which source files a kind of media applies to, and how they are
+/// turned into destination files.
+pub trait MediaHandler {
+    /// Whether the source file at `source_path` should be handled by this
+    /// handler
+    fn handles_source(&self, source_path: &Path) -> bool;
+
+    /// The file name that the source file gets in the destination
+    fn destination_name(
+        &self,
+        source_path: &Path,
+        fs: &dyn FileSystem,
+    ) -> String;
+
+    /// Creates the destination file from the source file. Nothing should be
+    /// left at `destination_path` on failure.
+    fn convert(
+        &self,
+        source_path: &Path,
+        destination_path: &Path,
+        settings: &Settings,
+        fs: &dyn FileSystem,
+    ) -> Result<Conversion, Box<dyn Error>>;
+
+    /// If `destination_name` looks like one of this handler's outputs, the
+    /// name of the source file it was created from
+    fn source_name(&self, destination_name: &str) -> Option<String>;
+
+    /// Whether `destination_name`, one of this handler's outputs, is still
+    /// named the way this handler names them, without reading the source
+    /// file. An output in another format isn't, for example. True by
+    /// default.
+    fn is_current_name(&self, _destination_name: &str) -> bool {
+        true
+    }
+
+    /// Checks that the destination file is intact and matches the source
+    /// file it was created from, and if not, returns what's wrong with it.
+    /// Checks nothing by default.
+    fn verify(
+        &self,
+        _source_path: &Path,
+        _destination_path: &Path,
+        _settings: &VerifySettings,
+        _fs: &dyn FileSystem,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// When the source file was captured, as "yyyy-mm-dd hh:mm:ss". None by
+    /// default.
+    fn capture_time(
+        &self,
+        _source_path: &Path,
+        _fs: &dyn FileSystem,
+    ) -> Option<String> {
+        None
+    }
+
+    /// What the index tells about the destination file. By default only
+    /// its name, source name and size.
+    fn index_entry(
+        &self,
+        source_path: &Path,
+        destination_path: &Path,
+        fs: &dyn FileSystem,
+    ) -> io::Result<FileEntry> {
+        FileEntry::new(source_path, destination_path, fs)
+    }
+}
+
+/// The handlers that the mapper consults, in order. The first handler that
+/// accepts a file is the one that's used.
+#[derive(Default)]
+pub struct HandlerRegistry {
+    handlers: Vec<Box<dyn MediaHandler>>,
+}
+
+impl HandlerRegistry {
+    pub fn new() -> HandlerRegistry {
+        HandlerRegistry::default()
+    }
+
+    /// Images in the format of `settings.image_quality`, and videos if
+    /// `settings.include_videos` is set
+    pub fn with_defaults(settings: &Settings) -> HandlerRegistry {
+        let format = settings.image_quality.preset().format;
+        let mut registry = HandlerRegistry::new();
+        registry.register(Box::new(ImageHandler::new().format(format)));
+        if settings.include_videos {
+            registry.register(Box::new(VideoHandler));
+        }
+        registry
+    }
+
+    pub fn register(&mut self, handler: Box<dyn MediaHandler>) {
+        self.handlers.push(handler);
+    }
+
+    pub fn handler_for_source(
+        &self,
+        source_path: &Path,
+    ) -> Option<&dyn MediaHandler> {
+        self.handlers
+            .iter()
+            .map(|handler| handler.as_ref())
+            .find(|handler| handler.handles_source(source_path))
+    }
+
+    /// The name of the source file that `destination_name` was created from,
+    /// if any handler recognises it as an output
+    pub fn source_name(&self, destination_name: &str) -> Option<String> {
+        self.handlers
+            .iter()
+            .find_map(|handler| handler.source_name(destination_name))
+    }
+}
+
+/// What [`MediaHandler::convert`] tells about a destination file it created
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Conversion {
+    /// The quality that an image was compressed with, if it was chosen for
+    /// the image by a target size or SSIM
+    pub quality: Option<u8>,
+}
+
+/// Returns the quality that the image was compressed with
+pub type ImageConverter =
+    fn(&Path, &Path, &Settings, &dyn FileSystem) -> Result<u8, ImageError>;
+
+/// Downscales and compresses images, and prepends their exif date/time to
+/// their names
+pub struct ImageHandler {
+    converter: ImageConverter,
+    format: OutputFormat,
+}
+
+impl ImageHandler {
+    /// Names the destination images as JPEGs
+    pub fn new() -> ImageHandler {
+        ImageHandler::with_converter(image::open_compress_and_save_image_in)
+    }
+
+    /// Names files like images normally are, but converts them with
+    /// `converter`
+    pub fn with_converter(converter: ImageConverter) -> ImageHandler {
+        ImageHandler {
+            converter,
+            format: OutputFormat::default(),
+        }
+    }
+
+    /// Names the destination images with the extension of `format`. It
+    /// should be the format of the settings that images are converted with.
+    pub fn format(mut self, format: OutputFormat) -> ImageHandler {
+        self.format = format;
+        self
+    }
+}
+
+impl Default for ImageHandler {
+    fn default() -> ImageHandler {
+        ImageHandler::new()
+    }
+}
+
+impl MediaHandler for ImageHandler {
+    fn handles_source(&self, source_path: &Path) -> bool {
+        source_path
+            .extension()
+            .map(file_names::extension_is_image_extension)
+            .unwrap_or(false)
+    }
+
+    fn destination_name(
+        &self,
+        source_path: &Path,
+        fs: &dyn FileSystem,
+    ) -> String {
+        file_names::destination_image_name_from_image_path_in(
+            source_path,
+            self.format,
+            fs,
+        )
+    }
+
+    fn convert(
+        &self,
+        source_path: &Path,
+        destination_path: &Path,
+        settings: &Settings,
+        fs: &dyn FileSystem,
+    ) -> Result<Conversion, Box<dyn Error>> {
+        let quality =
+            (self.converter)(source_path, destination_path, settings, fs)?;
+        let preset = settings.image_quality.preset();
+        let is_chosen =
+            preset.target_size.is_some() || preset.target_ssim.is_some();
+        Ok(Conversion {
+            quality: Some(quality).filter(|_| is_chosen),
+        })
+    }
+
+    fn source_name(&self, destination_name: &str) -> Option<String> {
+        let extension = Path::new(destination_name).extension()?;
+        if file_names::extension_is_destination_image_extension(extension) {
+            file_names::destination_image_name_to_source_image_name(
+                destination_name,
+            )
+        } else {
+            None
+        }
+    }
+
+    fn is_current_name(&self, destination_name: &str) -> bool {
+        Path::new(destination_name).extension()
+            == Some(OsStr::new(self.format.extension()))
+    }
+
+    fn verify(
+        &self,
+        _source_path: &Path,
+        destination_path: &Path,
+        _settings: &VerifySettings,
+        fs: &dyn FileSystem,
+    ) -> Result<(), String> {
+        image::verify_image(destination_path, fs)
+    }
+
+    fn capture_time(
+        &self,
+        source_path: &Path,
+        fs: &dyn FileSystem,
+    ) -> Option<String> {
+        image::read_exif_summary(source_path, fs).capture_time
+    }
+
+    fn index_entry(
+        &self,
+        source_path: &Path,
+        destination_path: &Path,
+        fs: &dyn FileSystem,
+    ) -> io::Result<FileEntry> {
+        let exif = image::read_exif_summary(source_path, fs);
+        let dimensions = image::image_dimensions(destination_path, fs).ok();
+
+        Ok(FileEntry {
+            capture_time: exif.capture_time,
+            width: dimensions.map(|(width, _)| width),
+            height: dimensions.map(|(_, height)| height),
+            orientation: exif.orientation,
+            camera_model: exif.camera_model,
+            ..FileEntry::new(source_path, destination_path, fs)?
+        })
+    }
+}
+
+/// Copies videos as-is
+pub struct VideoHandler;
+
+impl MediaHandler for VideoHandler {
+    fn handles_source(&self, source_path: &Path) -> bool {
+        source_path
+            .extension()
+            .map(file_names::extension_is_video_extension)
+            .unwrap_or(false)
+    }
+
+    fn destination_name(
+        &self,
+        source_path: &Path,
+        _fs: &dyn FileSystem,
+    ) -> String {
+        let file_name = unwrap!(
+            source_path.file_name(),
+            "Could not get the file name of a video \"{}\"",
+            source_path.display()
+        );
+        file_name.to_string_lossy().into_owned()
+    }
+
+    fn convert(
+        &self,
+        source_path: &Path,
+        destination_path: &Path,
+        _settings: &Settings,
+        fs: &dyn FileSystem,
+    ) -> Result<Conversion, Box<dyn Error>> {
+        if let Err(e) = fs.copy(source_path, destination_path) {
+            let _ = fs.remove_file(destination_path);
+            return Err(format!(
+                "Could not copy a video \"{}\" to \"{}\" due to \"{}\"",
+                source_path.display(),
+                destination_path.display(),
+                e
+            )
+            .into());
+        }
+        Ok(Conversion::default())
+    }
+
+    fn source_name(&self, destination_name: &str) -> Option<String> {
+        let extension = Path::new(destination_name).extension()?;
+        if file_names::extension_is_video_extension(extension) {
+            Some(destination_name.to_string())
+        } else {
+            None
+        }
+    }
+
+    fn verify(
+        &self,
+        source_path: &Path,
+        destination_path: &Path,
+        settings: &VerifySettings,
+        fs: &dyn FileSystem,
+    ) -> Result<(), String> {
+        let to_message = |e: io::Error| {
+            format!(
+                "Could not compare the video \"{}\" with \"{}\" due to \"{}\"",
+                destination_path.display(),
+                source_path.display(),
+                e
+            )
+        };
+
+        let source_size = fs.file_size(source_path).map_err(to_message)?;
+        let destination_size =
+            fs.file_size(destination_path).map_err(to_message)?;
+        if source_size != destination_size {
+            return Err(format!(
+                "The video \"{}\" has {} bytes, but \"{}\" has {}",
+                destination_path.display(),
+                destination_size,
+                source_path.display(),
+                source_size
+            ));
+        }
+
+        if settings.compare_hashes
+            && hash_file(source_path, fs).map_err(to_message)?
+                != hash_file(destination_path, fs).map_err(to_message)?
+        {
+            return Err(format!(
+                "The video \"{}\" differs from \"{}\"",
+                destination_path.display(),
+                source_path.display()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn hash_file(
+    path: &Path,
+    fs: &dyn FileSystem,
+) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs.open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
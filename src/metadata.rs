@@ -0,0 +1,438 @@
+//! Copies EXIF metadata and ICC colour profiles from source images to the
+//! destination images, which the encoders otherwise write without any.
+
+use std::io::{BufReader, Cursor};
+
+use image::codecs::jpeg::JpegDecoder;
+use image::codecs::png::PngDecoder;
+use image::{ImageDecoder, ImageFormat};
+
+use exif::experimental::Writer;
+use exif::{Context, Field, In, Reader, Tag, Value};
+
+use crate::settings::{ExifGroup, Settings};
+
+/// The EXIF fields of `source_contents` that `settings` keeps, encoded as
+/// the TIFF structure that images embed, or None if there are none. The
+/// orientation is reset, since the destination image is already rotated.
+pub fn exif_to_keep(
+    source_contents: &[u8],
+    settings: &Settings,
+) -> Option<Vec<u8>> {
+    if settings.keep_exif.is_empty() {
+        return None;
+    }
+
+    let exif = Reader::new()
+        .read_from_container(&mut BufReader::new(Cursor::new(source_contents)))
+        .ok()?;
+    let kept: Vec<&Field> = exif
+        .fields()
+        .filter(|field| {
+            field.ifd_num == In::PRIMARY && is_kept(field.tag, settings)
+        })
+        .collect();
+    if kept.is_empty() {
+        return None;
+    }
+
+    let orientation = Field {
+        tag: Tag::Orientation,
+        ifd_num: In::PRIMARY,
+        value: Value::Short(vec![1]),
+    };
+    let mut writer = Writer::new();
+    writer.push_field(&orientation);
+    for field in kept {
+        writer.push_field(field);
+    }
+
+    let mut encoded = Cursor::new(Vec::new());
+    writer.write(&mut encoded, exif.little_endian()).ok()?;
+    Some(encoded.into_inner())
+}
+
+fn is_kept(tag: Tag, settings: &Settings) -> bool {
+    let group = match exif_group(tag) {
+        Some(group) => group,
+        None => return false,
+    };
+    if group == ExifGroup::Gps && settings.strip_gps {
+        return false;
+    }
+    settings.keep_exif.contains(&group)
+}
+
+fn exif_group(tag: Tag) -> Option<ExifGroup> {
+    if tag.context() == Context::Gps {
+        return Some(ExifGroup::Gps);
+    }
+
+    match tag {
+        Tag::DateTime
+        | Tag::DateTimeOriginal
+        | Tag::DateTimeDigitized
+        | Tag::OffsetTime
+        | Tag::OffsetTimeOriginal
+        | Tag::OffsetTimeDigitized
+        | Tag::SubSecTime
+        | Tag::SubSecTimeOriginal
+        | Tag::SubSecTimeDigitized => Some(ExifGroup::Date),
+        Tag::Make | Tag::Model | Tag::LensMake | Tag::LensModel => {
+            Some(ExifGroup::Camera)
+        }
+        Tag::ExposureTime
+        | Tag::FNumber
+        | Tag::ExposureProgram
+        | Tag::PhotographicSensitivity
+        | Tag::ShutterSpeedValue
+        | Tag::ApertureValue
+        | Tag::ExposureBiasValue
+        | Tag::MaxApertureValue
+        | Tag::MeteringMode
+        | Tag::Flash
+        | Tag::FocalLength
+        | Tag::FocalLengthIn35mmFilm
+        | Tag::ExposureMode
+        | Tag::WhiteBalance => Some(ExifGroup::Exposure),
+        _ => None,
+    }
+}
+
+/// The ICC colour profile embedded in the JPEG or PNG `contents`, if it's
+/// an RGB one. The decoded pixels are always RGB or grayscale, so CMYK
+/// profiles don't apply to them, and grayscale ones hardly change anything.
+pub fn icc_profile(contents: &[u8]) -> Option<Vec<u8>> {
+    let icc = match image::guess_format(contents).ok()? {
+        ImageFormat::Jpeg => {
+            JpegDecoder::new(Cursor::new(contents)).ok()?.icc_profile()
+        }
+        ImageFormat::Png => {
+            PngDecoder::new(Cursor::new(contents)).ok()?.icc_profile()
+        }
+        _ => None,
+    }?;
+    // The colour space of the profile is in its header
+    if icc.get(16..20) == Some(b"RGB ") {
+        Some(icc)
+    } else {
+        None
+    }
+}
+
+// A segment can't be longer than its 16 bit length field allows
+const MAX_SEGMENT_PAYLOAD: usize = u16::MAX as usize - 2;
+
+const ICC_SEGMENT_SIGNATURE: &[u8] = b"ICC_PROFILE\0";
+
+// Leaves room for the signature, the sequence number and the count
+const MAX_ICC_CHUNK: usize =
+    MAX_SEGMENT_PAYLOAD - ICC_SEGMENT_SIGNATURE.len() - 2;
+
+/// `jpeg` with `icc` in APP2 segments right after the start of the image,
+/// or after the JFIF segment if there is one. Profiles larger than a segment
+/// are split over several.
+pub fn embed_icc_in_jpeg(jpeg: &[u8], icc: &[u8]) -> Vec<u8> {
+    let chunks: Vec<&[u8]> = icc.chunks(MAX_ICC_CHUNK).collect();
+    if chunks.is_empty() || chunks.len() > u8::MAX as usize {
+        return jpeg.to_vec();
+    }
+
+    let segments = chunks.iter().enumerate().map(|(index, chunk)| {
+        let mut payload = ICC_SEGMENT_SIGNATURE.to_vec();
+        payload.push(index as u8 + 1);
+        payload.push(chunks.len() as u8);
+        payload.extend_from_slice(chunk);
+        (0xE2, payload)
+    });
+    insert_jpeg_segments(jpeg, segments.collect())
+}
+
+/// `jpeg` with `exif` in an APP1 segment right after the start of the image,
+/// or after the JFIF segment if there is one
+pub fn embed_exif_in_jpeg(jpeg: &[u8], exif: &[u8]) -> Vec<u8> {
+    let mut payload = b"Exif\0\0".to_vec();
+    payload.extend_from_slice(exif);
+    insert_jpeg_segments(jpeg, vec![(0xE1, payload)])
+}
+
+// Inserts the segments, given as marker and payload, right after the start
+// of the image, or after the JFIF segment if there is one
+fn insert_jpeg_segments(jpeg: &[u8], segments: Vec<(u8, Vec<u8>)>) -> Vec<u8> {
+    let too_large = segments
+        .iter()
+        .any(|(_, payload)| payload.len() > MAX_SEGMENT_PAYLOAD);
+    if !jpeg.starts_with(&[0xFF, 0xD8]) || too_large {
+        return jpeg.to_vec();
+    }
+
+    let mut position = 2;
+    if jpeg.get(2..4) == Some(&[0xFF, 0xE0]) {
+        if let Some(length) = jpeg.get(4..6) {
+            position += 2 + u16::from_be_bytes([length[0], length[1]]) as usize;
+        }
+    }
+    let position = position.min(jpeg.len());
+
+    let mut embedded = jpeg[..position].to_vec();
+    for (marker, payload) in segments {
+        embedded.extend_from_slice(&[0xFF, marker]);
+        embedded.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        embedded.extend_from_slice(&payload);
+    }
+    embedded.extend_from_slice(&jpeg[position..]);
+    embedded
+}
+
+// In the VP8X chunk, which WebP files need for anything but the image itself
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_ICC_FLAG: u8 = 0x20;
+
+// The RIFF header and the VP8X chunk
+const WEBP_EXTENDED_HEADER: usize = 30;
+
+/// `webp` with `exif` in an EXIF chunk, which requires the extended format.
+/// `width` and `height` are the size of the image.
+pub fn embed_exif_in_webp(
+    webp: &[u8],
+    exif: &[u8],
+    width: u32,
+    height: u32,
+) -> Vec<u8> {
+    let mut embedded = match extend_webp(webp, WEBP_EXIF_FLAG, width, height) {
+        Some(extended) => extended,
+        None => return webp.to_vec(),
+    };
+    // The EXIF chunk goes after the image
+    append_webp_chunk(&mut embedded, b"EXIF", exif);
+    fix_riff_size(&mut embedded);
+    embedded
+}
+
+/// `webp` with `icc` in an ICCP chunk, which requires the extended format.
+/// `width` and `height` are the size of the image.
+pub fn embed_icc_in_webp(
+    webp: &[u8],
+    icc: &[u8],
+    width: u32,
+    height: u32,
+) -> Vec<u8> {
+    let extended = match extend_webp(webp, WEBP_ICC_FLAG, width, height) {
+        Some(extended) => extended,
+        None => return webp.to_vec(),
+    };
+    // The ICCP chunk goes before the image
+    let mut embedded = extended[..WEBP_EXTENDED_HEADER].to_vec();
+    append_webp_chunk(&mut embedded, b"ICCP", icc);
+    embedded.extend_from_slice(&extended[WEBP_EXTENDED_HEADER..]);
+    fix_riff_size(&mut embedded);
+    embedded
+}
+
+// `webp` in the extended format, with `flag` set in its VP8X chunk, or None
+// if it isn't a WebP file
+fn extend_webp(
+    webp: &[u8],
+    flag: u8,
+    width: u32,
+    height: u32,
+) -> Option<Vec<u8>> {
+    if webp.get(..4) != Some(b"RIFF") || webp.get(8..12) != Some(b"WEBP") {
+        return None;
+    }
+
+    let mut extended = webp[..12].to_vec();
+    if webp.get(12..16) == Some(b"VP8X") {
+        extended.extend_from_slice(&webp[12..]);
+        *extended.get_mut(20)? |= flag;
+    } else {
+        // A simple file has no transparency, or it would be extended already
+        extended.extend_from_slice(b"VP8X");
+        extended.extend_from_slice(&10u32.to_le_bytes());
+        extended.extend_from_slice(&[flag, 0, 0, 0]);
+        extended.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        extended.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        extended.extend_from_slice(&webp[12..]);
+    }
+    Some(extended)
+}
+
+fn append_webp_chunk(webp: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    webp.extend_from_slice(id);
+    webp.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    webp.extend_from_slice(data);
+    // Chunks are padded to an even size
+    if data.len() % 2 == 1 {
+        webp.push(0);
+    }
+}
+
+fn fix_riff_size(webp: &mut [u8]) {
+    let riff_size = (webp.len() - 8) as u32;
+    webp[4..8].copy_from_slice(&riff_size.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::ImageQuality;
+
+    #[test]
+    fn only_kept_groups_are_copied() {
+        let source = jpeg_with_exif();
+        let settings = Settings::builder(ImageQuality::Mobile)
+            .keep_exif(&[ExifGroup::Date, ExifGroup::Gps])
+            .build();
+
+        let exif = exif_to_keep(&source, &settings).unwrap();
+
+        let output = read_exif(&embed_exif_in_jpeg(&minimal_jpeg(), &exif));
+        assert!(output
+            .get_field(Tag::DateTimeOriginal, In::PRIMARY)
+            .is_some());
+        assert!(output.get_field(Tag::GPSLatitude, In::PRIMARY).is_some());
+        assert!(output.get_field(Tag::Model, In::PRIMARY).is_none());
+        assert!(output.get_field(Tag::FNumber, In::PRIMARY).is_none());
+        assert_eq!(
+            Some(1),
+            output
+                .get_field(Tag::Orientation, In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+        );
+    }
+
+    #[test]
+    fn gps_is_stripped_in_privacy_mode() {
+        let source = jpeg_with_exif();
+        let settings = Settings::builder(ImageQuality::Mobile)
+            .keep_exif(&[ExifGroup::Camera, ExifGroup::Gps])
+            .strip_gps(true)
+            .build();
+
+        let exif = exif_to_keep(&source, &settings).unwrap();
+
+        let output = read_exif(&embed_exif_in_jpeg(&minimal_jpeg(), &exif));
+        assert!(output.get_field(Tag::Model, In::PRIMARY).is_some());
+        assert!(output.get_field(Tag::GPSLatitude, In::PRIMARY).is_none());
+    }
+
+    #[test]
+    fn all_but_gps_is_copied_by_default() {
+        let settings = Settings::builder(ImageQuality::Mobile).build();
+
+        let exif = exif_to_keep(&jpeg_with_exif(), &settings).unwrap();
+
+        let output = read_exif(&embed_exif_in_jpeg(&minimal_jpeg(), &exif));
+        assert!(output
+            .get_field(Tag::DateTimeOriginal, In::PRIMARY)
+            .is_some());
+        assert!(output.get_field(Tag::Model, In::PRIMARY).is_some());
+        assert!(output.get_field(Tag::FNumber, In::PRIMARY).is_some());
+        assert!(output.get_field(Tag::GPSLatitude, In::PRIMARY).is_none());
+    }
+
+    #[test]
+    fn nothing_is_copied_without_groups() {
+        let settings = Settings::builder(ImageQuality::Mobile)
+            .keep_exif(&[])
+            .build();
+
+        assert_eq!(None, exif_to_keep(&jpeg_with_exif(), &settings));
+    }
+
+    #[test]
+    fn exif_is_embedded_in_webp() {
+        let image = ::image::RgbImage::new(20, 10);
+        let webp = webp::Encoder::from_rgb(&image, 20, 10)
+            .encode(50.0)
+            .to_vec();
+        let settings = Settings::builder(ImageQuality::Mobile)
+            .keep_exif(&[ExifGroup::Camera])
+            .build();
+        let exif = exif_to_keep(&jpeg_with_exif(), &settings).unwrap();
+
+        let embedded = embed_exif_in_webp(&webp, &exif, 20, 10);
+
+        let decoded = ::image::load_from_memory(&embedded).unwrap();
+        assert_eq!((20, 10), (decoded.width(), decoded.height()));
+        let output = read_exif(&embedded);
+        assert!(output.get_field(Tag::Model, In::PRIMARY).is_some());
+    }
+
+    #[test]
+    fn large_icc_profile_is_split_over_segments() {
+        let mut icc = vec![0; 150_000];
+        icc[16..20].copy_from_slice(b"RGB ");
+        icc[1000] = 1;
+        icc[100_000] = 2;
+
+        let embedded = embed_icc_in_jpeg(&minimal_jpeg(), &icc);
+
+        assert_eq!(Some(icc), icc_profile(&embedded));
+        assert!(::image::load_from_memory(&embedded).is_ok());
+    }
+
+    #[test]
+    fn cmyk_icc_profile_is_ignored() {
+        let mut icc = vec![0; 1000];
+        icc[16..20].copy_from_slice(b"CMYK");
+
+        let embedded = embed_icc_in_jpeg(&minimal_jpeg(), &icc);
+
+        assert_eq!(None, icc_profile(&embedded));
+    }
+
+    fn jpeg_with_exif() -> Vec<u8> {
+        let ascii = |tag, value: &str| Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![value.as_bytes().to_vec()]),
+        };
+        let fields = vec![
+            ascii(Tag::DateTimeOriginal, "2010:03:14 11:22:33"),
+            ascii(Tag::Model, "Camera"),
+            ascii(Tag::GPSLatitudeRef, "N"),
+            Field {
+                tag: Tag::GPSLatitude,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![
+                    (59, 1).into(),
+                    (20, 1).into(),
+                    (0, 1).into(),
+                ]),
+            },
+            Field {
+                tag: Tag::FNumber,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![(28, 10).into()]),
+            },
+            Field {
+                tag: Tag::Orientation,
+                ifd_num: In::PRIMARY,
+                value: Value::Short(vec![6]),
+            },
+        ];
+        let mut writer = Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut exif = Cursor::new(Vec::new());
+        writer.write(&mut exif, false).unwrap();
+
+        embed_exif_in_jpeg(&minimal_jpeg(), exif.get_ref())
+    }
+
+    fn minimal_jpeg() -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        ::image::codecs::jpeg::JpegEncoder::new(&mut jpeg)
+            .encode(&[0; 3], 1, 1, ::image::ColorType::Rgb8)
+            .unwrap();
+        jpeg
+    }
+
+    fn read_exif(contents: &[u8]) -> exif::Exif {
+        Reader::new()
+            .read_from_container(&mut BufReader::new(Cursor::new(contents)))
+            .unwrap()
+    }
+}
@@ -0,0 +1,581 @@
+//! Reads camera RAW files. DNG, NEF, CR2 and ARW files are all TIFF files,
+//! whose image file directories hold the sensor data and usually a JPEG
+//! preview that the camera developed at full or nearly full size.
+
+use std::collections::HashSet;
+use std::convert::TryInto;
+
+use image::{Rgb, RgbImage};
+use jpeg_decoder::CodingProcess;
+
+// TIFF tags
+const IMAGE_WIDTH: u16 = 0x100;
+const IMAGE_LENGTH: u16 = 0x101;
+const BITS_PER_SAMPLE: u16 = 0x102;
+const COMPRESSION: u16 = 0x103;
+const PHOTOMETRIC_INTERPRETATION: u16 = 0x106;
+const STRIP_OFFSETS: u16 = 0x111;
+const SAMPLES_PER_PIXEL: u16 = 0x115;
+const STRIP_BYTE_COUNTS: u16 = 0x117;
+const SUB_IFDS: u16 = 0x14A;
+const JPEG_INTERCHANGE_FORMAT: u16 = 0x201;
+const JPEG_INTERCHANGE_FORMAT_LENGTH: u16 = 0x202;
+const CFA_REPEAT_PATTERN_DIM: u16 = 0x828D;
+const CFA_PATTERN: u16 = 0x828E;
+const BLACK_LEVEL: u16 = 0xC61A;
+const WHITE_LEVEL: u16 = 0xC61D;
+const AS_SHOT_NEUTRAL: u16 = 0xC628;
+
+const UNCOMPRESSED: u32 = 1;
+const OLD_JPEG: u32 = 6;
+const JPEG: u32 = 7;
+const COLOR_FILTER_ARRAY: u32 = 32803;
+
+// Files with more directories than this are malformed
+const MAX_IFDS: usize = 64;
+
+/// Whether `contents` start like a TIFF file, which all supported RAW
+/// formats are
+pub fn is_tiff(contents: &[u8]) -> bool {
+    contents.starts_with(b"II*\0") || contents.starts_with(b"MM\0*")
+}
+
+/// The largest JPEG preview embedded in the RAW file, if it has one that
+/// can be decoded. The sensor data of some formats is a lossless JPEG,
+/// which isn't a preview.
+pub fn embedded_preview(contents: &[u8]) -> Option<&[u8]> {
+    let tiff = Tiff::new(contents)?;
+    tiff.ifds()
+        .iter()
+        .filter_map(|ifd| tiff.jpeg_in(ifd))
+        .filter_map(|jpeg| {
+            let mut decoder = jpeg_decoder::Decoder::new(jpeg);
+            decoder.read_info().ok()?;
+            let info = decoder.info()?;
+            if info.coding_process == CodingProcess::Lossless {
+                return None;
+            }
+            Some((info.width as u32 * info.height as u32, jpeg))
+        })
+        .max_by_key(|(area, _)| *area)
+        .map(|(_, jpeg)| jpeg)
+}
+
+/// Develops the uncompressed sensor data of the RAW file, for files without
+/// a preview. The colour filter array is interpolated bilinearly and white
+/// balanced as shot, or by the average colour if that's unknown, but the
+/// camera colours aren't converted, so they are only roughly right.
+pub fn develop(contents: &[u8]) -> Result<RgbImage, String> {
+    let tiff = Tiff::new(contents)
+        .ok_or_else(|| "It isn't a TIFF file".to_string())?;
+    let ifds = tiff.ifds();
+    let (ifd, width, height) = ifds
+        .iter()
+        .filter(|ifd| {
+            tiff.number(ifd, PHOTOMETRIC_INTERPRETATION)
+                == Some(COLOR_FILTER_ARRAY)
+        })
+        .filter_map(|ifd| {
+            Some((
+                ifd,
+                tiff.number(ifd, IMAGE_WIDTH)?,
+                tiff.number(ifd, IMAGE_LENGTH)?,
+            ))
+        })
+        .max_by_key(|(_, width, height)| *width as u64 * *height as u64)
+        .ok_or_else(|| {
+            "It has neither a JPEG preview nor sensor data".to_string()
+        })?;
+
+    let compression = tiff.number(ifd, COMPRESSION).unwrap_or(UNCOMPRESSED);
+    if compression != UNCOMPRESSED {
+        return Err(format!(
+            "It has no JPEG preview, and its sensor data is compressed with method {}, which isn't supported",
+            compression
+        ));
+    }
+    if tiff.number(ifd, SAMPLES_PER_PIXEL).unwrap_or(1) != 1 {
+        return Err(
+            "The sensor data has more than one sample per pixel".to_string()
+        );
+    }
+
+    let bits = tiff.number(ifd, BITS_PER_SAMPLE).unwrap_or(16);
+    let samples = read_samples(&tiff, ifd, width, height, bits)?;
+    let pattern = CfaPattern::read(&tiff, ifd)?;
+    let black = tiff.real(ifd, BLACK_LEVEL).unwrap_or(0.0);
+    let white = tiff
+        .real(ifd, WHITE_LEVEL)
+        .unwrap_or(((1u64 << bits.min(32)) - 1) as f64);
+    if white <= black {
+        return Err("The white level isn't above the black level".to_string());
+    }
+
+    let linear: Vec<f64> = samples
+        .iter()
+        .map(|&sample| ((sample as f64 - black) / (white - black)).max(0.0))
+        .collect();
+    let demosaiced = demosaic(&linear, width, height, &pattern);
+    let gains = match tiff.reals(ifd, AS_SHOT_NEUTRAL) {
+        Some(neutral)
+            if neutral.len() == 3 && neutral.iter().all(|&n| n > 0.0) =>
+        {
+            [neutral[1] / neutral[0], 1.0, neutral[1] / neutral[2]]
+        }
+        _ => gray_world_gains(&demosaiced),
+    };
+
+    Ok(RgbImage::from_fn(width, height, |x, y| {
+        let pixel = demosaiced[y as usize * width as usize + x as usize];
+        let channel = |c: usize| to_srgb(pixel[c] * gains[c]);
+        Rgb([channel(0), channel(1), channel(2)])
+    }))
+}
+
+// The sensor samples, row by row. Samples wider than 8 bits are either in
+// 16 bit words, or packed without padding with the most significant bit
+// first, which is told apart by how many bytes there are.
+fn read_samples(
+    tiff: &Tiff,
+    ifd: &[Entry],
+    width: u32,
+    height: u32,
+    bits: u32,
+) -> Result<Vec<u32>, String> {
+    let offsets = tiff.numbers(ifd, STRIP_OFFSETS);
+    let counts = tiff.numbers(ifd, STRIP_BYTE_COUNTS);
+    let (offsets, counts) = match (offsets, counts) {
+        (Some(offsets), Some(counts)) if offsets.len() == counts.len() => {
+            (offsets, counts)
+        }
+        _ => return Err("The sensor data isn't in strips".to_string()),
+    };
+    // Strips are whole rows, so they can simply be joined
+    let mut data = Vec::new();
+    for (&offset, &count) in offsets.iter().zip(&counts) {
+        let strip = tiff
+            .bytes(offset as usize, count as usize)
+            .ok_or_else(|| "The sensor data is truncated".to_string())?;
+        data.extend_from_slice(strip);
+    }
+
+    let count = width as usize * height as usize;
+    if !(1..=32).contains(&bits) {
+        return Err(format!("{} bit samples aren't supported", bits));
+    }
+    if bits == 8 && data.len() >= count {
+        Ok(data[..count].iter().map(|&byte| byte as u32).collect())
+    } else if bits <= 16 && data.len() >= count * 2 {
+        Ok(data[..count * 2]
+            .chunks_exact(2)
+            .map(|word| tiff.u16_from(word) as u32)
+            .collect())
+    } else if data.len() * 8 >= count * bits as usize {
+        let mut samples = Vec::with_capacity(count);
+        let mut bit = 0;
+        for _ in 0..count {
+            let mut sample = 0;
+            for _ in 0..bits {
+                let byte = data[bit / 8];
+                sample = sample << 1 | (byte >> (7 - bit % 8)) as u32 & 1;
+                bit += 1;
+            }
+            samples.push(sample);
+        }
+        Ok(samples)
+    } else {
+        Err("The sensor data is truncated".to_string())
+    }
+}
+
+// Which colour, 0 for red, 1 for green and 2 for blue, each sensor pixel
+// sees, repeated over the sensor
+struct CfaPattern {
+    columns: u32,
+    rows: u32,
+    colors: Vec<u8>,
+}
+
+impl CfaPattern {
+    // RGGB if the file doesn't tell, which most cameras have
+    fn read(tiff: &Tiff, ifd: &[Entry]) -> Result<CfaPattern, String> {
+        let dimensions = tiff.numbers(ifd, CFA_REPEAT_PATTERN_DIM);
+        let colors = tiff.numbers(ifd, CFA_PATTERN);
+        let (rows, columns, colors) = match (dimensions, colors) {
+            (Some(dimensions), Some(colors)) if dimensions.len() == 2 => {
+                (dimensions[0], dimensions[1], colors)
+            }
+            _ => (2, 2, vec![0, 1, 1, 2]),
+        };
+        if rows == 0
+            || columns == 0
+            || rows.checked_mul(columns).map(|count| count as usize)
+                != Some(colors.len())
+            || colors.iter().any(|&color| color > 2)
+        {
+            return Err(
+                "The colour filter array isn't red, green and blue".to_string()
+            );
+        }
+        Ok(CfaPattern {
+            columns,
+            rows,
+            colors: colors.iter().map(|&color| color as u8).collect(),
+        })
+    }
+
+    fn color(&self, x: u32, y: u32) -> usize {
+        self.colors
+            [((y % self.rows) * self.columns + x % self.columns) as usize]
+            as usize
+    }
+}
+
+// Each colour of a pixel is the average of the samples of that colour
+// around it, including its own
+fn demosaic(
+    samples: &[f64],
+    width: u32,
+    height: u32,
+    pattern: &CfaPattern,
+) -> Vec<[f64; 3]> {
+    let mut pixels = Vec::with_capacity(samples.len());
+    for y in 0..height {
+        for x in 0..width {
+            let mut sums = [0.0; 3];
+            let mut counts = [0; 3];
+            for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                    let color = pattern.color(nx, ny);
+                    sums[color] +=
+                        samples[ny as usize * width as usize + nx as usize];
+                    counts[color] += 1;
+                }
+            }
+            let average = |c: usize| {
+                if counts[c] == 0 {
+                    0.0
+                } else {
+                    sums[c] / counts[c] as f64
+                }
+            };
+            pixels.push([average(0), average(1), average(2)]);
+        }
+    }
+    pixels
+}
+
+// Makes the average colour grey
+fn gray_world_gains(pixels: &[[f64; 3]]) -> [f64; 3] {
+    let mut sums = [0.0; 3];
+    for pixel in pixels {
+        for c in 0..3 {
+            sums[c] += pixel[c];
+        }
+    }
+    let gain = |c: usize| {
+        if sums[c] > 0.0 {
+            sums[1] / sums[c]
+        } else {
+            1.0
+        }
+    };
+    [gain(0), 1.0, gain(2)]
+}
+
+fn to_srgb(linear: f64) -> u8 {
+    let linear = linear.clamp(0.0, 1.0);
+    let encoded = if linear <= 0.003_130_8 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    };
+    (encoded * 255.0).round() as u8
+}
+
+struct Entry {
+    tag: u16,
+    field_type: u16,
+    count: usize,
+    // Where the value is, which is in the entry itself if it fits
+    value_offset: usize,
+}
+
+struct Tiff<'a> {
+    contents: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(contents: &'a [u8]) -> Option<Tiff<'a>> {
+        if is_tiff(contents) {
+            Some(Tiff {
+                contents,
+                big_endian: contents[0] == b'M',
+            })
+        } else {
+            None
+        }
+    }
+
+    fn bytes(&self, offset: usize, length: usize) -> Option<&'a [u8]> {
+        self.contents.get(offset..offset.checked_add(length)?)
+    }
+
+    fn u16_from(&self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        self.bytes(offset, 2).map(|bytes| self.u16_from(bytes))
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.bytes(offset, 4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    // The directories of the main images and their sub images, in no
+    // particular order. The exif directory has no images.
+    fn ifds(&self) -> Vec<Vec<Entry>> {
+        let mut ifds = Vec::new();
+        let mut visited = HashSet::new();
+        let mut pending: Vec<u32> = self.u32(4).into_iter().collect();
+        while let Some(offset) = pending.pop() {
+            if offset == 0 || ifds.len() >= MAX_IFDS || !visited.insert(offset)
+            {
+                continue;
+            }
+            let (ifd, next) = match self.ifd(offset as usize) {
+                Some(ifd) => ifd,
+                None => continue,
+            };
+            pending.push(next);
+            pending.extend(self.numbers(&ifd, SUB_IFDS).unwrap_or_default());
+            ifds.push(ifd);
+        }
+        ifds
+    }
+
+    // The entries of the directory at `offset`, and the offset of the next
+    // one
+    fn ifd(&self, offset: usize) -> Option<(Vec<Entry>, u32)> {
+        let count = self.u16(offset)? as usize;
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let start = offset + 2 + i * 12;
+            let field_type = self.u16(start + 2)?;
+            let count = self.u32(start + 4)? as usize;
+            let size = type_size(field_type).checked_mul(count)?;
+            let value_offset = if size <= 4 {
+                start + 8
+            } else {
+                self.u32(start + 8)? as usize
+            };
+            entries.push(Entry {
+                tag: self.u16(start)?,
+                field_type,
+                count,
+                value_offset,
+            });
+        }
+        let next = self.u32(offset + 2 + count * 12).unwrap_or(0);
+        Some((entries, next))
+    }
+
+    // The values of an integer field
+    fn numbers(&self, ifd: &[Entry], tag: u16) -> Option<Vec<u32>> {
+        let entry = ifd.iter().find(|entry| entry.tag == tag)?;
+        let size = type_size(entry.field_type);
+        (0..entry.count)
+            .map(|i| {
+                let offset = entry.value_offset + i * size;
+                match entry.field_type {
+                    1 | 7 => self.bytes(offset, 1).map(|byte| byte[0] as u32),
+                    3 => self.u16(offset).map(|value| value as u32),
+                    4 | 13 => self.u32(offset),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    fn number(&self, ifd: &[Entry], tag: u16) -> Option<u32> {
+        self.numbers(ifd, tag)?.first().copied()
+    }
+
+    // The values of an integer or rational field
+    fn reals(&self, ifd: &[Entry], tag: u16) -> Option<Vec<f64>> {
+        let entry = ifd.iter().find(|entry| entry.tag == tag)?;
+        match entry.field_type {
+            5 | 10 => (0..entry.count)
+                .map(|i| {
+                    let offset = entry.value_offset + i * 8;
+                    let numerator = self.u32(offset)?;
+                    let denominator = self.u32(offset + 4)?;
+                    if denominator == 0 {
+                        return None;
+                    }
+                    Some(if entry.field_type == 10 {
+                        numerator as i32 as f64 / denominator as i32 as f64
+                    } else {
+                        numerator as f64 / denominator as f64
+                    })
+                })
+                .collect(),
+            _ => Some(
+                self.numbers(ifd, tag)?
+                    .into_iter()
+                    .map(|value| value as f64)
+                    .collect(),
+            ),
+        }
+    }
+
+    fn real(&self, ifd: &[Entry], tag: u16) -> Option<f64> {
+        self.reals(ifd, tag)?.first().copied()
+    }
+
+    // A JPEG that the directory points to, other than sensor data. CR2 files
+    // store theirs as a single strip.
+    fn jpeg_in(&self, ifd: &[Entry]) -> Option<&'a [u8]> {
+        let jpeg = if let (Some(offset), Some(length)) = (
+            self.number(ifd, JPEG_INTERCHANGE_FORMAT),
+            self.number(ifd, JPEG_INTERCHANGE_FORMAT_LENGTH),
+        ) {
+            self.bytes(offset as usize, length as usize)?
+        } else {
+            let compression = self.number(ifd, COMPRESSION)?;
+            let photometric = self.number(ifd, PHOTOMETRIC_INTERPRETATION);
+            let offsets = self.numbers(ifd, STRIP_OFFSETS)?;
+            let counts = self.numbers(ifd, STRIP_BYTE_COUNTS)?;
+            if !(compression == OLD_JPEG || compression == JPEG)
+                || photometric == Some(COLOR_FILTER_ARRAY)
+                || offsets.len() != 1
+                || counts.len() != 1
+            {
+                return None;
+            }
+            self.bytes(offsets[0] as usize, counts[0] as usize)?
+        };
+        if jpeg.starts_with(&[0xFF, 0xD8]) {
+            Some(jpeg)
+        } else {
+            None
+        }
+    }
+}
+
+fn type_size(field_type: u16) -> usize {
+    match field_type {
+        3 | 8 => 2,
+        4 | 9 | 11 | 13 => 4,
+        5 | 10 | 12 => 8,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAW_WITH_PREVIEW: &str = "test_resources/small-with-preview.nef";
+    const RAW_WITHOUT_PREVIEW: &str =
+        "test_resources/small-without-preview.dng";
+
+    #[test]
+    fn largest_preview_is_found() {
+        let contents = std::fs::read(RAW_WITH_PREVIEW).unwrap();
+        let expected =
+            std::fs::read("test_resources/small-without-exif.jpg").unwrap();
+
+        assert!(is_tiff(&contents));
+        assert_eq!(Some(&expected[..]), embedded_preview(&contents));
+    }
+
+    #[test]
+    fn files_without_preview_have_none() {
+        let contents = std::fs::read(RAW_WITHOUT_PREVIEW).unwrap();
+
+        assert_eq!(None, embedded_preview(&contents));
+        assert_eq!(None, embedded_preview(b"II*\0\xff\xff\xff\xff"));
+        assert_eq!(None, embedded_preview(b"not a tiff"));
+    }
+
+    #[test]
+    fn sensor_data_is_developed() {
+        let contents = std::fs::read(RAW_WITHOUT_PREVIEW).unwrap();
+
+        let image = develop(&contents).unwrap();
+
+        // Red on the left half and blue on the right half
+        assert_eq!((32, 24), image.dimensions());
+        let left = image.get_pixel(4, 12);
+        let right = image.get_pixel(27, 12);
+        assert!(left[0] > 200 && left[1] < 60 && left[2] < 60, "{:?}", left);
+        assert!(
+            right[0] < 60 && right[1] < 60 && right[2] > 200,
+            "{:?}",
+            right
+        );
+    }
+
+    #[test]
+    fn packed_samples_are_read() {
+        // The strip offset and byte count, followed by two 12 bit samples
+        let contents = b"MM\0*\0\0\0\x0c\0\0\0\x03\xAB\xCD\xEF";
+        let tiff = Tiff::new(contents).unwrap();
+        let entry = |tag, value_offset| Entry {
+            tag,
+            field_type: 4,
+            count: 1,
+            value_offset,
+        };
+        let ifd = [entry(STRIP_OFFSETS, 4), entry(STRIP_BYTE_COUNTS, 8)];
+
+        let samples = read_samples(&tiff, &ifd, 2, 1, 12);
+
+        assert_eq!(Ok(vec![0xABC, 0xDEF]), samples);
+    }
+
+    #[test]
+    fn overflowing_colour_filter_arrays_are_rejected() {
+        // Two 65536 long dimensions, whose product overflows
+        let contents = b"MM\0*\0\x01\0\0\0\x01\0\0";
+        let tiff = Tiff::new(contents).unwrap();
+        let ifd = [
+            Entry {
+                tag: CFA_REPEAT_PATTERN_DIM,
+                field_type: 4,
+                count: 2,
+                value_offset: 4,
+            },
+            Entry {
+                tag: CFA_PATTERN,
+                field_type: 1,
+                count: 0,
+                value_offset: 0,
+            },
+        ];
+
+        assert!(CfaPattern::read(&tiff, &ifd).is_err());
+    }
+
+    #[test]
+    fn compressed_sensor_data_is_rejected() {
+        let contents = std::fs::read(RAW_WITH_PREVIEW).unwrap();
+
+        let error = develop(&contents).unwrap_err();
+
+        assert!(error.contains("compressed"), "{}", error);
+    }
+}
@@ -0,0 +1,337 @@
+//! Scales images with separable convolution, four channels at a time.
+//!
+//! The filters weigh the same source pixels as those of the image crate,
+//! but every pixel is held in one SIMD vector of its channels, and each
+//! source row is converted and filtered horizontally only once.
+
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageBuffer, Pixel, Primitive};
+use num_traits::{NumCast, ToPrimitive};
+use wide::f32x4;
+
+use crate::settings::ResizeFilter;
+
+/// Scales the image to exactly `width` x `height`, keeping its colour type
+pub fn resize(
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+    filter: ResizeFilter,
+) -> DynamicImage {
+    // Nearest picks pixels, so there is nothing to convolve
+    if filter == ResizeFilter::Nearest {
+        return image.resize_exact(width, height, FilterType::Nearest);
+    }
+    match image {
+        DynamicImage::ImageLuma8(buffer) => DynamicImage::ImageLuma8(
+            resize_buffer(buffer, width, height, filter),
+        ),
+        DynamicImage::ImageLumaA8(buffer) => DynamicImage::ImageLumaA8(
+            resize_buffer(buffer, width, height, filter),
+        ),
+        DynamicImage::ImageRgb8(buffer) => DynamicImage::ImageRgb8(
+            resize_buffer(buffer, width, height, filter),
+        ),
+        DynamicImage::ImageRgba8(buffer) => DynamicImage::ImageRgba8(
+            resize_buffer(buffer, width, height, filter),
+        ),
+        DynamicImage::ImageLuma16(buffer) => DynamicImage::ImageLuma16(
+            resize_buffer(buffer, width, height, filter),
+        ),
+        DynamicImage::ImageLumaA16(buffer) => DynamicImage::ImageLumaA16(
+            resize_buffer(buffer, width, height, filter),
+        ),
+        DynamicImage::ImageRgb16(buffer) => DynamicImage::ImageRgb16(
+            resize_buffer(buffer, width, height, filter),
+        ),
+        DynamicImage::ImageRgba16(buffer) => DynamicImage::ImageRgba16(
+            resize_buffer(buffer, width, height, filter),
+        ),
+        DynamicImage::ImageRgb32F(buffer) => DynamicImage::ImageRgb32F(
+            resize_buffer(buffer, width, height, filter),
+        ),
+        DynamicImage::ImageRgba32F(buffer) => DynamicImage::ImageRgba32F(
+            resize_buffer(buffer, width, height, filter),
+        ),
+        _ => DynamicImage::ImageRgba32F(resize_buffer(
+            &image.to_rgba32f(),
+            width,
+            height,
+            filter,
+        )),
+    }
+}
+
+fn resize_buffer<P>(
+    image: &ImageBuffer<P, Vec<P::Subpixel>>,
+    width: u32,
+    height: u32,
+    filter: ResizeFilter,
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel,
+{
+    let mut resized = ImageBuffer::new(width, height);
+    if width == 0 || height == 0 || image.width() == 0 || image.height() == 0 {
+        return resized;
+    }
+    let columns = Weights::new(image.width(), width, filter);
+    let rows = Weights::new(image.height(), height, filter);
+
+    // The source rows under the filter, already scaled horizontally
+    let mut window: VecDeque<Vec<f32x4>> = VecDeque::new();
+    let mut first_row = 0;
+    let mut source_row = Vec::with_capacity(image.width() as usize);
+    let mut row = vec![f32x4::ZERO; width as usize];
+    for y in 0..height {
+        let (top, weights) = rows.of(y);
+        while first_row < top {
+            window.pop_front();
+            first_row += 1;
+        }
+        while first_row + window.len() < top + weights.len() {
+            let source_y = (first_row + window.len()) as u32;
+            source_row.clear();
+            source_row.extend(
+                (0..image.width())
+                    .map(|x| to_vector(image.get_pixel(x, source_y))),
+            );
+            window.push_back(
+                (0..width)
+                    .map(|x| {
+                        let (left, weights) = columns.of(x);
+                        convolve(&source_row[left..], weights)
+                    })
+                    .collect(),
+            );
+        }
+
+        for (x, pixel) in row.iter_mut().enumerate() {
+            *pixel = weights
+                .iter()
+                .zip(&window)
+                .fold(f32x4::ZERO, |sum, (&weight, source)| {
+                    sum + source[x] * f32x4::splat(weight)
+                });
+        }
+        for (x, pixel) in row.iter().enumerate() {
+            resized.put_pixel(x as u32, y, from_vector(*pixel));
+        }
+    }
+    resized
+}
+
+fn convolve(pixels: &[f32x4], weights: &[f32]) -> f32x4 {
+    weights
+        .iter()
+        .zip(pixels)
+        .fold(f32x4::ZERO, |sum, (&weight, &pixel)| {
+            sum + pixel * f32x4::splat(weight)
+        })
+}
+
+fn to_vector<P: Pixel>(pixel: &P) -> f32x4 {
+    let mut lanes = [0.0; 4];
+    for (lane, channel) in lanes.iter_mut().zip(pixel.channels()) {
+        *lane = channel.to_f32().unwrap_or(0.0);
+    }
+    f32x4::from(lanes)
+}
+
+fn from_vector<P: Pixel>(vector: f32x4) -> P {
+    let max = P::Subpixel::DEFAULT_MAX_VALUE.to_f32().unwrap_or(1.0);
+    // Floating point samples go from 0 to 1, and aren't rounded
+    let vector = if max > 1.0 { vector.round() } else { vector };
+    let lanes = vector.max(f32x4::ZERO).min(f32x4::splat(max)).to_array();
+    let mut pixel = *P::from_slice(
+        &[P::Subpixel::DEFAULT_MIN_VALUE; 4][..P::CHANNEL_COUNT as usize],
+    );
+    for (channel, lane) in pixel.channels_mut().iter_mut().zip(lanes) {
+        *channel =
+            NumCast::from(lane).unwrap_or(P::Subpixel::DEFAULT_MAX_VALUE);
+    }
+    pixel
+}
+
+// For each target pixel along one axis, the first source pixel under the
+// filter, and the normalized weights of it and the following ones
+struct Weights {
+    starts: Vec<usize>,
+    // `taps` per target pixel, zero past the end of its weights
+    weights: Vec<f32>,
+    lens: Vec<usize>,
+    taps: usize,
+}
+
+impl Weights {
+    fn new(source_len: u32, target_len: u32, filter: ResizeFilter) -> Weights {
+        let (kernel, support): (fn(f32) -> f32, f32) = match filter {
+            ResizeFilter::Nearest | ResizeFilter::Bilinear => (triangle, 1.0),
+            ResizeFilter::CatmullRom => (catmull_rom, 2.0),
+            ResizeFilter::Gaussian => (gaussian, 3.0),
+            ResizeFilter::Lanczos3 => (lanczos3, 3.0),
+        };
+        let ratio = source_len as f32 / target_len as f32;
+        // When shrinking, the filter is stretched to cover all source pixels
+        let scale = ratio.max(1.0);
+        let source_support = support * scale;
+        let taps = (source_support * 2.0).ceil() as usize + 2;
+
+        let mut starts = Vec::with_capacity(target_len as usize);
+        let mut lens = Vec::with_capacity(target_len as usize);
+        let mut weights = vec![0.0; taps * target_len as usize];
+        for i in 0..target_len as usize {
+            let center = (i as f32 + 0.5) * ratio;
+            let start = ((center - source_support).floor().max(0.0) as usize)
+                .min(source_len as usize - 1);
+            let end = ((center + source_support).ceil() as usize)
+                .clamp(start + 1, source_len as usize)
+                .min(start + taps);
+            let tap_weights = &mut weights[i * taps..i * taps + end - start];
+            for (j, weight) in tap_weights.iter_mut().enumerate() {
+                let x = (start + j) as f32 + 0.5 - center;
+                *weight = kernel(x / scale);
+            }
+            let sum: f32 = tap_weights.iter().sum();
+            if sum != 0.0 {
+                tap_weights.iter_mut().for_each(|weight| *weight /= sum);
+            }
+            starts.push(start);
+            lens.push(end - start);
+        }
+        Weights {
+            starts,
+            weights,
+            lens,
+            taps,
+        }
+    }
+
+    fn of(&self, i: u32) -> (usize, &[f32]) {
+        let i = i as usize;
+        let weights =
+            &self.weights[i * self.taps..i * self.taps + self.lens[i]];
+        (self.starts[i], weights)
+    }
+}
+
+fn triangle(x: f32) -> f32 {
+    (1.0 - x.abs()).max(0.0)
+}
+
+fn catmull_rom(x: f32) -> f32 {
+    let x = x.abs();
+    if x < 1.0 {
+        (9.0 * x - 15.0) * x * x / 6.0 + 1.0
+    } else if x < 2.0 {
+        ((-3.0 * x + 15.0) * x - 24.0) * x / 6.0 + 2.0
+    } else {
+        0.0
+    }
+}
+
+fn gaussian(x: f32) -> f32 {
+    // The same standard deviation of half a pixel as the image crate
+    const SIGMA: f32 = 0.5;
+    (-x * x / (2.0 * SIGMA * SIGMA)).exp() / ((2.0 * PI).sqrt() * SIGMA)
+}
+
+fn lanczos3(x: f32) -> f32 {
+    if x.abs() < 3.0 {
+        sinc(x) * sinc(x / 3.0)
+    } else {
+        0.0
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView, GrayImage, Luma, Rgb, RgbImage};
+
+    use super::*;
+
+    const FILTERS: [ResizeFilter; 4] = [
+        ResizeFilter::Bilinear,
+        ResizeFilter::CatmullRom,
+        ResizeFilter::Gaussian,
+        ResizeFilter::Lanczos3,
+    ];
+
+    #[test]
+    fn flat_images_keep_their_color() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(
+            301,
+            97,
+            Rgb([12, 200, 255]),
+        ));
+
+        for filter in FILTERS {
+            for (width, height) in [(30, 10), (1000, 500), (1, 1)] {
+                let resized = resize(&image, width, height, filter);
+
+                assert_eq!((width, height), resized.dimensions());
+                for (_, _, pixel) in resized.to_rgb8().enumerate_pixels() {
+                    assert_eq!(&Rgb([12, 200, 255]), pixel, "{:?}", filter);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn color_types_are_kept() {
+        let image = DynamicImage::new_luma_a16(40, 40);
+
+        let resized = resize(&image, 10, 20, ResizeFilter::Lanczos3);
+
+        assert_eq!(image.color(), resized.color());
+        assert_eq!((10, 20), resized.dimensions());
+    }
+
+    #[test]
+    fn results_are_close_to_those_of_the_image_crate() {
+        let image =
+            DynamicImage::ImageLuma8(GrayImage::from_fn(400, 300, |x, y| {
+                Luma([((x * 7 + y * 3) % 256) as u8])
+            }));
+
+        for (filter, filter_type) in [
+            (ResizeFilter::Bilinear, FilterType::Triangle),
+            (ResizeFilter::CatmullRom, FilterType::CatmullRom),
+            (ResizeFilter::Gaussian, FilterType::Gaussian),
+            (ResizeFilter::Lanczos3, FilterType::Lanczos3),
+        ] {
+            for (width, height) in [(100, 75), (37, 290), (800, 600)] {
+                let ours = resize(&image, width, height, filter).to_luma8();
+                let theirs =
+                    image.resize_exact(width, height, filter_type).to_luma8();
+
+                let total_difference: u64 = ours
+                    .pixels()
+                    .zip(theirs.pixels())
+                    .map(|(a, b)| (a[0] as i64 - b[0] as i64).unsigned_abs())
+                    .sum();
+                let mean_difference =
+                    total_difference as f64 / (width * height) as f64;
+                assert!(
+                    mean_difference < 3.0,
+                    "{:?} {}x{}: {}",
+                    filter,
+                    width,
+                    height,
+                    mean_difference
+                );
+            }
+        }
+    }
+}
@@ -1,97 +1,779 @@
-use std::path::PathBuf;
-
-use clap::{App, AppSettings, Arg, ArgMatches};
+use serde::{Deserialize, Serialize};
 
+/// Controls how the destination is produced from the source.
+///
+/// Create it with [`Settings::builder`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Settings {
     pub image_quality: ImageQuality,
-    pub verbose_print: bool,
     pub include_videos: bool,
+    #[serde(default)]
+    pub write_index: bool,
+    #[serde(default)]
+    pub write_gallery: bool,
+    #[serde(default)]
+    pub layout: Layout,
+    /// The top-level directory that files without a capture time are put
+    /// in, unless the layout is [`Layout::Mirror`]
+    #[serde(default = "default_undated_folder")]
+    pub undated_folder: String,
+    #[serde(default)]
+    pub deduplicate: bool,
+    /// The EXIF fields that are copied from the source images to the
+    /// destination images
+    // Markers from before it existed are of images without any
+    #[serde(default)]
+    pub keep_exif: Vec<ExifGroup>,
+    /// Never copies GPS fields, even if `keep_exif` has [`ExifGroup::Gps`]
+    #[serde(default)]
+    pub strip_gps: bool,
+    #[serde(default)]
+    pub color_profile: ColorProfile,
+    // Only affects the run it's given to, so it's not stored in the
+    // destination marker.
+    #[serde(skip)]
+    pub adopt: bool,
+}
+
+/// How the files are organized in the destination.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Layout {
+    /// The same directories as in the source
+    #[default]
+    Mirror,
+    /// `yyyy/mm/` directories from the capture time
+    YearMonth,
+    /// `yyyy/yyyy-mm-dd/` directories from the capture time
+    YearDate,
+}
+
+/// EXIF fields that are copied together. The orientation is always reset,
+/// since the images are already rotated by then.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExifGroup {
+    /// When the image was captured and last changed
+    Date,
+    /// The make and model of the camera and lens
+    Camera,
+    /// Exposure time, aperture, ISO, focal length, flash and so on
+    Exposure,
+    /// Where the image was captured
+    Gps,
+}
+
+impl ExifGroup {
+    /// The group with this name on the command line
+    pub fn from_name(name: &str) -> Option<ExifGroup> {
+        match name {
+            "date" => Some(ExifGroup::Date),
+            "camera" => Some(ExifGroup::Camera),
+            "exposure" => Some(ExifGroup::Exposure),
+            "gps" => Some(ExifGroup::Gps),
+            _ => None,
+        }
+    }
+}
+
+/// What is done with the ICC colour profile of source images that have one,
+/// such as Display P3 or Adobe RGB photos. Shown without it, their colours
+/// look washed out or oversaturated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorProfile {
+    /// The pixels are converted to sRGB, which all viewers assume
+    #[default]
+    Convert,
+    /// The profile is embedded in the destination images, for viewers that
+    /// manage colours. AVIF images are converted instead.
+    Keep,
+}
+
+impl ColorProfile {
+    /// The choice with this name on the command line
+    pub fn from_name(name: &str) -> Option<ColorProfile> {
+        match name {
+            "convert" => Some(ColorProfile::Convert),
+            "keep" => Some(ColorProfile::Keep),
+            _ => None,
+        }
+    }
+}
+
+fn default_undated_folder() -> String {
+    "Undated".to_string()
 }
 
+/// The size and compression that images are converted to.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum ImageQuality {
+    /// 1024x1024, JPEG quality 30
     Mobile,
+    /// 1920x1080, JPEG quality 70
     Television,
+    /// 300x300, JPEG quality 30
     Thumbnail,
+    /// Exactly 300x300, cropped from the centre, JPEG quality 30, so that
+    /// thumbnails line up in a grid
+    SquareThumbnail,
+    /// A user-defined size and quality, for example from a config file
+    Custom(Preset),
 }
 
-impl Settings {
-    pub fn new_from_matches(matches: &ArgMatches) -> Settings {
-        let image_quality = matches.value_of("image quality").unwrap();
-        let image_quality = match image_quality {
-            "Mobile" => ImageQuality::Mobile,
-            "TV" => ImageQuality::Television,
-            "Thumbnail" => ImageQuality::Thumbnail,
-            _ => panic!("Unknown image quality selected."),
-        };
+impl ImageQuality {
+    /// The built-in image quality with this name on the command line
+    pub fn built_in(name: &str) -> Option<ImageQuality> {
+        match name {
+            "Mobile" => Some(ImageQuality::Mobile),
+            "TV" => Some(ImageQuality::Television),
+            "Thumbnail" => Some(ImageQuality::Thumbnail),
+            "SquareThumbnail" => Some(ImageQuality::SquareThumbnail),
+            _ => None,
+        }
+    }
+
+    pub fn preset(&self) -> Preset {
+        let built_in =
+            |name: &str, max_width, max_height, quality, resize| Preset {
+                name: name.to_string(),
+                max_width,
+                max_height,
+                quality,
+                target_size: None,
+                target_ssim: None,
+                resize,
+                crop: CropMode::Center,
+                filter: ResizeFilter::default(),
+                pre_shrink: false,
+                format: OutputFormat::default(),
+                background: default_background(),
+                jpeg: JpegOptions::default(),
+            };
+
+        match self {
+            ImageQuality::Mobile => {
+                built_in("Mobile", 1024, 1024, 30, ResizePolicy::Fit)
+            }
+            ImageQuality::Television => {
+                built_in("TV", 1920, 1080, 70, ResizePolicy::Fit)
+            }
+            ImageQuality::Thumbnail => {
+                built_in("Thumbnail", 300, 300, 30, ResizePolicy::Fit)
+            }
+            ImageQuality::SquareThumbnail => {
+                built_in("SquareThumbnail", 300, 300, 30, ResizePolicy::Fill)
+            }
+            ImageQuality::Custom(preset) => preset.clone(),
+        }
+    }
+}
+
+/// A named image size, quality and format.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Preset {
+    pub name: String,
+    /// The box that images are scaled to, as decided by `resize`
+    pub max_width: u32,
+    pub max_height: u32,
+    /// From 1 to 100
+    pub quality: u8,
+    /// Replaces `quality` with the highest one that keeps images within a
+    /// file size
+    #[serde(default)]
+    pub target_size: Option<TargetSize>,
+    /// Replaces `quality` with the lowest one that keeps images visually
+    /// similar to the resized source images
+    #[serde(default)]
+    pub target_ssim: Option<TargetSsim>,
+    #[serde(default)]
+    pub resize: ResizePolicy,
+    /// Which part of the image is kept when `resize` crops it
+    #[serde(default)]
+    pub crop: CropMode,
+    /// How pixels are interpolated when scaling
+    #[serde(default)]
+    pub filter: ResizeFilter,
+    /// Whether large images are first shrunk by averaging blocks of pixels
+    /// to twice the target size, which is faster but a little blurrier
+    #[serde(default)]
+    pub pre_shrink: bool,
+    /// The file format that images are written in
+    #[serde(default)]
+    pub format: OutputFormat,
+    /// The colour, as red, green and blue, that transparent parts of images
+    /// are filled with when written in a format without transparency
+    #[serde(default = "default_background")]
+    pub background: [u8; 3],
+    /// How images are encoded when `format` is [`OutputFormat::Jpeg`]
+    #[serde(default)]
+    pub jpeg: JpegOptions,
+}
+
+/// Values that replace those of a preset, where they're given
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PresetOverrides {
+    /// The maximum width and height
+    pub max_size: Option<(u32, u32)>,
+    pub quality: Option<u8>,
+    /// Replaces the target SSIM of the preset
+    pub target_size: Option<u64>,
+    /// Replaces the target size of the preset
+    pub target_ssim: Option<f64>,
+    /// The range of qualities searched for the target size or SSIM
+    pub min_quality: Option<u8>,
+    pub max_quality: Option<u8>,
+    pub resize: Option<ResizePolicy>,
+    pub crop: Option<CropMode>,
+    pub filter: Option<ResizeFilter>,
+    pub pre_shrink: Option<bool>,
+    pub format: Option<OutputFormat>,
+    pub background: Option<[u8; 3]>,
+    pub jpeg_encoder: Option<JpegEncoder>,
+    pub progressive: Option<bool>,
+    pub trellis: Option<bool>,
+    pub chroma_subsampling: Option<ChromaSubsampling>,
+}
+
+impl PresetOverrides {
+    pub fn is_empty(&self) -> bool {
+        *self == PresetOverrides::default()
+    }
+}
+
+pub(crate) fn default_background() -> [u8; 3] {
+    [255, 255, 255]
+}
+
+/// The colour in a hex string like "#ff8000" or "ff8000", as red, green and
+/// blue
+pub fn parse_color(value: &str) -> Option<[u8; 3]> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([component(0)?, component(2)?, component(4)?])
+}
+
+/// How images are scaled to the box of a [`Preset`]. The aspect ratio is
+/// always kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResizePolicy {
+    /// Downscale to fit in the box. Smaller images are kept as they are.
+    #[default]
+    Fit,
+    /// Scale up or down to fit in the box
+    FitUpscale,
+    /// Scale to cover the box, and crop what's outside it, so that the
+    /// image gets exactly the size of the box
+    Fill,
+    /// Scale so that the longer edge gets the longer side of the box
+    LongEdge,
+    /// Scale so that the shorter edge gets the shorter side of the box
+    ShortEdge,
+}
+
+impl ResizePolicy {
+    /// The policy with this name in config files and on the command line,
+    /// such as "long-edge"
+    pub fn from_name(name: &str) -> Option<ResizePolicy> {
+        match name {
+            "fit" => Some(ResizePolicy::Fit),
+            "fit-upscale" => Some(ResizePolicy::FitUpscale),
+            "fill" => Some(ResizePolicy::Fill),
+            "long-edge" => Some(ResizePolicy::LongEdge),
+            "short-edge" => Some(ResizePolicy::ShortEdge),
+            _ => None,
+        }
+    }
+}
+
+/// Which part of an image is kept when it's cropped to the box of a
+/// [`Preset`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CropMode {
+    /// The middle
+    #[default]
+    Center,
+    /// The part with the most edges, which is usually where the subject
+    /// is, rather than sky, walls or blurred background
+    Smart,
+}
+
+impl CropMode {
+    /// The mode with this name in config files and on the command line
+    pub fn from_name(name: &str) -> Option<CropMode> {
+        match name {
+            "center" => Some(CropMode::Center),
+            "smart" => Some(CropMode::Smart),
+            _ => None,
+        }
+    }
+}
+
+/// The filter that images are scaled with. The faster filters suit small
+/// images such as thumbnails, where the differences are hard to see.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResizeFilter {
+    /// The fastest, but jagged edges and aliasing
+    Nearest,
+    /// Fast and smooth, but a little blurry
+    Bilinear,
+    /// Sharp, and not much slower than bilinear
+    CatmullRom,
+    /// Soft, and the slowest
+    #[default]
+    Gaussian,
+    /// The sharpest, but may ring around hard edges
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    /// The filter with this name in config files and on the command line
+    pub fn from_name(name: &str) -> Option<ResizeFilter> {
+        match name {
+            "nearest" => Some(ResizeFilter::Nearest),
+            "bilinear" => Some(ResizeFilter::Bilinear),
+            "catmull-rom" => Some(ResizeFilter::CatmullRom),
+            "gaussian" => Some(ResizeFilter::Gaussian),
+            "lanczos3" => Some(ResizeFilter::Lanczos3),
+            _ => None,
+        }
+    }
+}
+
+/// The file format of the destination images. WebP and AVIF files are much
+/// smaller than JPEGs of the same visual quality, but take longer to encode,
+/// AVIF in particular.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    #[default]
+    Jpeg,
+    Webp,
+    Avif,
+}
+
+impl OutputFormat {
+    /// The format with this name in config files and on the command line
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        match name {
+            "jpeg" => Some(OutputFormat::Jpeg),
+            "webp" => Some(OutputFormat::Webp),
+            "avif" => Some(OutputFormat::Avif),
+            _ => None,
+        }
+    }
+
+    /// The extension of destination images in this format, without the dot
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Avif => "avif",
+        }
+    }
+}
+
+/// A file size that images are compressed to by searching the quality.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetSize {
+    /// The largest size of a destination image, in bytes
+    pub max_bytes: u64,
+    /// The range of the quality, from 1 to 100. Images that are larger than
+    /// `max_bytes` at `min_quality` are written at `min_quality` anyway.
+    #[serde(default = "default_min_quality")]
+    pub min_quality: u8,
+    #[serde(default = "default_max_quality")]
+    pub max_quality: u8,
+}
 
-        let verbose_print = matches.is_present("verbose");
-        let include_videos = matches.is_present("include-videos");
+/// A visual similarity that images are compressed to by searching the
+/// quality, so that all images look about as good, whatever their
+/// contents. The similarity is the structural similarity index, SSIM,
+/// between the brightness of the destination image and of the resized source
+/// image.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetSsim {
+    /// From 0 to 1, where 1 is identical. Around 0.95 hardly shows, and
+    /// 0.99 is hard to tell from the source even side by side.
+    pub min_ssim: f64,
+    /// The range of the quality, from 1 to 100. Images that are less
+    /// similar than `min_ssim` at `max_quality` are written at it anyway.
+    #[serde(default = "default_min_quality")]
+    pub min_quality: u8,
+    #[serde(default = "default_max_quality")]
+    pub max_quality: u8,
+}
 
-        Settings {
-            image_quality: image_quality,
-            verbose_print: verbose_print,
-            include_videos: include_videos,
+fn default_min_quality() -> u8 {
+    10
+}
+
+fn default_max_quality() -> u8 {
+    90
+}
+
+/// How JPEGs are encoded. Apart from `encoder`, the options only apply to
+/// [`JpegEncoder::Mozjpeg`].
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JpegOptions {
+    pub encoder: JpegEncoder,
+    /// Whether the image is stored in several scans of increasing detail,
+    /// so that viewers can show all of it before it has loaded
+    pub progressive: bool,
+    /// Whether the quantization is optimized for the size of the file,
+    /// which makes it a little smaller but takes several times longer
+    pub trellis: bool,
+    pub chroma_subsampling: ChromaSubsampling,
+}
+
+impl Default for JpegOptions {
+    fn default() -> JpegOptions {
+        JpegOptions {
+            encoder: JpegEncoder::default(),
+            progressive: true,
+            trellis: false,
+            chroma_subsampling: ChromaSubsampling::default(),
         }
     }
 }
 
-pub fn get_matches<'a>() -> ArgMatches<'a> {
-    App::new("ImageMapper")
-        .setting(AppSettings::DisableVersion)
-        .about("Maps the source directory structure to an equivalent structure in the destination directory. The differences are: 1. Images will be downscaled and compressed. 2. Images will get their exif date/time prepended to their file names. 3. Images (and optionally videos) are the only files that will be kept.")
-        .max_term_width(90)
-        .arg(source_path_argument())
-        .arg(destination_path_argument())
-        .arg(image_quality_argument())
-        .arg(verbose_print_argument())
-        .arg(include_videos_argument())
-        .get_matches()
+/// The library that encodes JPEGs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JpegEncoder {
+    /// The encoder of the image crate, which is fast, but only writes
+    /// baseline JPEGs
+    #[default]
+    Image,
+    /// MozJPEG, which writes progressive JPEGs with optimized Huffman
+    /// tables that are considerably smaller at the same quality
+    Mozjpeg,
 }
 
-fn source_path_argument<'a>() -> Arg<'a, 'a> {
-    Arg::with_name("source directory")
-        .required(true)
-        .takes_value(true)
-        .help("The path to the directory that will be mapped.")
+impl JpegEncoder {
+    /// The encoder with this name in config files and on the command line
+    pub fn from_name(name: &str) -> Option<JpegEncoder> {
+        match name {
+            "image" => Some(JpegEncoder::Image),
+            "mozjpeg" => Some(JpegEncoder::Mozjpeg),
+            _ => None,
+        }
+    }
 }
 
-pub fn source_path_from_matches(matches: &ArgMatches) -> PathBuf {
-    let source_path = matches.value_of("source directory").unwrap();
-    PathBuf::from(source_path)
+/// How much of the colour resolution is kept, relative to the brightness.
+/// Eyes hardly notice less colour resolution in photos, but do around sharp
+/// coloured edges, such as in text and drawings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum ChromaSubsampling {
+    /// Half horizontally and vertically, the smallest files
+    #[default]
+    #[serde(rename = "4:2:0")]
+    Yuv420,
+    /// Half horizontally
+    #[serde(rename = "4:2:2")]
+    Yuv422,
+    /// Full, the largest files
+    #[serde(rename = "4:4:4")]
+    Yuv444,
 }
 
-fn destination_path_argument<'a>() -> Arg<'a, 'a> {
-    Arg::with_name("destination directory")
-        .required(true)
-        .takes_value(true)
-        .help("The path to the directory where the result of the mapping will be placed.")
+impl ChromaSubsampling {
+    /// The subsampling with this name in config files and on the command
+    /// line
+    pub fn from_name(name: &str) -> Option<ChromaSubsampling> {
+        match name {
+            "4:2:0" => Some(ChromaSubsampling::Yuv420),
+            "4:2:2" => Some(ChromaSubsampling::Yuv422),
+            "4:4:4" => Some(ChromaSubsampling::Yuv444),
+            _ => None,
+        }
+    }
 }
 
-pub fn destination_path_from_matches(matches: &ArgMatches) -> PathBuf {
-    let destination_path = matches.value_of("destination directory").unwrap();
-    PathBuf::from(destination_path)
+impl Preset {
+    /// A preset without a maximum size, at quality 70, for the overrides to
+    /// fill in
+    pub fn custom(name: &str) -> Preset {
+        Preset {
+            name: name.to_string(),
+            max_width: 0,
+            max_height: 0,
+            quality: 70,
+            target_size: None,
+            target_ssim: None,
+            resize: ResizePolicy::default(),
+            crop: CropMode::default(),
+            filter: ResizeFilter::default(),
+            pre_shrink: false,
+            format: OutputFormat::default(),
+            background: default_background(),
+            jpeg: JpegOptions::default(),
+        }
+    }
+
+    /// Replaces the values that the overrides give, and fails if the result
+    /// isn't valid
+    pub fn with_overrides(
+        mut self,
+        overrides: &PresetOverrides,
+    ) -> Result<Preset, String> {
+        if let Some((max_width, max_height)) = overrides.max_size {
+            self.max_width = max_width;
+            self.max_height = max_height;
+        }
+        if let Some(quality) = overrides.quality {
+            self.quality = quality;
+        }
+        if let Some(max_bytes) = overrides.target_size {
+            self.target_size = Some(match self.target_size {
+                Some(target_size) => TargetSize {
+                    max_bytes,
+                    ..target_size
+                },
+                None => TargetSize::new(max_bytes),
+            });
+            self.target_ssim = None;
+        }
+        if let Some(min_ssim) = overrides.target_ssim {
+            self.target_ssim = Some(match self.target_ssim {
+                Some(target_ssim) => TargetSsim {
+                    min_ssim,
+                    ..target_ssim
+                },
+                None => TargetSsim::new(min_ssim),
+            });
+            self.target_size = None;
+        }
+        if overrides.min_quality.is_some() || overrides.max_quality.is_some() {
+            let (min_quality, max_quality) = match (
+                self.target_size.as_mut(),
+                self.target_ssim.as_mut(),
+            ) {
+                (Some(t), _) => (&mut t.min_quality, &mut t.max_quality),
+                (None, Some(t)) => (&mut t.min_quality, &mut t.max_quality),
+                (None, None) => return Err(
+                    "A minimum or maximum quality needs a target size or SSIM"
+                        .to_string(),
+                ),
+            };
+            *min_quality = overrides.min_quality.unwrap_or(*min_quality);
+            *max_quality = overrides.max_quality.unwrap_or(*max_quality);
+        }
+        self.resize = overrides.resize.unwrap_or(self.resize);
+        self.crop = overrides.crop.unwrap_or(self.crop);
+        self.filter = overrides.filter.unwrap_or(self.filter);
+        self.pre_shrink = overrides.pre_shrink.unwrap_or(self.pre_shrink);
+        self.format = overrides.format.unwrap_or(self.format);
+        self.background = overrides.background.unwrap_or(self.background);
+        self.jpeg.encoder = overrides.jpeg_encoder.unwrap_or(self.jpeg.encoder);
+        self.jpeg.progressive =
+            overrides.progressive.unwrap_or(self.jpeg.progressive);
+        self.jpeg.trellis = overrides.trellis.unwrap_or(self.jpeg.trellis);
+        self.jpeg.chroma_subsampling = overrides
+            .chroma_subsampling
+            .unwrap_or(self.jpeg.chroma_subsampling);
+        self.validate()?;
+        Ok(self)
+    }
+
+    /// Returns what's wrong with the values, if anything
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_width == 0 || self.max_height == 0 {
+            Err("The maximum width and height must be at least 1".to_string())
+        } else if !(1..=100).contains(&self.quality) {
+            Err("The quality must be from 1 to 100".to_string())
+        } else if self.target_size.is_some() && self.target_ssim.is_some() {
+            Err("A target size and a target SSIM can't both be given"
+                .to_string())
+        } else if let Some(target_size) = self.target_size {
+            target_size.validate()
+        } else if let Some(target_ssim) = self.target_ssim {
+            if self.format == OutputFormat::Avif {
+                // There is no AVIF decoder to compare with
+                Err("A target SSIM isn't supported for AVIF".to_string())
+            } else {
+                target_ssim.validate()
+            }
+        } else {
+            Ok(())
+        }
+    }
 }
 
-fn image_quality_argument<'a>() -> Arg<'a, 'a> {
-    Arg::with_name("image quality")
-        .required(true)
-        .takes_value(true)
-        .possible_values(&["Mobile", "TV", "Thumbnail"])
-        .help("Select if the images should be converted to the mobile quality (1024x1024, 30% compression), the TV quality (1920x1080, 70% compression) or the thumbnail quality (300x300, 30% compression.")
+impl TargetSize {
+    /// Searches the qualities from 10 to 90
+    pub fn new(max_bytes: u64) -> TargetSize {
+        TargetSize {
+            max_bytes,
+            min_quality: default_min_quality(),
+            max_quality: default_max_quality(),
+        }
+    }
+
+    /// Returns what's wrong with the values, if anything
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_bytes == 0 {
+            Err("The target size must be at least 1 byte".to_string())
+        } else {
+            validate_quality_range(self.min_quality, self.max_quality)
+        }
+    }
 }
 
-fn verbose_print_argument<'a>() -> Arg<'a, 'a> {
-    Arg::with_name("verbose")
-        .short("v")
-        .long("verbose")
-        .takes_value(false)
-        .help("Print when a directory is enterted and when a file is added/delted. No matter of this setting, errors will always be printed.")
+impl TargetSsim {
+    /// Searches the qualities from 10 to 90
+    pub fn new(min_ssim: f64) -> TargetSsim {
+        TargetSsim {
+            min_ssim,
+            min_quality: default_min_quality(),
+            max_quality: default_max_quality(),
+        }
+    }
+
+    /// Returns what's wrong with the values, if anything
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.min_ssim > 0.0 && self.min_ssim <= 1.0) {
+            Err("The target SSIM must be above 0 and at most 1".to_string())
+        } else {
+            validate_quality_range(self.min_quality, self.max_quality)
+        }
+    }
+}
+
+fn validate_quality_range(
+    min_quality: u8,
+    max_quality: u8,
+) -> Result<(), String> {
+    let qualities = 1..=100;
+    if !qualities.contains(&min_quality) || !qualities.contains(&max_quality) {
+        Err("The minimum and maximum quality must be from 1 to 100".to_string())
+    } else if min_quality > max_quality {
+        Err("The minimum quality must not be above the maximum quality"
+            .to_string())
+    } else {
+        Ok(())
+    }
+}
+
+impl Settings {
+    /// Starts building settings with the given image quality. Everything
+    /// else defaults to what the command line does without flags.
+    pub fn builder(image_quality: ImageQuality) -> SettingsBuilder {
+        SettingsBuilder {
+            settings: Settings {
+                image_quality,
+                include_videos: false,
+                write_index: false,
+                write_gallery: false,
+                layout: Layout::Mirror,
+                undated_folder: default_undated_folder(),
+                deduplicate: false,
+                keep_exif: vec![
+                    ExifGroup::Date,
+                    ExifGroup::Camera,
+                    ExifGroup::Exposure,
+                ],
+                strip_gps: false,
+                color_profile: ColorProfile::Convert,
+                adopt: false,
+            },
+        }
+    }
+}
+
+pub struct SettingsBuilder {
+    settings: Settings,
 }
 
-fn include_videos_argument<'a>() -> Arg<'a, 'a> {
-    Arg::with_name("include-videos")
-        .short("i")
-        .long("include-videos")
-        .takes_value(false)
-        .help("Instead of just images, with this option, videos will also be included in the destination. Note that they will just be copied as-is without any conversion.")
+impl SettingsBuilder {
+    /// Whether videos are copied as-is to the destination. Off by default.
+    pub fn include_videos(mut self, include_videos: bool) -> SettingsBuilder {
+        self.settings.include_videos = include_videos;
+        self
+    }
+
+    /// Whether an `index.json` describing the files is written to each
+    /// destination directory. Off by default.
+    pub fn write_index(mut self, write_index: bool) -> SettingsBuilder {
+        self.settings.write_index = write_index;
+        self
+    }
+
+    /// Whether an `index.html` page showing the images is written to each
+    /// destination directory, so that the destination can be browsed in a
+    /// web browser. Off by default.
+    pub fn write_gallery(mut self, write_gallery: bool) -> SettingsBuilder {
+        self.settings.write_gallery = write_gallery;
+        self
+    }
+
+    /// How the files are organized in the destination. Mirrors the source
+    /// by default.
+    pub fn layout(mut self, layout: Layout) -> SettingsBuilder {
+        self.settings.layout = layout;
+        self
+    }
+
+    /// The top-level directory that files without a capture time are put
+    /// in, for the date layouts. "Undated" by default.
+    pub fn undated_folder(mut self, undated_folder: &str) -> SettingsBuilder {
+        self.settings.undated_folder = undated_folder.to_string();
+        self
+    }
+
+    /// Whether source files with identical contents are converted only
+    /// once, and the other destination files are hard links to the first
+    /// one, or copies of it where hard links aren't supported. Off by
+    /// default.
+    pub fn deduplicate(mut self, deduplicate: bool) -> SettingsBuilder {
+        self.settings.deduplicate = deduplicate;
+        self
+    }
+
+    /// The EXIF fields that are copied to the destination images. The date,
+    /// camera and exposure by default, but not the GPS location.
+    pub fn keep_exif(mut self, keep_exif: &[ExifGroup]) -> SettingsBuilder {
+        self.settings.keep_exif = keep_exif.to_vec();
+        self
+    }
+
+    /// Whether GPS fields are left out even if `keep_exif` has them, so
+    /// that the destination never reveals where images were taken. Off by
+    /// default.
+    pub fn strip_gps(mut self, strip_gps: bool) -> SettingsBuilder {
+        self.settings.strip_gps = strip_gps;
+        self
+    }
+
+    /// What is done with ICC colour profiles of source images. Converts
+    /// the pixels to sRGB by default.
+    pub fn color_profile(
+        mut self,
+        color_profile: ColorProfile,
+    ) -> SettingsBuilder {
+        self.settings.color_profile = color_profile;
+        self
+    }
+
+    /// Whether to take over a destination that isn't marked as mapped from
+    /// this source, instead of refusing to touch it. Off by default.
+    pub fn adopt(mut self, adopt: bool) -> SettingsBuilder {
+        self.settings.adopt = adopt;
+        self
+    }
+
+    pub fn build(self) -> Settings {
+        self.settings
+    }
 }
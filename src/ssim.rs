@@ -0,0 +1,116 @@
+//! The structural similarity index, SSIM, which tells how alike two images
+//! look to people better than comparing their pixels one by one.
+
+use image::GrayImage;
+
+// The side of the square windows that are compared, and how far apart they
+// are
+const WINDOW: u32 = 8;
+const STRIDE: u32 = 4;
+
+// Keep the ratios stable where the windows are flat, as given in the
+// original paper for 8 bit samples
+const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+/// The mean SSIM of overlapping windows of `a` and `b`, from about 0 for
+/// unrelated images to 1 for identical ones. Images smaller than a window
+/// are compared as a whole. Returns 0 if the images differ in size.
+pub fn ssim(a: &GrayImage, b: &GrayImage) -> f64 {
+    if a.dimensions() != b.dimensions() || a.width() == 0 || a.height() == 0 {
+        return 0.0;
+    }
+
+    let window_width = WINDOW.min(a.width());
+    let window_height = WINDOW.min(a.height());
+    let starts = |length: u32, window: u32| {
+        (0..=length - window).step_by(STRIDE as usize)
+    };
+
+    let mut sum = 0.0;
+    let mut count = 0;
+    for y in starts(a.height(), window_height) {
+        for x in starts(a.width(), window_width) {
+            sum += window_ssim(a, b, (x, y), (window_width, window_height));
+            count += 1;
+        }
+    }
+    sum / count as f64
+}
+
+fn window_ssim(
+    a: &GrayImage,
+    b: &GrayImage,
+    (x, y): (u32, u32),
+    (width, height): (u32, u32),
+) -> f64 {
+    let mut sum_a = 0.0;
+    let mut sum_b = 0.0;
+    let mut sum_aa = 0.0;
+    let mut sum_bb = 0.0;
+    let mut sum_ab = 0.0;
+    for j in y..y + height {
+        for i in x..x + width {
+            let pixel_a = a.get_pixel(i, j)[0] as f64;
+            let pixel_b = b.get_pixel(i, j)[0] as f64;
+            sum_a += pixel_a;
+            sum_b += pixel_b;
+            sum_aa += pixel_a * pixel_a;
+            sum_bb += pixel_b * pixel_b;
+            sum_ab += pixel_a * pixel_b;
+        }
+    }
+
+    let n = (width * height) as f64;
+    let mean_a = sum_a / n;
+    let mean_b = sum_b / n;
+    let variance_a = sum_aa / n - mean_a * mean_a;
+    let variance_b = sum_bb / n - mean_b * mean_b;
+    let covariance = sum_ab / n - mean_a * mean_b;
+
+    ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+        / ((mean_a * mean_a + mean_b * mean_b + C1)
+            * (variance_a + variance_b + C2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    #[test]
+    fn identical_images_are_similar() {
+        let image = gradient();
+
+        assert!((ssim(&image, &image) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn more_noise_is_less_similar() {
+        let image = gradient();
+        let noisy = |amount: u8| {
+            GrayImage::from_fn(image.width(), image.height(), |x, y| {
+                let noise = if (x * 7 + y * 13) % 3 == 0 { amount } else { 0 };
+                Luma([image.get_pixel(x, y)[0].saturating_add(noise)])
+            })
+        };
+
+        let slightly = ssim(&image, &noisy(5));
+        let heavily = ssim(&image, &noisy(60));
+
+        assert!(slightly < 1.0);
+        assert!(heavily < slightly);
+    }
+
+    #[test]
+    fn small_and_different_sized_images_are_compared() {
+        let tiny = GrayImage::from_pixel(3, 2, Luma([100]));
+
+        assert!((ssim(&tiny, &tiny) - 1.0).abs() < 1e-9);
+        assert_eq!(0.0, ssim(&tiny, &gradient()));
+    }
+
+    fn gradient() -> GrayImage {
+        GrayImage::from_fn(40, 30, |x, y| Luma([(x * 5 + y * 2) as u8]))
+    }
+}
@@ -0,0 +1,457 @@
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+
+use log::{debug, error, info};
+use unwrap::unwrap;
+
+use crate::file_system::{FileSystem, RealFileSystem};
+use crate::mapper::date_layout::Plan;
+use crate::mapper::{self, MapperError};
+use crate::marker;
+use crate::media_handler::HandlerRegistry;
+use crate::settings::{Layout, Settings};
+
+/// Controls what [`verify_directory`] checks, and what it does with damaged
+/// files.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VerifySettings {
+    /// Delete damaged files, so that the next mapping creates them again
+    pub repair: bool,
+    /// Compare copied videos with their sources by hash, not just by size
+    pub compare_hashes: bool,
+}
+
+/// Checks that the files in `destination_path` are intact and match the
+/// source that it was mapped from, according to its marker.
+pub fn verify_directory(
+    destination_path: &Path,
+    settings: VerifySettings,
+) -> Result<VerifyReport, MapperError> {
+    let fs = &RealFileSystem;
+    if !fs.is_dir(destination_path) {
+        return Err(MapperError::DstDoesNotExist);
+    }
+    let marker = match marker::read_marker(destination_path, fs) {
+        Some(marker) => marker,
+        None => return Err(MapperError::DstNotMarked),
+    };
+
+    let registry = HandlerRegistry::with_defaults(&marker.settings);
+    verify_directory_with_handlers(
+        &marker.source,
+        destination_path,
+        &marker.settings,
+        settings,
+        registry,
+        fs,
+    )
+}
+
+/// Like [`verify_directory`], but checks `destination_path` against
+/// `source_path` as mapped with `mapping_settings`, with the handlers in
+/// `registry`, and all file system access goes through `fs`.
+pub fn verify_directory_with_handlers(
+    source_path: &Path,
+    destination_path: &Path,
+    mapping_settings: &Settings,
+    settings: VerifySettings,
+    registry: HandlerRegistry,
+    fs: &dyn FileSystem,
+) -> Result<VerifyReport, MapperError> {
+    if !fs.is_dir(source_path) {
+        return Err(MapperError::SrcDoesNotExist);
+    }
+    if !fs.is_dir(destination_path) {
+        return Err(MapperError::DstDoesNotExist);
+    }
+
+    // A file that is being written by a running mapper would look damaged
+    let _lock = match mapper::lock_destination(destination_path, fs) {
+        Some(lock) => lock,
+        None => return Err(MapperError::DstLocked),
+    };
+
+    let opts = VerifierOptions {
+        settings,
+        registry,
+        fs,
+        report: RefCell::new(VerifyReport::default()),
+    };
+
+    match mapping_settings.layout {
+        Layout::Mirror => {
+            verify_directory_int(source_path, destination_path, &opts)
+        }
+        Layout::YearMonth | Layout::YearDate => {
+            let plan = Plan::new(
+                source_path,
+                destination_path,
+                mapping_settings,
+                &opts.registry,
+                fs,
+            );
+            verify_planned_files(&plan, &opts);
+        }
+    }
+
+    Ok(opts.report.into_inner())
+}
+
+fn verify_directory_int(
+    source_path: &Path,
+    destination_path: &Path,
+    opts: &VerifierOptions,
+) {
+    debug!("Verifying \"{}\"", destination_path.display());
+
+    let destination_entry_paths = match opts.fs.read_dir(destination_path) {
+        Ok(destination_entry_paths) => destination_entry_paths,
+        Err(e) => {
+            let message = format!(
+                "Could not read the destination directory \"{}\" due to \"{}\"",
+                destination_path.display(),
+                e
+            );
+            opts.report_damaged(destination_path, message, false);
+            return;
+        }
+    };
+
+    for destination_entry_path in &destination_entry_paths {
+        if mapper::is_reserved_destination_path(destination_entry_path) {
+            continue;
+        }
+
+        let destination_entry_name = unwrap!(
+            destination_entry_path.file_name(),
+            "Could not get the file name of \"{}\"",
+            destination_entry_path.display()
+        );
+
+        if opts.fs.is_dir(destination_entry_path) {
+            verify_directory_int(
+                &source_path.join(destination_entry_name),
+                destination_entry_path,
+                opts,
+            );
+        } else {
+            verify_file(
+                source_path,
+                destination_entry_path,
+                &destination_entry_name.to_string_lossy(),
+                opts,
+            );
+        }
+    }
+}
+
+// With a date layout, the plan tells which source file each destination
+// file was created from. Files that aren't planned are deleted by the next
+// mapping anyway, so only the planned ones are checked.
+fn verify_planned_files(plan: &Plan, opts: &VerifierOptions) {
+    for (source_path, message) in &plan.failures {
+        opts.report_damaged(source_path, message.clone(), false);
+    }
+
+    for (destination_file_path, source_file_path) in plan.outputs() {
+        if opts.fs.is_file(destination_file_path) {
+            verify_output(source_file_path, destination_file_path, opts);
+        }
+    }
+}
+
+fn verify_file(
+    source_path: &Path,
+    destination_file_path: &Path,
+    destination_file_name: &str,
+    opts: &VerifierOptions,
+) {
+    // Files without a source are deleted by the next mapping anyway, so
+    // they aren't checked.
+    let source_file_path =
+        match opts.registry.source_name(destination_file_name) {
+            Some(source_file_name) => source_path.join(source_file_name),
+            None => return,
+        };
+    if !opts.fs.is_file(&source_file_path) {
+        return;
+    }
+    verify_output(&source_file_path, destination_file_path, opts);
+}
+
+fn verify_output(
+    source_file_path: &Path,
+    destination_file_path: &Path,
+    opts: &VerifierOptions,
+) {
+    let handler = match opts.registry.handler_for_source(source_file_path) {
+        Some(handler) => handler,
+        None => return,
+    };
+
+    opts.report.borrow_mut().checked_files += 1;
+    let result = handler.verify(
+        source_file_path,
+        destination_file_path,
+        &opts.settings,
+        opts.fs,
+    );
+
+    if let Err(message) = result {
+        let deleted = opts.settings.repair
+            && delete_damaged_file(destination_file_path, opts);
+        opts.report_damaged(destination_file_path, message, deleted);
+    }
+}
+
+fn delete_damaged_file(path: &Path, opts: &VerifierOptions) -> bool {
+    match opts.fs.remove_file(path) {
+        Ok(()) => true,
+        Err(e) => {
+            error!(
+                "Could not delete the damaged file \"{}\" due to \"{}\"",
+                path.display(),
+                e
+            );
+            false
+        }
+    }
+}
+
+struct VerifierOptions<'a> {
+    settings: VerifySettings,
+    registry: HandlerRegistry,
+    fs: &'a dyn FileSystem,
+    report: RefCell<VerifyReport>,
+}
+
+impl VerifierOptions<'_> {
+    fn report_damaged(&self, path: &Path, message: String, deleted: bool) {
+        error!("{}", message);
+        if deleted {
+            info!("Deleted \"{}\"", path.display());
+        }
+
+        self.report.borrow_mut().damaged_files.push(DamagedFile {
+            path: path.to_path_buf(),
+            message,
+            deleted,
+        });
+    }
+}
+
+/// The outcome of a [`verify_directory`] run.
+#[derive(Debug, Default, PartialEq)]
+pub struct VerifyReport {
+    pub checked_files: usize,
+    pub damaged_files: Vec<DamagedFile>,
+}
+
+/// A destination entry that is corrupt, truncated or doesn't match its
+/// source, or that couldn't be checked.
+#[derive(Debug, PartialEq)]
+pub struct DamagedFile {
+    pub path: PathBuf,
+    /// What's wrong with it
+    pub message: String,
+    /// Whether it was deleted, so that the next mapping creates it again
+    pub deleted: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_system::InMemoryFileSystem;
+    use crate::media_handler::{ImageHandler, VideoHandler};
+    use crate::settings::ImageQuality;
+
+    #[test]
+    fn intact_destination_has_no_damaged_files() {
+        let fs = mapped_file_system();
+
+        let report = verify(&fs, VerifySettings::default());
+
+        assert_eq!(
+            VerifyReport {
+                checked_files: 3,
+                damaged_files: vec![]
+            },
+            report
+        );
+    }
+
+    #[test]
+    fn truncated_image_is_damaged() {
+        let fs = mapped_file_system();
+        let path = Path::new("/dst/dir/image.jpg.jpg");
+        let contents = fs.read(path).unwrap();
+        fs.write(path, &contents[..contents.len() / 2]).unwrap();
+
+        let report = verify(&fs, VerifySettings::default());
+
+        assert_eq!(
+            vec![DamagedFile {
+                path: path.to_path_buf(),
+                message: "The image \"/dst/dir/image.jpg.jpg\" is truncated"
+                    .to_string(),
+                deleted: false
+            }],
+            report.damaged_files
+        );
+        assert!(fs.exists(path));
+    }
+
+    #[test]
+    fn corrupt_image_is_damaged_and_repaired() {
+        let fs = mapped_file_system();
+        let path = Path::new("/dst/image.png.jpg");
+        fs.write(path, b"not an image").unwrap();
+
+        let settings = VerifySettings {
+            repair: true,
+            ..VerifySettings::default()
+        };
+        let report = verify(&fs, settings);
+
+        assert_eq!(1, report.damaged_files.len());
+        assert_eq!(path, report.damaged_files[0].path);
+        assert!(report.damaged_files[0].deleted);
+        assert!(!fs.exists(path));
+    }
+
+    #[test]
+    fn video_with_other_size_is_damaged() {
+        let fs = mapped_file_system();
+        fs.write(Path::new("/dst/video.mp4"), b"vid").unwrap();
+
+        let report = verify(&fs, VerifySettings::default());
+
+        assert_eq!(
+            "The video \"/dst/video.mp4\" has 3 bytes, but \"/src/video.mp4\" has 5",
+            report.damaged_files[0].message
+        );
+    }
+
+    #[test]
+    fn video_with_same_size_is_damaged_only_when_comparing_hashes() {
+        let fs = mapped_file_system();
+        fs.write(Path::new("/dst/video.mp4"), b"VIDEO").unwrap();
+
+        let report = verify(&fs, VerifySettings::default());
+        assert!(report.damaged_files.is_empty());
+
+        let settings = VerifySettings {
+            compare_hashes: true,
+            ..VerifySettings::default()
+        };
+        let report = verify(&fs, settings);
+        assert_eq!(
+            "The video \"/dst/video.mp4\" differs from \"/src/video.mp4\"",
+            report.damaged_files[0].message
+        );
+    }
+
+    #[test]
+    fn files_without_source_are_not_checked() {
+        let fs = mapped_file_system();
+        fs.add_file(Path::new("/dst/removed.jpg.jpg"), b"not an image");
+        fs.add_file(Path::new("/dst/notes.txt"), b"notes");
+
+        let report = verify(&fs, VerifySettings::default());
+
+        assert_eq!(3, report.checked_files);
+        assert!(report.damaged_files.is_empty());
+    }
+
+    #[test]
+    fn files_are_checked_against_their_sources_in_date_layout() {
+        let fs = mapped_file_system_with_layout(Layout::YearMonth);
+        let image =
+            std::fs::read("test_resources/small-with-exif.jpg").unwrap();
+        fs.add_file(Path::new("/src/dated.jpg"), &image);
+        let dated_path = "/dst/2010/03/   2010-03-14 11;22;33 dated.jpg.jpg";
+        fs.add_file(Path::new(dated_path), b"not an image");
+        fs.write(Path::new("/dst/Undated/video.mp4"), b"vid")
+            .unwrap();
+
+        let report = verify_with_layout(
+            &fs,
+            Layout::YearMonth,
+            VerifySettings::default(),
+        );
+
+        assert_eq!(4, report.checked_files);
+        let damaged_paths: Vec<&Path> = report
+            .damaged_files
+            .iter()
+            .map(|damaged_file| damaged_file.path.as_path())
+            .collect();
+        assert_eq!(
+            vec![Path::new(dated_path), Path::new("/dst/Undated/video.mp4")],
+            damaged_paths
+        );
+    }
+
+    fn mapping_settings(layout: Layout) -> Settings {
+        Settings::builder(ImageQuality::Thumbnail)
+            .include_videos(true)
+            .layout(layout)
+            .build()
+    }
+
+    fn mapped_file_system() -> InMemoryFileSystem {
+        mapped_file_system_with_layout(Layout::Mirror)
+    }
+
+    fn mapped_file_system_with_layout(layout: Layout) -> InMemoryFileSystem {
+        let fs = InMemoryFileSystem::new();
+        let image =
+            std::fs::read("test_resources/small-without-exif.jpg").unwrap();
+        fs.add_file(Path::new("/src/dir/image.jpg"), &image);
+        fs.add_file(Path::new("/src/image.png"), &image);
+        fs.add_file(Path::new("/src/video.mp4"), b"video");
+        fs.add_dir(Path::new("/dst"));
+
+        let settings = mapping_settings(layout);
+        let registry = HandlerRegistry::with_defaults(&settings);
+        let summary = mapper::map_directory_with_handlers(
+            Path::new("/src"),
+            Path::new("/dst"),
+            settings,
+            registry,
+            &fs,
+            &|_| {},
+        )
+        .unwrap();
+        assert_eq!(3, summary.created_files);
+
+        fs
+    }
+
+    fn verify(
+        fs: &InMemoryFileSystem,
+        settings: VerifySettings,
+    ) -> VerifyReport {
+        verify_with_layout(fs, Layout::Mirror, settings)
+    }
+
+    fn verify_with_layout(
+        fs: &InMemoryFileSystem,
+        layout: Layout,
+        settings: VerifySettings,
+    ) -> VerifyReport {
+        let mut registry = HandlerRegistry::new();
+        registry.register(Box::new(ImageHandler::new()));
+        registry.register(Box::new(VideoHandler));
+
+        verify_directory_with_handlers(
+            Path::new("/src"),
+            Path::new("/dst"),
+            &mapping_settings(layout),
+            settings,
+            registry,
+            fs,
+        )
+        .unwrap()
+    }
+}